@@ -108,6 +108,18 @@ pub struct AddInvoices {
     pub pubkey: String,
     pub signature: String,
     pub invoices: Vec<Bolt11Invoice>,
+    /// LUD-10 encrypted success actions, one per invoice
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aes_payloads: Vec<AesPayload>,
+}
+
+/// LUD-10 success action data encrypted with the preimage of an invoice
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AesPayload {
+    /// base64 encoded ciphertext
+    pub ciphertext: String,
+    /// base64 encoded initialization vector
+    pub iv: String,
 }
 
 impl AddInvoices {
@@ -119,11 +131,18 @@ impl AddInvoices {
         Ok(Signature::from_str(&self.signature)?)
     }
 
-    pub fn message_hash(invoices: &[Bolt11Invoice]) -> anyhow::Result<Message> {
-        let bytes: Vec<u8> = invoices.iter().fold(Vec::new(), |mut acc, x| {
+    pub fn message_hash(
+        invoices: &[Bolt11Invoice],
+        aes_payloads: &[AesPayload],
+    ) -> anyhow::Result<Message> {
+        let mut bytes: Vec<u8> = invoices.iter().fold(Vec::new(), |mut acc, x| {
             acc.extend(x.payment_hash().to_vec());
             acc
         });
+        for payload in aes_payloads {
+            bytes.extend(payload.ciphertext.as_bytes());
+            bytes.extend(payload.iv.as_bytes());
+        }
         let hash = Sha256::hash(&bytes);
 
         Ok(Message::from_slice(&hash)?)
@@ -133,7 +152,162 @@ impl AddInvoices {
         let pubkey = self.pubkey().map_err(|_| anyhow!("Invalid pubkey"))?;
         let signature = self.signature().map_err(|_| anyhow!("Invalid signature"))?;
 
-        let message_hash = Self::message_hash(&self.invoices)?;
+        let message_hash = Self::message_hash(&self.invoices, &self.aes_payloads)?;
+
+        if context
+            .verify_ecdsa(&message_hash, &signature, &pubkey)
+            .is_err()
+        {
+            return Err(anyhow!("Invalid signature"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Settings a user can configure for their lightning address
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserSettings {
+    /// Minimum amount, in millisatoshis, the user can receive
+    pub min_sendable: Option<u64>,
+    /// Maximum amount, in millisatoshis, the user can receive
+    pub max_sendable: Option<u64>,
+    /// LUD-18 payer data the user asks payers for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payer_data: Option<PayerDataSchema>,
+    /// LUD-09 action shown to the payer after paying
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub success_action: Option<SuccessAction>,
+    /// Overrides of the server's policy for routing payments to the user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub routing_policy: Option<RoutingPolicy>,
+}
+
+/// How the server routes payments to a user, unset fields use the server's policy
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoutingPolicy {
+    /// Maximum number of parts a payment can be split into,
+    /// never above the server's own limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_parts: Option<u32>,
+    /// Channels of the server's node payments must leave through,
+    /// eg. a channel to the user's node, only ones the server allows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outgoing_chan_ids: Option<Vec<u64>>,
+    /// Seconds to try paying the user's invoice for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_seconds: Option<u64>,
+    /// Percentage of the server's fee that can be spent on routing,
+    /// never above the server's own limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee_limit_percent: Option<u8>,
+}
+
+/// LUD-09 success action a user can configure
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "tag", rename_all = "lowercase")]
+pub enum SuccessAction {
+    Message {
+        message: String,
+    },
+    Url {
+        description: String,
+        url: String,
+    },
+    /// LUD-10, the encrypted data is uploaded with each invoice
+    /// as only the user knows the preimages
+    Aes {
+        description: String,
+    },
+}
+
+/// A LUD-18 payer data field and whether the payer must provide it
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PayerDataField {
+    pub mandatory: bool,
+}
+
+/// LUD-18 payer data fields a user asks payers for
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PayerDataSchema {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<PayerDataField>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<PayerDataField>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identifier: Option<PayerDataField>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<PayerDataField>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<PayerDataField>,
+}
+
+/// LUD-18 proof that the payer controls a linking key
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PayerAuth {
+    pub key: String,
+    pub k1: String,
+    pub sig: String,
+}
+
+/// LUD-18 payer data sent by the payer
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PayerData {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identifier: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<PayerAuth>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UpdateSettings {
+    pub pubkey: String,
+    pub time: u64,
+    pub signature: String,
+    pub settings: UserSettings,
+}
+
+impl UpdateSettings {
+    pub fn pubkey(&self) -> anyhow::Result<PublicKey> {
+        Ok(PublicKey::from_str(&self.pubkey)?)
+    }
+
+    pub fn signature(&self) -> anyhow::Result<Signature> {
+        Ok(Signature::from_str(&self.signature)?)
+    }
+
+    pub fn message_hash(current_time: u64, settings: &UserSettings) -> anyhow::Result<Message> {
+        let str = format!(
+            "UpdateZapTunnelSettings-{}-{}",
+            current_time,
+            serde_json::to_string(settings)?
+        );
+        let hash = Sha256::hash(str.as_bytes());
+
+        Ok(Message::from_slice(&hash)?)
+    }
+
+    pub fn validate<C: Verification>(&self, context: &Secp256k1<C>) -> anyhow::Result<()> {
+        let pubkey = self.pubkey().map_err(|_| anyhow!("Invalid pubkey"))?;
+        let signature = self.signature().map_err(|_| anyhow!("Invalid signature"))?;
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs();
+
+        if now.saturating_sub(self.time) > 60 {
+            return Err(anyhow!("Request expired"));
+        } else if now + 60 < self.time {
+            return Err(anyhow!("Request is in the future"));
+        }
+
+        let message_hash = Self::message_hash(self.time, &self.settings)?;
 
         if context
             .verify_ecdsa(&message_hash, &signature, &pubkey)
@@ -145,3 +319,54 @@ impl AddInvoices {
         Ok(())
     }
 }
+
+/// A payment received by the user through zap-tunnel
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedPayment {
+    pub payment_hash: String,
+    pub amount_msats: Option<u64>,
+    pub fees_earned_msats: u64,
+    /// LUD-12 comment from the payer
+    pub comment: Option<String>,
+    /// LUD-18 payer data sent by the payer
+    pub payer_data: Option<PayerData>,
+}
+
+pub struct GetPayments;
+
+impl GetPayments {
+    pub fn message_hash(current_time: u64) -> anyhow::Result<Message> {
+        let str = format!("GetZapTunnelPayments-{}", current_time);
+        let hash = Sha256::hash(str.as_bytes());
+
+        Ok(Message::from_slice(&hash)?)
+    }
+
+    pub fn validate<C: Verification>(
+        context: &Secp256k1<C>,
+        time: u64,
+        pubkey: &PublicKey,
+        signature: &Signature,
+    ) -> anyhow::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs();
+
+        if now.saturating_sub(time) > 60 {
+            return Err(anyhow!("Request expired"));
+        } else if now + 60 < time {
+            return Err(anyhow!("Request is in the future"));
+        }
+
+        let message_hash = Self::message_hash(time)?;
+
+        if context
+            .verify_ecdsa(&message_hash, signature, pubkey)
+            .is_err()
+        {
+            return Err(anyhow!("Invalid signature"));
+        }
+
+        Ok(())
+    }
+}
@@ -96,11 +96,23 @@ impl AsyncClient {
         context: &Secp256k1<C>,
         private_key: &SecretKey,
         invoices: &[Bolt11Invoice],
+    ) -> Result<usize, Error> {
+        self.add_invoices_with_aes(context, private_key, invoices, &[])
+            .await
+    }
+
+    /// Adds invoices along with their LUD-10 encrypted success actions
+    pub async fn add_invoices_with_aes<C: Signing>(
+        &self,
+        context: &Secp256k1<C>,
+        private_key: &SecretKey,
+        invoices: &[Bolt11Invoice],
+        aes_payloads: &[AesPayload],
     ) -> Result<usize, Error> {
         let pubkey = PublicKey::from_secret_key(context, private_key);
 
         let signature = context.sign_ecdsa_low_r(
-            &AddInvoices::message_hash(invoices).expect("Failed to create hash"),
+            &AddInvoices::message_hash(invoices, aes_payloads).expect("Failed to create hash"),
             private_key,
         );
 
@@ -108,6 +120,7 @@ impl AsyncClient {
             pubkey: pubkey.to_string(),
             signature: signature.to_string(),
             invoices: invoices.to_vec(),
+            aes_payloads: aes_payloads.to_vec(),
         };
 
         let resp = self
@@ -119,4 +132,60 @@ impl AsyncClient {
 
         Ok(resp.error_for_status()?.json().await?)
     }
+
+    pub async fn update_settings<C: Signing>(
+        &self,
+        context: &Secp256k1<C>,
+        current_time: u64,
+        private_key: &SecretKey,
+        settings: UserSettings,
+    ) -> Result<UserSettings, Error> {
+        let pubkey = PublicKey::from_secret_key(context, private_key);
+
+        let signature = context.sign_ecdsa_low_r(
+            &UpdateSettings::message_hash(current_time, &settings).expect("Failed to create hash"),
+            private_key,
+        );
+
+        let payload = UpdateSettings {
+            pubkey: pubkey.to_string(),
+            time: current_time,
+            signature: signature.to_string(),
+            settings,
+        };
+
+        let resp = self
+            .client
+            .post(&format!("{}/update-settings", self.url))
+            .body(serde_json::to_vec(&payload)?)
+            .send()
+            .await?;
+
+        Ok(resp.error_for_status()?.json().await?)
+    }
+
+    pub async fn get_payments<C: Signing>(
+        &self,
+        context: &Secp256k1<C>,
+        current_time: u64,
+        private_key: &SecretKey,
+    ) -> Result<Vec<ReceivedPayment>, Error> {
+        let pubkey = PublicKey::from_secret_key(context, private_key);
+
+        let signature = context.sign_ecdsa_low_r(
+            &GetPayments::message_hash(current_time).expect("Failed to create hash"),
+            private_key,
+        );
+
+        let resp = self
+            .client
+            .get(&format!(
+                "{}/payments?time={}&pubkey={}&signature={}",
+                self.url, current_time, pubkey, signature
+            ))
+            .send()
+            .await?;
+
+        Ok(resp.error_for_status()?.json().await?)
+    }
 }
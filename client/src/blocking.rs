@@ -7,7 +7,10 @@ use std::time::Duration;
 
 use ureq::{Agent, Proxy};
 
-use crate::{AddInvoices, Builder, CheckUser, CreateUser, CreateUserResponse, Error};
+use crate::{
+    AddInvoices, AesPayload, Builder, CheckUser, CreateUser, CreateUserResponse, Error,
+    GetPayments, ReceivedPayment, UpdateSettings, UserSettings,
+};
 
 #[derive(Debug, Clone)]
 pub struct BlockingClient {
@@ -106,11 +109,22 @@ impl BlockingClient {
         context: &Secp256k1<C>,
         private_key: &SecretKey,
         invoices: &[Bolt11Invoice],
+    ) -> Result<usize, Error> {
+        self.add_invoices_with_aes(context, private_key, invoices, &[])
+    }
+
+    /// Adds invoices along with their LUD-10 encrypted success actions
+    pub fn add_invoices_with_aes<C: Signing>(
+        &self,
+        context: &Secp256k1<C>,
+        private_key: &SecretKey,
+        invoices: &[Bolt11Invoice],
+        aes_payloads: &[AesPayload],
     ) -> Result<usize, Error> {
         let pubkey = PublicKey::from_secret_key(context, private_key);
 
         let signature = context.sign_ecdsa_low_r(
-            &AddInvoices::message_hash(invoices).expect("Failed to create hash"),
+            &AddInvoices::message_hash(invoices, aes_payloads).expect("Failed to create hash"),
             private_key,
         );
 
@@ -118,6 +132,7 @@ impl BlockingClient {
             pubkey: pubkey.to_string(),
             signature: signature.to_string(),
             invoices: invoices.to_vec(),
+            aes_payloads: aes_payloads.to_vec(),
         };
 
         let resp = self
@@ -134,4 +149,71 @@ impl BlockingClient {
             Err(e) => Err(Error::Ureq(e)),
         }
     }
+
+    pub fn update_settings<C: Signing>(
+        &self,
+        context: &Secp256k1<C>,
+        current_time: u64,
+        private_key: &SecretKey,
+        settings: UserSettings,
+    ) -> Result<UserSettings, Error> {
+        let pubkey = PublicKey::from_secret_key(context, private_key);
+
+        let signature = context.sign_ecdsa_low_r(
+            &UpdateSettings::message_hash(current_time, &settings).expect("Failed to create hash"),
+            private_key,
+        );
+
+        let payload = UpdateSettings {
+            pubkey: pubkey.to_string(),
+            time: current_time,
+            signature: signature.to_string(),
+            settings,
+        };
+
+        let resp = self
+            .agent
+            .post(&format!("{}/update-settings", self.url))
+            .send_json(payload);
+
+        match resp {
+            Ok(resp) => Ok(resp.into_json()?),
+            Err(ureq::Error::Status(code, resp)) => {
+                let str = resp.into_string().ok();
+                Err(Error::HttpResponse(code, str))
+            }
+            Err(e) => Err(Error::Ureq(e)),
+        }
+    }
+
+    pub fn get_payments<C: Signing>(
+        &self,
+        context: &Secp256k1<C>,
+        current_time: u64,
+        private_key: &SecretKey,
+    ) -> Result<Vec<ReceivedPayment>, Error> {
+        let pubkey = PublicKey::from_secret_key(context, private_key);
+
+        let signature = context.sign_ecdsa_low_r(
+            &GetPayments::message_hash(current_time).expect("Failed to create hash"),
+            private_key,
+        );
+
+        let resp = self
+            .agent
+            .get(&format!(
+                "{}/payments?time={}&pubkey={}&signature={}",
+                self.url, current_time, pubkey, signature
+            ))
+            .call();
+
+        match resp {
+            Ok(resp) => Ok(resp.into_json()?),
+            Err(ureq::Error::Status(code, resp)) => {
+                let str = resp.into_string().ok();
+                Err(Error::HttpResponse(code, str))
+            }
+            Err(e) => Err(Error::Ureq(e)),
+        }
+    }
 }
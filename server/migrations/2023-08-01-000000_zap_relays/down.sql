@@ -0,0 +1 @@
+ALTER TABLE zaps DROP COLUMN published_relays;
@@ -0,0 +1 @@
+ALTER TABLE zaps ADD COLUMN published_relays TEXT;
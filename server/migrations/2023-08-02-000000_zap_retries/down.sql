@@ -0,0 +1,5 @@
+DROP INDEX zaps_note_id_idx;
+
+ALTER TABLE zaps DROP COLUMN next_attempt_at;
+ALTER TABLE zaps DROP COLUMN last_error;
+ALTER TABLE zaps DROP COLUMN attempts;
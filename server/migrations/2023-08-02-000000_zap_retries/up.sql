@@ -0,0 +1,5 @@
+ALTER TABLE zaps ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE zaps ADD COLUMN last_error TEXT;
+ALTER TABLE zaps ADD COLUMN next_attempt_at BIGINT;
+
+create index zaps_note_id_idx on zaps (note_id);
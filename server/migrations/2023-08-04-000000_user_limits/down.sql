@@ -0,0 +1,2 @@
+ALTER TABLE users DROP COLUMN max_sendable;
+ALTER TABLE users DROP COLUMN min_sendable;
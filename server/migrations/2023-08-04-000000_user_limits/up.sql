@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN min_sendable BIGINT;
+ALTER TABLE users ADD COLUMN max_sendable BIGINT;
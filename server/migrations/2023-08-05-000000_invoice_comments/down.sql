@@ -0,0 +1,2 @@
+ALTER TABLE invoices DROP COLUMN comment;
+ALTER TABLE invoices DROP COLUMN amount_msats;
@@ -0,0 +1,2 @@
+ALTER TABLE invoices ADD COLUMN amount_msats BIGINT;
+ALTER TABLE invoices ADD COLUMN comment TEXT;
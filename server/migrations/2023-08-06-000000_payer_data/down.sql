@@ -0,0 +1,2 @@
+ALTER TABLE invoices DROP COLUMN payer_data;
+ALTER TABLE users DROP COLUMN payer_data;
@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN payer_data TEXT;
+ALTER TABLE invoices ADD COLUMN payer_data TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE invoices DROP COLUMN aes_payload;
+ALTER TABLE users DROP COLUMN success_action;
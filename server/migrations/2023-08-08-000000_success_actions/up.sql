@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN success_action TEXT;
+ALTER TABLE invoices ADD COLUMN aes_payload TEXT;
@@ -0,0 +1,4 @@
+ALTER TABLE invoices ADD COLUMN settled_at BIGINT;
+
+-- invoices used to be marked paid only after settling
+UPDATE invoices SET settled_at = COALESCE(wrapped_expiry, expires_at) WHERE fees_earned IS NOT NULL;
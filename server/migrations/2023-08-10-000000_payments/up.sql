@@ -0,0 +1,43 @@
+CREATE TABLE payments
+(
+    payment_hash         TEXT PRIMARY KEY NOT NULL,
+    username             TEXT,
+    state                TEXT             NOT NULL,
+    amount_msats         BIGINT           NOT NULL,
+    forward_amount_msats BIGINT,
+    fee_limit_msats      BIGINT,
+    routing_fee_msats    BIGINT,
+    fees_earned_msats    BIGINT,
+    failure_reason       TEXT,
+    reserved_at          BIGINT           NOT NULL,
+    htlc_accepted_at     BIGINT,
+    forwarding_at        BIGINT,
+    forwarded_at         BIGINT,
+    settled_at           BIGINT,
+    failed_at            BIGINT,
+    updated_at           BIGINT           NOT NULL,
+    FOREIGN KEY (payment_hash) REFERENCES invoices (payment_hash),
+    FOREIGN KEY (username) REFERENCES users (username)
+);
+
+create index payments_state_idx on payments (state);
+create index payments_username_idx on payments (username);
+
+-- best effort history for invoices served before payments were tracked
+INSERT INTO payments (payment_hash, username, state, amount_msats, fees_earned_msats,
+                      reserved_at, forwarded_at, settled_at, updated_at)
+SELECT payment_hash,
+       username,
+       CASE
+           WHEN settled_at IS NOT NULL THEN 'settled'
+           WHEN fees_earned IS NOT NULL THEN 'forwarded'
+           ELSE 'reserved'
+           END,
+       COALESCE(amount_msats, 0),
+       fees_earned,
+       wrapped_expiry - 360,
+       CASE WHEN fees_earned IS NOT NULL THEN wrapped_expiry END,
+       settled_at,
+       COALESCE(settled_at, wrapped_expiry - 360)
+FROM invoices
+WHERE wrapped_expiry IS NOT NULL;
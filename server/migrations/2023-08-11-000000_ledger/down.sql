@@ -0,0 +1,3 @@
+DROP INDEX payments_settled_at_idx;
+DROP INDEX invoices_expires_at_idx;
+DROP TABLE ledger;
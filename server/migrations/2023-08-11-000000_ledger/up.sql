@@ -0,0 +1,13 @@
+CREATE TABLE ledger
+(
+    username          TEXT   NOT NULL,
+    day               BIGINT NOT NULL,
+    payments          BIGINT NOT NULL,
+    amount_msats      BIGINT NOT NULL,
+    fees_earned_msats BIGINT NOT NULL,
+    routing_fee_msats BIGINT NOT NULL,
+    PRIMARY KEY (username, day)
+);
+
+create index invoices_expires_at_idx on invoices (expires_at);
+create index payments_settled_at_idx on payments (settled_at);
@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN routing_policy TEXT;
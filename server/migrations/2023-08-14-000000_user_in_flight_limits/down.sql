@@ -0,0 +1 @@
+ALTER TABLE users DROP COLUMN in_flight_limits;
@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN in_flight_limits TEXT;
@@ -0,0 +1,3 @@
+DROP TABLE payment_history;
+DROP INDEX payments_request_id_idx;
+ALTER TABLE payments DROP COLUMN request_id;
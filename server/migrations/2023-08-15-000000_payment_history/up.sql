@@ -0,0 +1,30 @@
+ALTER TABLE payments ADD COLUMN request_id TEXT NOT NULL DEFAULT '';
+-- verify urls served so far point at the payment hash
+UPDATE payments SET request_id = payment_hash;
+create unique index payments_request_id_idx on payments (request_id);
+
+-- payments of invoices that were released to the pool or removed before being paid
+CREATE TABLE payment_history
+(
+    request_id           TEXT PRIMARY KEY NOT NULL,
+    payment_hash         TEXT             NOT NULL,
+    username             TEXT,
+    state                TEXT             NOT NULL,
+    amount_msats         BIGINT           NOT NULL,
+    forward_amount_msats BIGINT,
+    fee_limit_msats      BIGINT,
+    routing_fee_msats    BIGINT,
+    fees_earned_msats    BIGINT,
+    failure_reason       TEXT,
+    reserved_at          BIGINT           NOT NULL,
+    htlc_accepted_at     BIGINT,
+    forwarding_at        BIGINT,
+    forwarded_at         BIGINT,
+    settled_at           BIGINT,
+    failed_at            BIGINT,
+    updated_at           BIGINT           NOT NULL,
+    wrapped_invoice      TEXT             NOT NULL
+);
+
+create index payment_history_payment_hash_idx on payment_history (payment_hash);
+create index payment_history_updated_at_idx on payment_history (updated_at);
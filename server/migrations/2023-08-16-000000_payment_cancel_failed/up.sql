@@ -0,0 +1,2 @@
+-- ended payments whose hold invoice could not be cancelled, reconciling retries them
+ALTER TABLE payments ADD COLUMN cancel_failed BOOLEAN NOT NULL DEFAULT FALSE;
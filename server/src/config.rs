@@ -1,8 +1,24 @@
+use bitcoin::hashes::{sha256, Hash, HashEngine};
 use bitcoin::Network;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use nostr::key::{FromSkStr, XOnlyPublicKey};
 use nostr::Keys;
 
+use crate::fees::{min_amount, FeeMode, FeePolicy, PaymentFee};
+use crate::limits::InFlightLimits;
+use crate::payer_data::AuthChallenges;
+
+const DEFAULT_RELAYS: [&str; 8] = [
+    "wss://nostr.mutinywallet.com",
+    "wss://relay.snort.social",
+    "wss://relay.nostr.band",
+    "wss://eden.nostr.land",
+    "wss://nos.lol",
+    "wss://nostr.fmt.wiz.biz",
+    "wss://relay.damus.io",
+    "wss://nostr.wine",
+];
+
 #[derive(Parser, Debug, Clone)]
 #[command(version, author, about)]
 /// A tool for proxying LNURL pay addresses.
@@ -13,9 +29,34 @@ pub struct Config {
     /// Base fee, in millisatoshis, for routing payments
     #[clap(default_value_t = 1000, long)]
     pub base_fee: u64,
-    /// Fee rate in percentage of payment amount, for routing payments
-    #[clap(default_value_t = 1.0, long)]
-    pub fee_rate: f64,
+    /// Fee rate in parts per million of the payment amount, for routing payments
+    #[clap(default_value_t = 10_000, long)]
+    pub fee_ppm: u64,
+    /// Deprecated, fee rate in percentage of the payment amount, use --fee-ppm
+    #[clap(long, hide = true, conflicts_with = "fee_ppm")]
+    fee_rate: Option<f64>,
+    /// Minimum fee, in millisatoshis, for routing payments
+    #[clap(default_value_t = 0, long)]
+    pub min_fee: u64,
+    /// Maximum fee, in millisatoshis, for routing payments
+    #[clap(long)]
+    pub max_fee: Option<u64>,
+    /// How fees are charged: the fee policy, or the estimated routing cost
+    /// plus a margin without going above the fee policy
+    #[clap(value_enum, default_value_t = FeeMode::Fixed, long)]
+    pub fee_mode: FeeMode,
+    /// Base margin, in millisatoshis, charged on top of the routing cost in route-cost mode
+    #[clap(default_value_t = 0, long)]
+    pub margin_base_fee: u64,
+    /// Margin in parts per million, charged on top of the routing cost in route-cost mode
+    #[clap(default_value_t = 1_000, long)]
+    pub margin_fee_ppm: u64,
+    /// Maximum amount, in millisatoshis, that can be sent to a user
+    #[clap(default_value_t = 100_000_000, long)]
+    pub max_sendable: u64,
+    /// Maximum length of payer comments, 0 to disable comments
+    #[clap(default_value_t = 255, long)]
+    pub comment_allowed: u32,
     #[clap(default_value_t = String::from("127.0.0.1"), long)]
     /// Host of the GRPC server for lnd
     pub lnd_host: String,
@@ -40,9 +81,116 @@ pub struct Config {
     #[clap(default_value_t = 3000, long)]
     /// Port for zap-tunnel's webserver
     pub port: u16,
+    #[clap(default_value_t = String::from("127.0.0.1"), long)]
+    /// Bind address for the operator endpoints, /health and /relay-stats
+    pub operator_bind: String,
+    #[clap(default_value_t = 3001, long)]
+    /// Port for the operator endpoints
+    pub operator_port: u16,
     #[clap(long)]
     /// Public URL for zap-tunnel's webserver (eg zaptunnel.com)
     pub public_url: String,
+    #[clap(long = "relay", default_values = DEFAULT_RELAYS)]
+    /// Relays to publish zap receipts to, in addition to the ones in the zap request
+    pub relays: Vec<String>,
+    #[clap(default_value_t = 16, long)]
+    /// Maximum number of parts payments to users can be split into,
+    /// users can only lower it
+    pub max_parts: u32,
+    #[clap(long = "outgoing-chan-id")]
+    /// Channels payments to users must leave through, any channel if unset,
+    /// users can only pick among them
+    pub outgoing_chan_ids: Vec<u64>,
+    #[clap(default_value_t = 60, long)]
+    /// Maximum seconds to try paying a user's invoice for
+    pub payment_timeout: u64,
+    #[clap(default_value_t = 100, long, value_parser = clap::value_parser!(u8).range(0..=100))]
+    /// Percentage of the fee that can be spent on routing payments to users,
+    /// failed payments are retried once with all of it
+    pub fee_limit_percent: u8,
+    #[clap(default_value_t = 0.9, long)]
+    /// Preference of lnd's pathfinding for fast (1) over cheap (-1) routes
+    pub time_pref: f64,
+    #[clap(long)]
+    /// Maximum number of payments to all users that can be in flight at once
+    pub max_in_flight_payments: Option<u64>,
+    #[clap(long)]
+    /// Maximum millisatoshis that can be in flight to all users at once
+    pub max_in_flight_msats: Option<u64>,
+    #[clap(long)]
+    /// Maximum number of payments to a user that can be in flight at once
+    pub max_user_in_flight_payments: Option<u64>,
+    #[clap(long)]
+    /// Maximum millisatoshis that can be in flight to a user at once
+    pub max_user_in_flight_msats: Option<u64>,
+    #[clap(default_value_t = 30, long)]
+    /// Seconds a paid invoice waits for the in-flight limits before it is cancelled
+    pub in_flight_queue_seconds: u64,
+    #[clap(long)]
+    /// Check a route to the user's node exists before serving an invoice
+    pub probe_recipients: bool,
+    #[clap(default_value_t = 300, long)]
+    /// Seconds to reuse the result of probing a user's node for
+    pub probe_cache_seconds: u64,
+    #[clap(default_value_t = 30, long)]
+    /// Days to keep invoices that expired without being used
+    pub invoice_retention_days: u64,
+    #[clap(default_value_t = 90, long)]
+    /// Days to keep settled payments before compacting them into the ledger
+    pub payment_retention_days: u64,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Run the database maintenance once and exit
+    Maintenance,
+    /// Print the payments to a payment hash or in a state and exit
+    Payments {
+        /// Only print the payments to this payment hash, with its earlier requests
+        #[clap(long)]
+        hash: Option<String>,
+        /// Only print the payments in this state, e.g. forwarding or failed
+        #[clap(long)]
+        state: Option<String>,
+    },
+    /// Print the daily totals of compacted payments and exit
+    Ledger {
+        /// Only print the totals of this user
+        #[clap(long)]
+        username: Option<String>,
+    },
+    /// Set the fee charged for routing payments to a user and exit
+    SetUserFee {
+        username: String,
+        /// Base fee, in millisatoshis
+        #[clap(long)]
+        base_fee: u64,
+        /// Fee rate in parts per million of the payment amount
+        #[clap(long)]
+        fee_ppm: u64,
+        /// Minimum fee, in millisatoshis
+        #[clap(default_value_t = 0, long)]
+        min_fee: u64,
+        /// Maximum fee, in millisatoshis
+        #[clap(long)]
+        max_fee: Option<u64>,
+    },
+    /// Charge a user the server's fee again and exit
+    ClearUserFee { username: String },
+    /// Set the limits on payments in flight to a user and exit
+    SetUserLimits {
+        username: String,
+        /// Maximum number of payments in flight at once
+        #[clap(long)]
+        max_payments: Option<u64>,
+        /// Maximum millisatoshis in flight at once
+        #[clap(long)]
+        max_msats: Option<u64>,
+    },
+    /// Apply the server's default in-flight limits to a user again and exit
+    ClearUserLimits { username: String },
 }
 
 impl Config {
@@ -50,6 +198,18 @@ impl Config {
         Keys::from_sk_str(&self.nsec).expect("Failed to parse nsec key")
     }
 
+    /// Payer auth challenges, keyed with a secret derived from our nostr key
+    pub fn auth_challenges(&self) -> AuthChallenges {
+        let secret_key = self
+            .nostr_keys()
+            .secret_key()
+            .expect("Failed to get nostr secret key");
+        let mut engine = sha256::Hash::engine();
+        engine.input(b"zap-tunnel payer auth");
+        engine.input(&secret_key.secret_bytes());
+        AuthChallenges::new(sha256::Hash::from_engine(engine).into_inner())
+    }
+
     pub fn public_key(&self) -> XOnlyPublicKey {
         self.nostr_keys().public_key()
     }
@@ -65,7 +225,78 @@ impl Config {
     }
 
     pub fn min_sendable(&self) -> u64 {
-        1_000.max(self.base_fee)
+        self.min_sendable_for(&self.fee_policy())
+    }
+
+    /// Smallest amount that can be sent to a user charged `fee_policy`,
+    /// something must be left to forward after the least fee we could charge
+    pub fn min_sendable_for(&self, fee_policy: &FeePolicy) -> u64 {
+        let min = min_amount(|amount| self.least_fee(fee_policy, amount));
+        1_000.max(min.unwrap_or(u64::MAX))
+    }
+
+    /// Converts the deprecated `--fee-rate` percentage into `fee_ppm`
+    pub fn apply_deprecated_fee_rate(&mut self) {
+        if let Some(rate) = self.fee_rate.take() {
+            let fee_ppm = (rate * 10_000.0).round() as u64;
+            println!("Warning: --fee-rate is deprecated, use --fee-ppm {fee_ppm} instead");
+            self.fee_ppm = fee_ppm;
+        }
+    }
+
+    /// Fee charged for routing payments to users without their own fee
+    pub fn fee_policy(&self) -> FeePolicy {
+        FeePolicy::new(self.base_fee, self.fee_ppm, self.min_fee, self.max_fee)
+            .expect("Maximum fee must not be below the minimum fee")
+    }
+
+    /// Limits on the payments to all users in flight at once
+    pub fn in_flight_limits(&self) -> InFlightLimits {
+        InFlightLimits {
+            max_payments: self.max_in_flight_payments,
+            max_msats: self.max_in_flight_msats,
+        }
+    }
+
+    /// Limits on the payments in flight to users without their own limits
+    pub fn user_in_flight_limits(&self) -> InFlightLimits {
+        InFlightLimits {
+            max_payments: self.max_user_in_flight_payments,
+            max_msats: self.max_user_in_flight_msats,
+        }
+    }
+
+    /// Margin charged on top of the routing cost in route-cost mode
+    pub fn route_fee_margin(&self) -> FeePolicy {
+        FeePolicy {
+            base_msat: self.margin_base_fee,
+            ppm: self.margin_fee_ppm,
+            min_msat: 0,
+            max_msat: None,
+        }
+    }
+
+    /// Least fee that can be charged for `amount_msat`,
+    /// only the margin when routing is free in route-cost mode
+    pub fn least_fee(&self, fee_policy: &FeePolicy, amount_msat: u64) -> u64 {
+        match self.fee_mode {
+            FeeMode::Fixed => fee_policy.fee(amount_msat),
+            FeeMode::RouteCost => {
+                PaymentFee::route_cost(fee_policy, &self.route_fee_margin(), amount_msat, 0)
+                    .total_msat
+            }
+        }
+    }
+
+    /// Fee published to payers and users
+    pub fn describe_fee(&self, fee_policy: &FeePolicy) -> String {
+        match self.fee_mode {
+            FeeMode::Fixed => fee_policy.to_string(),
+            FeeMode::RouteCost => format!(
+                "routing cost + {}, at most {fee_policy}",
+                self.route_fee_margin()
+            ),
+        }
     }
 
     #[cfg(test)]
@@ -73,7 +304,15 @@ impl Config {
         Self {
             nsec: "nsec1f77xgphdtw7g9qdryer6md8wv4nxvj83vweaejz8e8g7zgr2wttsxkmmfm".to_string(),
             base_fee: 1000,
-            fee_rate: 1.0,
+            fee_ppm: 10_000,
+            fee_rate: None,
+            min_fee: 0,
+            max_fee: None,
+            fee_mode: FeeMode::Fixed,
+            margin_base_fee: 0,
+            margin_fee_ppm: 1_000,
+            max_sendable: 100_000_000,
+            comment_allowed: 255,
             lnd_host: "127.0.0.1".to_string(),
             lnd_port: 10009,
             network: Network::Regtest,
@@ -82,7 +321,25 @@ impl Config {
             db_path: "db.sqlite".to_string(),
             bind: "0.0.0.0".to_string(),
             port: 3000,
+            operator_bind: "127.0.0.1".to_string(),
+            operator_port: 3001,
             public_url: "localhost".to_string(),
+            relays: DEFAULT_RELAYS.iter().map(|r| r.to_string()).collect(),
+            max_parts: 16,
+            outgoing_chan_ids: vec![],
+            payment_timeout: 60,
+            fee_limit_percent: 100,
+            time_pref: 0.9,
+            max_in_flight_payments: None,
+            max_in_flight_msats: None,
+            max_user_in_flight_payments: None,
+            max_user_in_flight_msats: None,
+            in_flight_queue_seconds: 30,
+            probe_recipients: false,
+            probe_cache_seconds: 300,
+            invoice_retention_days: 30,
+            payment_retention_days: 90,
+            command: None,
         }
     }
 }
@@ -117,3 +374,28 @@ pub fn default_macaroon_file(network: &Network) -> String {
         network_str
     )
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NSEC: &str = "nsec1f77xgphdtw7g9qdryer6md8wv4nxvj83vweaejz8e8g7zgr2wttsxkmmfm";
+
+    fn parse(args: &[&str]) -> Result<Config, clap::Error> {
+        let required = ["zap-tunnel", "--nsec", NSEC, "--public-url", "localhost"];
+        Config::try_parse_from(required.iter().chain(args))
+    }
+
+    #[test]
+    fn test_deprecated_fee_rate() {
+        let mut config = parse(&["--fee-rate", "0.5"]).unwrap();
+        config.apply_deprecated_fee_rate();
+        assert_eq!(config.fee_ppm, 5_000);
+
+        let mut config = parse(&[]).unwrap();
+        config.apply_deprecated_fee_rate();
+        assert_eq!(config.fee_ppm, 10_000);
+
+        assert!(parse(&["--fee-rate", "1", "--fee-ppm", "100"]).is_err());
+    }
+}
@@ -0,0 +1,278 @@
+use std::fmt;
+
+use anyhow::anyhow;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+const PPM: u128 = 1_000_000;
+
+/// How the fee for a payment is decided
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeeMode {
+    /// Charge the fee policy and keep whatever routing doesn't use
+    Fixed,
+    /// Charge the estimated routing cost plus a margin, never more than the fee policy
+    RouteCost,
+}
+
+/// Fee charged for forwarding a payment to a user
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeePolicy {
+    /// Fixed fee, in millisatoshis
+    pub base_msat: u64,
+    /// Proportional fee, in parts per million of the amount
+    pub ppm: u64,
+    /// Minimum fee, in millisatoshis
+    pub min_msat: u64,
+    /// Maximum fee, in millisatoshis
+    pub max_msat: Option<u64>,
+}
+
+impl FeePolicy {
+    pub fn new(
+        base_msat: u64,
+        ppm: u64,
+        min_msat: u64,
+        max_msat: Option<u64>,
+    ) -> anyhow::Result<Self> {
+        if max_msat.map_or(false, |max| max < min_msat) {
+            return Err(anyhow!("Maximum fee must not be below the minimum fee"));
+        }
+
+        Ok(Self {
+            base_msat,
+            ppm,
+            min_msat,
+            max_msat,
+        })
+    }
+
+    /// Fee for forwarding `amount_msat`, the proportional part is rounded up
+    pub fn fee(&self, amount_msat: u64) -> u64 {
+        let proportional = (amount_msat as u128 * self.ppm as u128 + PPM - 1) / PPM;
+        let fee = (self.base_msat as u128 + proportional).min(u64::MAX as u128) as u64;
+
+        let fee = fee.max(self.min_msat);
+        self.max_msat.map_or(fee, |max| fee.min(max))
+    }
+}
+
+/// Fee kept from a payment and how much of it can be spent on routing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaymentFee {
+    pub total_msat: u64,
+    pub routing_budget_msat: u64,
+}
+
+impl PaymentFee {
+    /// The fee policy's fee, all of which can be spent on routing
+    pub fn fixed(fee_policy: &FeePolicy, amount_msat: u64) -> Self {
+        let fee = fee_policy.fee(amount_msat);
+        Self {
+            total_msat: fee,
+            routing_budget_msat: fee,
+        }
+    }
+
+    /// The routing cost plus the margin, capped by the fee policy.
+    /// Routing is budgeted first, when capped the margin is what shrinks.
+    pub fn route_cost(
+        fee_policy: &FeePolicy,
+        margin: &FeePolicy,
+        amount_msat: u64,
+        route_fee_msat: u64,
+    ) -> Self {
+        let max = fee_policy.fee(amount_msat);
+        let total = route_fee_msat
+            .saturating_add(margin.fee(amount_msat))
+            .min(max);
+
+        Self {
+            total_msat: total,
+            routing_budget_msat: route_fee_msat.min(total),
+        }
+    }
+}
+
+/// Smallest amount that leaves something to forward after `fee`,
+/// None if the fee takes every amount.
+///
+/// The amount left after the fee must never shrink as the amount grows,
+/// which holds for fee policies of at most 1,000,000 ppm.
+pub fn min_amount(fee: impl Fn(u64) -> u64) -> Option<u64> {
+    let covers = |amount: u64| fee(amount) < amount;
+    if !covers(u64::MAX) {
+        return None;
+    }
+
+    // `low` never covers its fee and `high` always does
+    let (mut low, mut high) = (0, u64::MAX);
+    while high - low > 1 {
+        let mid = low + (high - low) / 2;
+        if covers(mid) {
+            high = mid;
+        } else {
+            low = mid;
+        }
+    }
+
+    Some(high)
+}
+
+impl fmt::Display for FeePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} msats + {} ppm", self.base_msat, self.ppm)?;
+        if self.min_msat > 0 {
+            write!(f, ", min {} msats", self.min_msat)?;
+        }
+        if let Some(max) = self.max_msat {
+            write!(f, ", max {max} msats")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Config;
+    use crate::lightning::fake::ROUTING_FEE_MSAT;
+    use crate::lightning::InvoiceState;
+    use crate::routes::get_lnurlp_impl;
+    use crate::subscriber::handle_accepted_invoice;
+    use crate::test_utils::TestContext;
+
+    #[test]
+    fn test_fee() {
+        let policy = FeePolicy::new(1_000, 10_000, 0, None).unwrap();
+        assert_eq!(policy.fee(0), 1_000);
+        assert_eq!(policy.fee(10_000), 1_100);
+        // rounded up
+        assert_eq!(policy.fee(1), 1_001);
+        assert_eq!(policy.fee(99), 1_001);
+        assert_eq!(policy.fee(100), 1_001);
+        assert_eq!(policy.fee(101), 1_002);
+        assert_eq!(policy.fee(100_000_000), 1_001_000);
+
+        // no overflow
+        let policy = FeePolicy::new(u64::MAX, 1_000_000, 0, None).unwrap();
+        assert_eq!(policy.fee(u64::MAX), u64::MAX);
+
+        let policy = FeePolicy::new(0, 0, 0, None).unwrap();
+        assert_eq!(policy.fee(100_000_000), 0);
+    }
+
+    #[test]
+    fn test_fee_caps() {
+        let policy = FeePolicy::new(0, 10_000, 500, Some(2_000)).unwrap();
+        assert_eq!(policy.fee(0), 500);
+        assert_eq!(policy.fee(50_000), 500);
+        assert_eq!(policy.fee(50_001), 501);
+        assert_eq!(policy.fee(200_000), 2_000);
+        assert_eq!(policy.fee(200_001), 2_000);
+
+        // min and max can be equal for a flat fee
+        let policy = FeePolicy::new(0, 10_000, 1_000, Some(1_000)).unwrap();
+        assert_eq!(policy.fee(1), 1_000);
+        assert_eq!(policy.fee(1_000_000_000), 1_000);
+
+        assert!(FeePolicy::new(0, 0, 1_000, Some(999)).is_err());
+    }
+
+    #[test]
+    fn test_route_cost() {
+        let policy = FeePolicy::new(1_000, 10_000, 0, None).unwrap();
+        let margin = FeePolicy::new(0, 1_000, 0, None).unwrap();
+
+        let fee = PaymentFee::fixed(&policy, 100_000);
+        assert_eq!(fee.total_msat, 2_000);
+        assert_eq!(fee.routing_budget_msat, 2_000);
+
+        // cheap routes leave more for the user
+        let fee = PaymentFee::route_cost(&policy, &margin, 100_000, 50);
+        assert_eq!(fee.total_msat, 150);
+        assert_eq!(fee.routing_budget_msat, 50);
+
+        // the total never goes above the fee policy, the margin shrinks first
+        let fee = PaymentFee::route_cost(&policy, &margin, 100_000, 1_950);
+        assert_eq!(fee.total_msat, 2_000);
+        assert_eq!(fee.routing_budget_msat, 1_950);
+        let fee = PaymentFee::route_cost(&policy, &margin, 100_000, 5_000);
+        assert_eq!(fee.total_msat, 2_000);
+        assert_eq!(fee.routing_budget_msat, 2_000);
+    }
+
+    #[test]
+    fn test_min_amount() {
+        let policy = FeePolicy::new(1_000, 10_000, 0, None).unwrap();
+        let min = min_amount(|amount| policy.fee(amount)).unwrap();
+        assert_eq!(min, 1_012);
+        assert!(policy.fee(min) < min);
+        assert!(policy.fee(min - 1) >= min - 1);
+
+        let policy = FeePolicy::new(0, 0, 0, None).unwrap();
+        assert_eq!(min_amount(|amount| policy.fee(amount)), Some(1));
+
+        let policy = FeePolicy::new(0, 1_000_000, 0, None).unwrap();
+        assert_eq!(min_amount(|amount| policy.fee(amount)), None);
+
+        // the maximum makes large amounts cover the fee again
+        let policy = FeePolicy::new(0, 1_000_000, 0, Some(5_000)).unwrap();
+        assert_eq!(min_amount(|amount| policy.fee(amount)), Some(5_001));
+    }
+
+    #[test]
+    fn test_display() {
+        let policy = FeePolicy::new(1_000, 10_000, 0, None).unwrap();
+        assert_eq!(policy.to_string(), "1000 msats + 10000 ppm");
+
+        let policy = FeePolicy::new(0, 5_000, 100, Some(5_000)).unwrap();
+        assert_eq!(
+            policy.to_string(),
+            "0 msats + 5000 ppm, min 100 msats, max 5000 msats"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_route_cost_fee() {
+        let mut config = Config::dummy();
+        config.fee_mode = FeeMode::RouteCost;
+        let mut ctx = TestContext::new(config);
+
+        let username = String::from("test_user");
+        ctx.create_user(&username);
+
+        // payers only need to cover the margin
+        let res = get_lnurlp_impl(username.clone(), &ctx.state.config, &mut ctx.conn).unwrap();
+        assert_eq!(res.pay.min_sendable, 1_000);
+        assert!(res
+            .pay
+            .metadata
+            .contains("fee: routing cost + 0 msats + 1000 ppm, at most 1000 msats + 10000 ppm"));
+
+        for (i, route_fee) in [Some(ROUTING_FEE_MSAT), None].into_iter().enumerate() {
+            ctx.node.set_route_fee(route_fee);
+
+            let hash = ctx
+                .serve_invoice(&username, [9u8 + i as u8; 32], 10_000)
+                .await;
+
+            let update = ctx.node.accept_htlc(hash).unwrap();
+            handle_accepted_invoice(update, ctx.state.clone()).await;
+            assert_eq!(ctx.node.invoice_state(&hash), Some(InvoiceState::Settled));
+        }
+
+        // the routing cost plus a 10 msats margin
+        let payments = ctx.node.payments();
+        assert_eq!(payments.len(), 2);
+        assert_eq!(payments[0].amount_msat, 9_980);
+        assert_eq!(payments[0].fee_limit_msat, ROUTING_FEE_MSAT);
+        let payment = ctx.payment(payments[0].invoice.payment_hash()).unwrap();
+        assert_eq!(payment.fees_earned_msats, Some(10));
+
+        // without a route estimate the fee policy is charged
+        assert_eq!(payments[1].amount_msat, 8_900);
+        assert_eq!(payments[1].fee_limit_msat, 1_100);
+    }
+}
@@ -0,0 +1,40 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use bitcoin::hashes::sha256::Hash as Sha256;
+
+/// Payments a task of this process is forwarding or tracking, reconciling
+/// leaves them alone as lnd may not know about their payment yet
+#[derive(Default)]
+pub struct ActiveForwards {
+    payment_hashes: Mutex<HashSet<Sha256>>,
+}
+
+impl ActiveForwards {
+    /// Marks the payment as forwarded by the caller until the guard is dropped,
+    /// None if another task already is
+    pub fn start(self: &Arc<Self>, payment_hash: Sha256) -> Option<ForwardGuard> {
+        let mut payment_hashes = self.payment_hashes.lock().unwrap();
+        if !payment_hashes.insert(payment_hash) {
+            return None;
+        }
+
+        Some(ForwardGuard {
+            forwards: self.clone(),
+            payment_hash,
+        })
+    }
+}
+
+/// Keeps a payment in [`ActiveForwards`] while it is alive
+pub struct ForwardGuard {
+    forwards: Arc<ActiveForwards>,
+    payment_hash: Sha256,
+}
+
+impl Drop for ForwardGuard {
+    fn drop(&mut self) {
+        let mut payment_hashes = self.forwards.payment_hashes.lock().unwrap();
+        payment_hashes.remove(&self.payment_hash);
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use bitcoin::hashes::sha256::Hash as Sha256;
+use serde::Serialize;
+
+/// Connection state of the lnd invoice subscription
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct SubscriptionStatus {
+    pub connected: bool,
+    /// When the current connection was established
+    pub connected_since: Option<u64>,
+    /// Number of times the subscription was lost
+    pub disconnects: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct HealthReport {
+    pub invoice_subscription: SubscriptionStatus,
+    /// Number of hold invoices with their own subscription
+    pub single_invoice_subscriptions: usize,
+}
+
+/// Health of the lnd subscriptions, exposed to operators
+#[derive(Default)]
+pub struct SubscriptionHealth {
+    invoices: Mutex<SubscriptionStatus>,
+    single_invoices: Mutex<HashSet<Sha256>>,
+}
+
+impl SubscriptionHealth {
+    pub fn connected(&self) {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .ok();
+
+        let mut status = self.invoices.lock().unwrap();
+        status.connected = true;
+        status.connected_since = now;
+    }
+
+    pub fn disconnected(&self, error: String) {
+        let mut status = self.invoices.lock().unwrap();
+        if status.connected {
+            status.disconnects += 1;
+        }
+        status.connected = false;
+        status.connected_since = None;
+        status.last_error = Some(error);
+    }
+
+    /// Registers a single invoice subscription,
+    /// returns false if the invoice already has one.
+    pub fn start_single(&self, payment_hash: Sha256) -> bool {
+        self.single_invoices.lock().unwrap().insert(payment_hash)
+    }
+
+    pub fn end_single(&self, payment_hash: &Sha256) {
+        self.single_invoices.lock().unwrap().remove(payment_hash);
+    }
+
+    pub fn report(&self) -> HealthReport {
+        HealthReport {
+            invoice_subscription: self.invoices.lock().unwrap().clone(),
+            single_invoice_subscriptions: self.single_invoices.lock().unwrap().len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash;
+
+    use super::*;
+
+    #[test]
+    fn test_subscription_health() {
+        let health = SubscriptionHealth::default();
+        assert!(!health.report().invoice_subscription.connected);
+
+        health.connected();
+        let report = health.report();
+        assert!(report.invoice_subscription.connected);
+        assert!(report.invoice_subscription.connected_since.is_some());
+
+        health.disconnected(String::from("stream closed"));
+        health.disconnected(String::from("connection refused"));
+        let status = health.report().invoice_subscription;
+        assert!(!status.connected);
+        assert_eq!(status.disconnects, 1);
+        assert_eq!(status.last_error, Some(String::from("connection refused")));
+
+        let hash = Sha256::hash(&[1u8; 32]);
+        assert!(health.start_single(hash));
+        assert!(!health.start_single(hash));
+        assert_eq!(health.report().single_invoice_subscriptions, 1);
+        health.end_single(&hash);
+        assert_eq!(health.report().single_invoice_subscriptions, 0);
+    }
+}
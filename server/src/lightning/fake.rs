@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::rand::rngs::OsRng;
+use bitcoin::secp256k1::rand::RngCore;
+use bitcoin::secp256k1::{rand, SecretKey, SECP256K1};
+use bitcoin::Network;
+use lightning::ln::PaymentSecret;
+use lightning_invoice::{Bolt11Invoice, Currency, InvoiceBuilder};
+use tokio::sync::mpsc::{channel, Sender};
+
+use super::*;
+
+/// Routing fee the fake node charges for every payment
+pub const ROUTING_FEE_MSAT: u64 = 10;
+
+type UpdateSender = Sender<anyhow::Result<InvoiceUpdate>>;
+
+#[derive(Default)]
+struct FakeNodeState {
+    invoices: HashMap<Sha256, InvoiceUpdate>,
+    /// Preimages of invoices the fake node is able to pay
+    payable: HashMap<Sha256, [u8; 32]>,
+    payments: Vec<PaymentRequest>,
+    /// Latest result of the payments to each payment hash
+    payment_results: HashMap<Sha256, PaymentResult>,
+    /// Keeps payments in flight until [`FakeNode::complete_payments`]
+    hold_payments: bool,
+    /// Results of the payments that are held in flight
+    in_flight: HashMap<Sha256, PaymentResult>,
+    /// Makes tracking wait for payments in flight to finish,
+    /// like lnd without in-flight updates
+    block_tracking: bool,
+    /// Routing fee estimates, [`ROUTING_FEE_MSAT`] when unset
+    route_fee: Option<Option<u64>>,
+    /// Outbound liquidity, unlimited when unset
+    outbound_liquidity: Option<u64>,
+    /// Makes settling invoices fail, like lnd being unreachable
+    fail_settle: bool,
+    /// Makes cancelling invoices fail
+    fail_cancel: bool,
+    /// Forgets cancelled invoices, like lnd with `gc-canceled-invoices-on-the-fly`
+    gc_canceled: bool,
+    subscribers: Vec<UpdateSender>,
+    single_subscribers: Vec<(Sha256, UpdateSender)>,
+}
+
+impl FakeNodeState {
+    fn notify(&mut self, update: &InvoiceUpdate) {
+        self.subscribers
+            .retain(|tx| tx.try_send(Ok(update.clone())).is_ok());
+        self.single_subscribers.retain(|(hash, tx)| {
+            hash != &update.payment_hash || tx.try_send(Ok(update.clone())).is_ok()
+        });
+    }
+}
+
+fn in_flight_result() -> PaymentResult {
+    PaymentResult {
+        status: PaymentStatus::InFlight,
+        preimage: None,
+        fee_msat: 0,
+        failure_reason: String::new(),
+    }
+}
+
+/// In-memory lightning node for testing the payment flow without lnd
+pub struct FakeNode {
+    network: Network,
+    node_key: SecretKey,
+    state: Mutex<FakeNodeState>,
+}
+
+fn random_bytes() -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+impl FakeNode {
+    pub fn new(network: Network) -> Self {
+        Self {
+            network,
+            node_key: SecretKey::new(&mut rand::thread_rng()),
+            state: Mutex::new(FakeNodeState::default()),
+        }
+    }
+
+    /// Creates an amount-less invoice belonging to another node that this node can pay,
+    /// like the invoices users upload to zap-tunnel.
+    pub fn create_payable_invoice(&self, preimage: [u8; 32]) -> Bolt11Invoice {
+        let payment_hash = Sha256::hash(&preimage);
+        let private_key = SecretKey::new(&mut rand::thread_rng());
+
+        let invoice = InvoiceBuilder::new(Currency::from(self.network))
+            .description(String::from("fake invoice"))
+            .current_timestamp()
+            .payment_hash(payment_hash)
+            .payment_secret(PaymentSecret(random_bytes()))
+            .min_final_cltv_expiry_delta(144)
+            .expiry_time(Duration::from_secs(3_600))
+            .build_signed(|hash| SECP256K1.sign_ecdsa_recoverable(hash, &private_key))
+            .expect("Failed to build invoice");
+
+        let mut state = self.state.lock().unwrap();
+        state.payable.insert(payment_hash, preimage);
+
+        invoice
+    }
+
+    /// Simulates a payer paying one of our hold invoices
+    pub fn accept_htlc(&self, payment_hash: Sha256) -> anyhow::Result<InvoiceUpdate> {
+        let mut state = self.state.lock().unwrap();
+        let invoice = state
+            .invoices
+            .get_mut(&payment_hash)
+            .ok_or(anyhow!("Invoice not found"))?;
+
+        if invoice.state != InvoiceState::Open {
+            return Err(anyhow!("Invoice is not open"));
+        }
+        invoice.state = InvoiceState::Accepted;
+
+        let update = invoice.clone();
+        state.notify(&update);
+
+        Ok(update)
+    }
+
+    pub fn invoice_state(&self, payment_hash: &Sha256) -> Option<InvoiceState> {
+        let state = self.state.lock().unwrap();
+        state.invoices.get(payment_hash).map(|inv| inv.state)
+    }
+
+    pub fn set_fail_settle(&self, fail_settle: bool) {
+        self.state.lock().unwrap().fail_settle = fail_settle;
+    }
+
+    pub fn set_fail_cancel(&self, fail_cancel: bool) {
+        self.state.lock().unwrap().fail_cancel = fail_cancel;
+    }
+
+    pub fn set_gc_canceled(&self, gc_canceled: bool) {
+        self.state.lock().unwrap().gc_canceled = gc_canceled;
+    }
+
+    /// Sets the routing fee estimate, None for no route
+    pub fn set_route_fee(&self, route_fee: Option<u64>) {
+        self.state.lock().unwrap().route_fee = Some(route_fee);
+    }
+
+    pub fn set_outbound_liquidity(&self, outbound_msat: u64) {
+        self.state.lock().unwrap().outbound_liquidity = Some(outbound_msat);
+    }
+
+    pub fn set_hold_payments(&self, hold_payments: bool) {
+        self.state.lock().unwrap().hold_payments = hold_payments;
+    }
+
+    pub fn set_block_tracking(&self, block_tracking: bool) {
+        self.state.lock().unwrap().block_tracking = block_tracking;
+    }
+
+    /// Lets the payments held in flight finish
+    pub fn complete_payments(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.hold_payments = false;
+        let in_flight: Vec<_> = state.in_flight.drain().collect();
+        state.payment_results.extend(in_flight);
+    }
+
+    /// Payments this node has attempted
+    pub fn payments(&self) -> Vec<PaymentRequest> {
+        self.state.lock().unwrap().payments.clone()
+    }
+}
+
+#[async_trait]
+impl LightningBackend for FakeNode {
+    async fn add_hold_invoice(&self, req: HoldInvoiceRequest) -> anyhow::Result<Bolt11Invoice> {
+        let invoice = InvoiceBuilder::new(Currency::from(self.network))
+            .amount_milli_satoshis(req.value_msat)
+            .description_hash(req.description_hash)
+            .current_timestamp()
+            .payment_hash(req.payment_hash)
+            .payment_secret(PaymentSecret(random_bytes()))
+            .min_final_cltv_expiry_delta(req.cltv_expiry)
+            .expiry_time(Duration::from_secs(req.expiry as u64))
+            .build_signed(|hash| SECP256K1.sign_ecdsa_recoverable(hash, &self.node_key))?;
+
+        let update = InvoiceUpdate {
+            payment_hash: req.payment_hash,
+            state: InvoiceState::Open,
+            value_msat: req.value_msat,
+            preimage: None,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        if state.invoices.contains_key(&req.payment_hash) {
+            return Err(anyhow!("Invoice with payment hash already exists"));
+        }
+        state.invoices.insert(req.payment_hash, update.clone());
+        state.notify(&update);
+
+        Ok(invoice)
+    }
+
+    async fn settle_invoice(&self, preimage: [u8; 32]) -> anyhow::Result<()> {
+        let payment_hash = Sha256::hash(&preimage);
+
+        let mut state = self.state.lock().unwrap();
+        if state.fail_settle {
+            return Err(anyhow!("Failed to settle invoice"));
+        }
+
+        let invoice = state
+            .invoices
+            .get_mut(&payment_hash)
+            .ok_or(anyhow!("Invoice not found"))?;
+
+        if invoice.state != InvoiceState::Accepted {
+            return Err(anyhow!("Invoice is not accepted"));
+        }
+        invoice.state = InvoiceState::Settled;
+        invoice.preimage = Some(preimage);
+
+        let update = invoice.clone();
+        state.notify(&update);
+
+        Ok(())
+    }
+
+    async fn lookup_invoice(&self, payment_hash: Sha256) -> anyhow::Result<Option<InvoiceUpdate>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.invoices.get(&payment_hash).cloned())
+    }
+
+    async fn cancel_invoice(&self, payment_hash: Sha256) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.fail_cancel {
+            return Err(anyhow!("Failed to cancel invoice"));
+        }
+
+        let invoice = state
+            .invoices
+            .get_mut(&payment_hash)
+            .ok_or(anyhow!("Invoice not found"))?;
+
+        if invoice.state == InvoiceState::Settled {
+            return Err(anyhow!("Invoice is already settled"));
+        }
+        invoice.state = InvoiceState::Canceled;
+
+        let update = invoice.clone();
+        state.notify(&update);
+        if state.gc_canceled {
+            state.invoices.remove(&payment_hash);
+        }
+
+        Ok(())
+    }
+
+    async fn subscribe_invoices(&self) -> anyhow::Result<InvoiceStream> {
+        let (tx, rx) = channel(100);
+        self.state.lock().unwrap().subscribers.push(tx);
+
+        Ok(rx)
+    }
+
+    async fn subscribe_single_invoice(
+        &self,
+        payment_hash: Sha256,
+    ) -> anyhow::Result<InvoiceStream> {
+        let (tx, rx) = channel(100);
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(invoice) = state.invoices.get(&payment_hash) {
+            tx.try_send(Ok(invoice.clone()))?;
+        }
+        state.single_subscribers.push((payment_hash, tx));
+
+        Ok(rx)
+    }
+
+    async fn send_payment(&self, req: PaymentRequest) -> anyhow::Result<PaymentResult> {
+        let payment_hash = *req.invoice.payment_hash();
+
+        let mut state = self.state.lock().unwrap();
+        state.payments.push(req.clone());
+
+        let result = match state.payable.get(&payment_hash) {
+            Some(preimage) if req.fee_limit_msat >= ROUTING_FEE_MSAT => PaymentResult {
+                status: PaymentStatus::Succeeded,
+                preimage: Some(*preimage),
+                fee_msat: ROUTING_FEE_MSAT,
+                failure_reason: String::new(),
+            },
+            Some(_) => PaymentResult {
+                status: PaymentStatus::Failed,
+                preimage: None,
+                fee_msat: 0,
+                failure_reason: String::from("FAILURE_REASON_NO_ROUTE"),
+            },
+            None => PaymentResult {
+                status: PaymentStatus::Failed,
+                preimage: None,
+                fee_msat: 0,
+                failure_reason: String::from("FAILURE_REASON_INCORRECT_PAYMENT_DETAILS"),
+            },
+        };
+
+        if state.hold_payments {
+            state.in_flight.insert(payment_hash, result);
+            return Ok(in_flight_result());
+        }
+        state.payment_results.insert(payment_hash, result.clone());
+
+        Ok(result)
+    }
+
+    async fn track_payment(&self, payment_hash: Sha256) -> anyhow::Result<Option<PaymentResult>> {
+        loop {
+            {
+                let state = self.state.lock().unwrap();
+                if !state.in_flight.contains_key(&payment_hash) {
+                    return Ok(state.payment_results.get(&payment_hash).cloned());
+                }
+                if !state.block_tracking {
+                    return Ok(Some(in_flight_result()));
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    async fn estimate_route_fee(
+        &self,
+        _invoice: &Bolt11Invoice,
+        _amount_msat: u64,
+    ) -> anyhow::Result<Option<u64>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.route_fee.unwrap_or(Some(ROUTING_FEE_MSAT)))
+    }
+
+    async fn outbound_liquidity(&self) -> anyhow::Result<u64> {
+        let state = self.state.lock().unwrap();
+        Ok(state.outbound_liquidity.unwrap_or(u64::MAX))
+    }
+}
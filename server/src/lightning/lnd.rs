@@ -0,0 +1,328 @@
+use std::convert::TryInto;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::hashes::Hash;
+use lightning_invoice::Bolt11Invoice;
+use tokio::sync::mpsc::channel;
+use tonic_openssl_lnd::invoicesrpc::{
+    AddHoldInvoiceRequest, CancelInvoiceMsg, SettleInvoiceMsg, SubscribeSingleInvoiceRequest,
+};
+use tonic_openssl_lnd::lnrpc::invoice::InvoiceState as LndInvoiceState;
+use tonic_openssl_lnd::lnrpc::payment::PaymentStatus as LndPaymentStatus;
+use tonic_openssl_lnd::routerrpc::{SendPaymentRequest, TrackPaymentRequest};
+use tonic_openssl_lnd::{lnrpc, LndInvoicesClient, LndLightningClient, LndRouterClient};
+
+use super::*;
+
+/// Buffer size for the channels backing invoice streams
+const STREAM_BUFFER: usize = 100;
+
+/// [`LightningBackend`] backed by an LND node over gRPC
+#[derive(Clone)]
+pub struct LndBackend {
+    lightning: LndLightningClient,
+    invoices: LndInvoicesClient,
+    router: LndRouterClient,
+}
+
+impl LndBackend {
+    pub fn new(
+        lightning: LndLightningClient,
+        invoices: LndInvoicesClient,
+        router: LndRouterClient,
+    ) -> Self {
+        Self {
+            lightning,
+            invoices,
+            router,
+        }
+    }
+}
+
+/// Converts an lnd invoice into an [`InvoiceUpdate`],
+/// returns None for invoices in an unknown state.
+fn invoice_update(invoice: lnrpc::Invoice) -> Option<InvoiceUpdate> {
+    let state = match LndInvoiceState::from_i32(invoice.state)? {
+        LndInvoiceState::Open => InvoiceState::Open,
+        LndInvoiceState::Accepted => InvoiceState::Accepted,
+        LndInvoiceState::Settled => InvoiceState::Settled,
+        LndInvoiceState::Canceled => InvoiceState::Canceled,
+    };
+
+    let payment_hash = Sha256::from_slice(&invoice.r_hash).ok()?;
+    let preimage = invoice.r_preimage.as_slice().try_into().ok();
+
+    Some(InvoiceUpdate {
+        payment_hash,
+        state,
+        value_msat: invoice.value_msat as u64,
+        preimage,
+    })
+}
+
+fn payment_result(payment: lnrpc::Payment) -> PaymentResult {
+    let status = match LndPaymentStatus::from_i32(payment.status) {
+        Some(LndPaymentStatus::InFlight) => PaymentStatus::InFlight,
+        Some(LndPaymentStatus::Succeeded) => PaymentStatus::Succeeded,
+        Some(LndPaymentStatus::Failed) => PaymentStatus::Failed,
+        _ => PaymentStatus::Unknown,
+    };
+
+    let preimage = Vec::from_hex(&payment.payment_preimage)
+        .ok()
+        .and_then(|bytes| bytes.as_slice().try_into().ok())
+        .filter(|preimage: &[u8; 32]| preimage != &[0u8; 32]);
+
+    PaymentResult {
+        status,
+        preimage,
+        fee_msat: payment.fee_msat as u64,
+        failure_reason: format!(
+            "{:?}",
+            lnrpc::PaymentFailureReason::from_i32(payment.failure_reason)
+        ),
+    }
+}
+
+/// Forwards messages from an lnd invoice stream into an [`InvoiceStream`],
+/// the stream is closed after the first error.
+macro_rules! forward_invoice_stream {
+    ($stream:expr) => {{
+        let mut stream = $stream;
+        let (tx, rx) = channel(STREAM_BUFFER);
+        tokio::spawn(async move {
+            loop {
+                let item = match stream.message().await {
+                    Ok(Some(invoice)) => match invoice_update(invoice) {
+                        Some(update) => Ok(update),
+                        None => continue,
+                    },
+                    Ok(None) => break,
+                    Err(e) => Err(anyhow!("Invoice stream error: {e}")),
+                };
+
+                let is_err = item.is_err();
+                if tx.send(item).await.is_err() || is_err {
+                    break;
+                }
+            }
+        });
+        rx
+    }};
+}
+
+#[async_trait]
+impl LightningBackend for LndBackend {
+    async fn add_hold_invoice(&self, req: HoldInvoiceRequest) -> anyhow::Result<Bolt11Invoice> {
+        let request = AddHoldInvoiceRequest {
+            hash: req.payment_hash.to_vec(),
+            value_msat: req.value_msat as i64,
+            description_hash: req.description_hash.to_vec(),
+            expiry: req.expiry,
+            cltv_expiry: req.cltv_expiry,
+            ..Default::default()
+        };
+
+        let resp = self
+            .invoices
+            .clone()
+            .add_hold_invoice(request)
+            .await?
+            .into_inner();
+
+        Ok(Bolt11Invoice::from_str(&resp.payment_request)?)
+    }
+
+    async fn settle_invoice(&self, preimage: [u8; 32]) -> anyhow::Result<()> {
+        self.invoices
+            .clone()
+            .settle_invoice(SettleInvoiceMsg {
+                preimage: preimage.to_vec(),
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn lookup_invoice(&self, payment_hash: Sha256) -> anyhow::Result<Option<InvoiceUpdate>> {
+        let req = lnrpc::PaymentHash {
+            r_hash: payment_hash.to_vec(),
+            ..Default::default()
+        };
+
+        match self.lightning.clone().lookup_invoice(req).await {
+            Ok(resp) => Ok(invoice_update(resp.into_inner())),
+            Err(status) if status.message().contains("unable to locate invoice") => Ok(None),
+            Err(e) => Err(anyhow!("Failed to lookup invoice: {e}")),
+        }
+    }
+
+    async fn cancel_invoice(&self, payment_hash: Sha256) -> anyhow::Result<()> {
+        self.invoices
+            .clone()
+            .cancel_invoice(CancelInvoiceMsg {
+                payment_hash: payment_hash.to_vec(),
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn subscribe_invoices(&self) -> anyhow::Result<InvoiceStream> {
+        let sub = lnrpc::InvoiceSubscription::default();
+        let stream = self
+            .lightning
+            .clone()
+            .subscribe_invoices(sub)
+            .await?
+            .into_inner();
+
+        Ok(forward_invoice_stream!(stream))
+    }
+
+    async fn subscribe_single_invoice(
+        &self,
+        payment_hash: Sha256,
+    ) -> anyhow::Result<InvoiceStream> {
+        let req = SubscribeSingleInvoiceRequest {
+            r_hash: payment_hash.to_vec(),
+        };
+        let stream = self
+            .invoices
+            .clone()
+            .subscribe_single_invoice(req)
+            .await?
+            .into_inner();
+
+        Ok(forward_invoice_stream!(stream))
+    }
+
+    async fn send_payment(&self, req: PaymentRequest) -> anyhow::Result<PaymentResult> {
+        let payment_hash = req.invoice.payment_hash().to_hex();
+        let req = SendPaymentRequest {
+            payment_request: req.invoice.to_string(),
+            amt_msat: req.amount_msat as i64,
+            fee_limit_msat: req.fee_limit_msat as i64,
+            timeout_seconds: req.timeout_seconds as i32,
+            // in-flight updates tell us the payment started if the stream drops
+            no_inflight_updates: false,
+            allow_self_payment: false,
+            amp: false,
+            max_parts: req.max_parts,
+            outgoing_chan_ids: req.outgoing_chan_ids,
+            time_pref: req.time_pref,
+            ..Default::default()
+        };
+
+        let mut stream = self.router.clone().send_payment_v2(req).await?.into_inner();
+
+        // the payment is reported in flight before it is final,
+        // follow it until it succeeds or fails
+        let mut last = None;
+        while let Some(payment) = stream.message().await? {
+            let result = payment_result(payment);
+            if result.status.is_final() {
+                return Ok(result);
+            }
+            last = Some(result);
+        }
+
+        last.ok_or(anyhow!("No payment update for {payment_hash}"))
+    }
+
+    async fn track_payment(&self, payment_hash: Sha256) -> anyhow::Result<Option<PaymentResult>> {
+        // without in-flight updates lnd only answers once the payment is final
+        let req = TrackPaymentRequest {
+            payment_hash: payment_hash.to_vec(),
+            no_inflight_updates: false,
+        };
+
+        let mut stream = match self.router.clone().track_payment_v2(req).await {
+            Ok(resp) => resp.into_inner(),
+            Err(status) if status.message().contains("isn't initiated") => return Ok(None),
+            Err(e) => return Err(anyhow!("Failed to track payment: {e}")),
+        };
+
+        // the first update is the current status of the payment
+        match stream.message().await {
+            Ok(payment) => Ok(payment.map(payment_result)),
+            Err(status) if status.message().contains("isn't initiated") => Ok(None),
+            Err(e) => Err(anyhow!("Failed to track payment: {e}")),
+        }
+    }
+
+    async fn estimate_route_fee(
+        &self,
+        invoice: &Bolt11Invoice,
+        amount_msat: u64,
+    ) -> anyhow::Result<Option<u64>> {
+        // the invoice's private channels can only be reached through its hints
+        let route_hints = invoice
+            .route_hints()
+            .into_iter()
+            .map(|hint| lnrpc::RouteHint {
+                hop_hints: hint
+                    .0
+                    .into_iter()
+                    .map(|hop| lnrpc::HopHint {
+                        node_id: hop.src_node_id.to_string(),
+                        chan_id: hop.short_channel_id,
+                        fee_base_msat: hop.fees.base_msat,
+                        fee_proportional_millionths: hop.fees.proportional_millionths,
+                        cltv_expiry_delta: hop.cltv_expiry_delta as u32,
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        let req = lnrpc::QueryRoutesRequest {
+            pub_key: invoice.recover_payee_pub_key().to_string(),
+            amt_msat: amount_msat as i64,
+            final_cltv_delta: invoice.min_final_cltv_expiry_delta() as i32,
+            route_hints,
+            ..Default::default()
+        };
+
+        match self.lightning.clone().query_routes(req).await {
+            Ok(resp) => Ok(resp
+                .into_inner()
+                .routes
+                .first()
+                .map(|route| route.total_fees_msat as u64)),
+            Err(status) if status.message().contains("unable to find a path") => Ok(None),
+            Err(e) => Err(anyhow!("Failed to query routes: {e}")),
+        }
+    }
+
+    async fn outbound_liquidity(&self) -> anyhow::Result<u64> {
+        let req = lnrpc::ListChannelsRequest {
+            active_only: true,
+            ..Default::default()
+        };
+        let channels = self
+            .lightning
+            .clone()
+            .list_channels(req)
+            .await?
+            .into_inner()
+            .channels;
+
+        // pending HTLCs are already taken out of the local balance
+        let spendable_sat: i64 = channels
+            .iter()
+            .map(|channel| {
+                let reserve = channel
+                    .local_constraints
+                    .as_ref()
+                    .map_or(0, |constraints| constraints.chan_reserve_sat as i64);
+                (channel.local_balance - reserve).max(0)
+            })
+            .sum();
+
+        Ok(spendable_sat as u64 * 1_000)
+    }
+}
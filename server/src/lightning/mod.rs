@@ -0,0 +1,132 @@
+use async_trait::async_trait;
+use bitcoin::hashes::sha256::Hash as Sha256;
+use lightning_invoice::Bolt11Invoice;
+use tokio::sync::mpsc::Receiver;
+
+#[cfg(test)]
+pub mod fake;
+pub mod lnd;
+
+/// State of an invoice on the lightning node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvoiceState {
+    Open,
+    Accepted,
+    Settled,
+    Canceled,
+}
+
+/// An update for an invoice on the lightning node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvoiceUpdate {
+    pub payment_hash: Sha256,
+    pub state: InvoiceState,
+    pub value_msat: u64,
+    /// Preimage of the invoice, None for hold invoices that have not been settled
+    pub preimage: Option<[u8; 32]>,
+}
+
+impl InvoiceUpdate {
+    pub fn is_hold_invoice(&self) -> bool {
+        self.preimage.is_none()
+    }
+}
+
+/// Stream of invoice updates, closed when the subscription ends
+pub type InvoiceStream = Receiver<anyhow::Result<InvoiceUpdate>>;
+
+/// Parameters for creating a hold invoice
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HoldInvoiceRequest {
+    pub payment_hash: Sha256,
+    pub value_msat: u64,
+    pub description_hash: Sha256,
+    /// Expiry of the invoice in seconds
+    pub expiry: i64,
+    pub cltv_expiry: u64,
+}
+
+/// Parameters for paying an invoice
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaymentRequest {
+    pub invoice: Bolt11Invoice,
+    pub amount_msat: u64,
+    pub fee_limit_msat: u64,
+    pub timeout_seconds: u64,
+    /// Maximum number of parts the payment can be split into
+    pub max_parts: u32,
+    /// Channels the payment must leave through, any channel if empty
+    pub outgoing_chan_ids: Vec<u64>,
+    /// Preference for fast (1) over cheap (-1) routes
+    pub time_pref: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentStatus {
+    Unknown,
+    InFlight,
+    Succeeded,
+    Failed,
+}
+
+impl PaymentStatus {
+    /// Whether the payment can no longer change status
+    pub fn is_final(&self) -> bool {
+        matches!(self, PaymentStatus::Succeeded | PaymentStatus::Failed)
+    }
+}
+
+/// Result of an outgoing payment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaymentResult {
+    pub status: PaymentStatus,
+    pub preimage: Option<[u8; 32]>,
+    pub fee_msat: u64,
+    pub failure_reason: String,
+}
+
+/// The operations zap-tunnel needs from a lightning node to proxy payments.
+///
+/// Every hold invoice we create wraps one of a user's pooled invoices, using
+/// the same payment hash, so that paying the user's invoice reveals the
+/// preimage we need to settle the hold invoice.
+#[async_trait]
+pub trait LightningBackend: Send + Sync {
+    /// Create a hold invoice, returns the encoded invoice
+    async fn add_hold_invoice(&self, req: HoldInvoiceRequest) -> anyhow::Result<Bolt11Invoice>;
+
+    /// Settle an accepted hold invoice with its preimage
+    async fn settle_invoice(&self, preimage: [u8; 32]) -> anyhow::Result<()>;
+
+    /// Look up the current state of an invoice, None if the node doesn't know it
+    async fn lookup_invoice(&self, payment_hash: Sha256) -> anyhow::Result<Option<InvoiceUpdate>>;
+
+    /// Cancel a hold invoice, failing back any accepted HTLCs
+    async fn cancel_invoice(&self, payment_hash: Sha256) -> anyhow::Result<()>;
+
+    /// Subscribe to updates for all invoices on the node
+    async fn subscribe_invoices(&self) -> anyhow::Result<InvoiceStream>;
+
+    /// Subscribe to updates for a single invoice
+    async fn subscribe_single_invoice(&self, payment_hash: Sha256)
+        -> anyhow::Result<InvoiceStream>;
+
+    /// Pay an invoice, returns once the payment reached a final status
+    /// or the last status seen if the update stream ended before that
+    async fn send_payment(&self, req: PaymentRequest) -> anyhow::Result<PaymentResult>;
+
+    /// Look up the current status of an outgoing payment without waiting for
+    /// it to finish, None if the node never attempted to pay the payment hash
+    async fn track_payment(&self, payment_hash: Sha256) -> anyhow::Result<Option<PaymentResult>>;
+
+    /// Estimate the routing fee for paying `amount_msat` to an invoice,
+    /// None if no route to its destination was found
+    async fn estimate_route_fee(
+        &self,
+        invoice: &Bolt11Invoice,
+        amount_msat: u64,
+    ) -> anyhow::Result<Option<u64>>;
+
+    /// Millisatoshis we can send over our active channels, above their reserves
+    async fn outbound_liquidity(&self) -> anyhow::Result<u64>;
+}
@@ -0,0 +1,300 @@
+use std::fmt;
+use std::time::Duration;
+
+use diesel::SqliteConnection;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::models::payment::{Payment, PaymentState};
+use crate::models::user::User;
+
+/// How often a payment waiting for the in-flight limits checks them again
+pub const IN_FLIGHT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Payments that are holding our liquidity
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InFlight {
+    pub payments: u64,
+    /// Sum of the payers' amounts, more than what we send out
+    pub msats: u64,
+}
+
+/// Limits on the payments holding our liquidity at once, no limit when unset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InFlightLimits {
+    pub max_payments: Option<u64>,
+    pub max_msats: Option<u64>,
+}
+
+impl InFlightLimits {
+    /// Whether a payment of `amount_msats` fits next to the ones in flight
+    pub fn allows(&self, in_flight: &InFlight, amount_msats: u64) -> bool {
+        self.max_payments
+            .map_or(true, |max| in_flight.payments < max)
+            && self.max_msats.map_or(true, |max| {
+                in_flight.msats.saturating_add(amount_msats) <= max
+            })
+    }
+}
+
+impl fmt::Display for InFlightLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.max_payments {
+            Some(max) => write!(f, "{max} payments")?,
+            None => write!(f, "unlimited payments")?,
+        }
+        match self.max_msats {
+            Some(max) => write!(f, ", {max} msats"),
+            None => write!(f, ", unlimited msats"),
+        }
+    }
+}
+
+/// The limits a payment to a user must fit within
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaymentLimits {
+    pub user: InFlightLimits,
+    pub server: InFlightLimits,
+}
+
+impl PaymentLimits {
+    /// Payments without a user only have the server's limits
+    pub fn new(user: Option<&User>, config: &Config) -> Self {
+        Self {
+            user: user.map_or(InFlightLimits::default(), |user| {
+                user.in_flight_limits(config)
+            }),
+            server: config.in_flight_limits(),
+        }
+    }
+}
+
+/// A payment was refused because too many payments are in flight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InFlightLimitError {
+    /// The user's limits were hit
+    User,
+    /// The server's limits were hit
+    Server,
+}
+
+impl fmt::Display for InFlightLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InFlightLimitError::User => {
+                write!(f, "Too many payments in flight to this user")
+            }
+            InFlightLimitError::Server => write!(f, "Too many payments in flight"),
+        }
+    }
+}
+
+impl std::error::Error for InFlightLimitError {}
+
+/// Checks a payment of `amount_msats` to `username` fits within the limits,
+/// counting the payments in the `states` that hold our liquidity
+pub fn check_in_flight(
+    username: Option<&str>,
+    amount_msats: u64,
+    states: &[PaymentState],
+    limits: &PaymentLimits,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    if let Some(username) = username {
+        let in_flight = Payment::in_flight(Some(username), states, conn)?;
+        if !limits.user.allows(&in_flight, amount_msats) {
+            return Err(InFlightLimitError::User.into());
+        }
+    }
+
+    let in_flight = Payment::in_flight(None, states, conn)?;
+    if !limits.server.allows(&in_flight, amount_msats) {
+        return Err(InFlightLimitError::Server.into());
+    }
+
+    Ok(())
+}
+
+/// Result of trying to claim a payment for forwarding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Claim {
+    Claimed,
+    /// Another handler claimed the payment first
+    Taken,
+    /// Forwarding it now would go over a limit
+    Limited(InFlightLimitError),
+}
+
+/// Claims an accepted payment for forwarding if it fits next to the payments
+/// we are already forwarding. The check and the claim run in one immediate
+/// transaction so concurrent claims can't both take the last slot.
+pub fn claim_forwarding(
+    payment_hash: &str,
+    username: Option<&str>,
+    amount_msats: u64,
+    forward_amount_msats: u64,
+    fee_limit_msats: u64,
+    limits: &PaymentLimits,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<Claim> {
+    conn.immediate_transaction(|conn| {
+        let checked = check_in_flight(
+            username,
+            amount_msats,
+            &[PaymentState::Forwarding],
+            limits,
+            conn,
+        );
+        if let Err(e) = checked {
+            return match e.downcast::<InFlightLimitError>() {
+                Ok(limit) => Ok(Claim::Limited(limit)),
+                Err(e) => Err(e),
+            };
+        }
+
+        let claimed =
+            Payment::mark_forwarding(payment_hash, forward_amount_msats, fee_limit_msats, conn)?;
+        Ok(if claimed {
+            Claim::Claimed
+        } else {
+            Claim::Taken
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::hex::ToHex;
+
+    use super::*;
+    use crate::lightning::InvoiceState;
+    use crate::routes::{get_lnurl_invoice_impl, InvoiceParams};
+    use crate::subscriber::handle_accepted_invoice;
+    use crate::test_utils::{wait_until, TestContext};
+
+    #[test]
+    fn test_in_flight_limits() {
+        let in_flight = InFlight {
+            payments: 2,
+            msats: 20_000,
+        };
+        assert!(InFlightLimits::default().allows(&in_flight, u64::MAX));
+
+        let limits = InFlightLimits {
+            max_payments: Some(3),
+            max_msats: Some(30_000),
+        };
+        assert!(limits.allows(&in_flight, 10_000));
+        assert!(!limits.allows(&in_flight, 10_001));
+
+        let in_flight = InFlight {
+            payments: 3,
+            msats: 0,
+        };
+        assert!(!limits.allows(&in_flight, 1));
+        assert_eq!(limits.to_string(), "3 payments, 30000 msats");
+    }
+
+    #[tokio::test]
+    async fn test_forwarding_in_flight_limits() {
+        let mut config = Config::dummy();
+        config.max_user_in_flight_payments = Some(1);
+        config.in_flight_queue_seconds = 10;
+        let mut ctx = TestContext::new(config);
+
+        let username = String::from("test_user");
+        ctx.create_user(&username);
+
+        // unpaid invoices don't count towards the limit
+        let mut hashes = vec![];
+        for i in 0..3 {
+            hashes.push(ctx.serve_invoice(&username, [12u8 + i; 32], 10_000).await);
+        }
+
+        ctx.node.set_hold_payments(true);
+        let update = ctx.node.accept_htlc(hashes[0]).unwrap();
+        let mut handlers = vec![tokio::spawn(handle_accepted_invoice(
+            update,
+            ctx.state.clone(),
+        ))];
+        wait_until(|| ctx.node.payments().len() == 1).await;
+
+        // the second payment can't be claimed while the first one is in flight
+        let update = ctx.node.accept_htlc(hashes[1]).unwrap();
+        let user = User::get_by_username(&mut ctx.conn, &username);
+        let limits = PaymentLimits::new(user.as_ref(), &ctx.state.config);
+        let claim = claim_forwarding(
+            &hashes[1].to_hex(),
+            Some(&username),
+            10_000,
+            8_900,
+            1_100,
+            &limits,
+            &mut ctx.conn,
+        )
+        .unwrap();
+        assert_eq!(claim, Claim::Limited(InFlightLimitError::User));
+
+        // and waits for it instead of being cancelled
+        handlers.push(tokio::spawn(handle_accepted_invoice(
+            update,
+            ctx.state.clone(),
+        )));
+        wait_until(|| {
+            ctx.payment(&hashes[1]).map_or(false, |payment| {
+                payment.state() == PaymentState::HtlcAccepted
+            })
+        })
+        .await;
+        assert_eq!(ctx.node.payments().len(), 1);
+        assert_eq!(
+            ctx.node.invoice_state(&hashes[1]),
+            Some(InvoiceState::Accepted)
+        );
+
+        // no more invoices are served while the user is at the limit
+        let params = InvoiceParams {
+            amount_msats: 10_000,
+            ..Default::default()
+        };
+        let err = get_lnurl_invoice_impl(
+            username.clone(),
+            params,
+            ctx.node.as_ref(),
+            &ctx.state.config,
+            &mut ctx.conn,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<InFlightLimitError>(),
+            Some(&InFlightLimitError::User)
+        );
+
+        ctx.node.complete_payments();
+        for handler in handlers {
+            handler.await.unwrap();
+        }
+        for hash in &hashes[..2] {
+            assert_eq!(ctx.node.invoice_state(hash), Some(InvoiceState::Settled));
+        }
+
+        // payments that can't be forwarded in time are cancelled
+        let limits = InFlightLimits {
+            max_payments: Some(0),
+            max_msats: None,
+        };
+        User::set_in_flight_limits(&username, Some(&limits), &mut ctx.conn).unwrap();
+        ctx.state.config.in_flight_queue_seconds = 0;
+        let update = ctx.node.accept_htlc(hashes[2]).unwrap();
+        handle_accepted_invoice(update, ctx.state.clone()).await;
+
+        assert_eq!(
+            ctx.node.invoice_state(&hashes[2]),
+            Some(InvoiceState::Canceled)
+        );
+        let payment = ctx.payment(&hashes[2]).unwrap();
+        assert_eq!(payment.state(), PaymentState::Cancelled);
+        assert_eq!(ctx.node.payments().len(), 2);
+    }
+}
@@ -0,0 +1,134 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::State;
+
+/// How often the outbound liquidity of our node is refreshed
+pub const LIQUIDITY_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Last known outbound liquidity of our node, the last value
+/// is kept when refreshing fails so lnd hiccups don't block payers
+#[derive(Default)]
+pub struct LiquidityCache {
+    outbound_msat: Mutex<Option<u64>>,
+}
+
+impl LiquidityCache {
+    /// Most we can send to users, None until the first refresh
+    pub fn outbound_msat(&self) -> Option<u64> {
+        *self.outbound_msat.lock().unwrap()
+    }
+
+    pub fn set_outbound_msat(&self, outbound_msat: u64) {
+        *self.outbound_msat.lock().unwrap() = Some(outbound_msat);
+    }
+
+    /// Caps `max_sendable` by what we can send, payers' amounts include
+    /// our fee so what we forward and the routing fee always fit
+    pub fn max_sendable(&self, max_sendable: u64) -> u64 {
+        self.outbound_msat()
+            .map_or(max_sendable, |outbound| outbound.min(max_sendable))
+    }
+}
+
+pub async fn refresh_liquidity(state: &State) {
+    match state.lightning.outbound_liquidity().await {
+        Ok(outbound_msat) => state.liquidity.set_outbound_msat(outbound_msat),
+        Err(e) => println!("Failed to refresh outbound liquidity: {e}"),
+    }
+}
+
+/// Keeps the outbound liquidity fresh, payments refresh it as well
+pub async fn start_liquidity_refresh(state: State) {
+    loop {
+        refresh_liquidity(&state).await;
+        tokio::time::sleep(LIQUIDITY_REFRESH_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    use axum::extract::{Path, Query};
+    use axum::http::StatusCode;
+    use axum::Extension;
+    use lightning_invoice::Bolt11Invoice;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::models::invoice::Invoice;
+    use crate::routes::{get_lnurl_invoice, get_lnurlp, InvoiceRequestError};
+    use crate::test_utils::TestContext;
+
+    #[test]
+    fn test_max_sendable() {
+        let cache = LiquidityCache::default();
+        assert_eq!(cache.outbound_msat(), None);
+        assert_eq!(cache.max_sendable(100_000), 100_000);
+
+        cache.set_outbound_msat(50_000);
+        assert_eq!(cache.max_sendable(100_000), 50_000);
+        assert_eq!(cache.max_sendable(10_000), 10_000);
+    }
+
+    #[tokio::test]
+    async fn test_outbound_liquidity() {
+        let mut ctx = TestContext::new(Config::dummy());
+
+        let username = String::from("test_user");
+        ctx.create_user(&username);
+
+        let user_invoice = ctx.add_user_invoice(&username, [15u8; 32]);
+
+        // unknown liquidity doesn't limit payers
+        let res = get_lnurlp(Path(username.clone()), Extension(ctx.state.clone()))
+            .await
+            .unwrap();
+        assert_eq!(res.pay.max_sendable, ctx.state.config.max_sendable);
+
+        ctx.node.set_outbound_liquidity(50_000);
+        refresh_liquidity(&ctx.state).await;
+        let res = get_lnurlp(Path(username.clone()), Extension(ctx.state.clone()))
+            .await
+            .unwrap();
+        assert_eq!(res.pay.max_sendable, 50_000);
+
+        // amounts we can't send are rejected before serving an invoice
+        let params = HashMap::from([(String::from("amount"), String::from("60000"))]);
+        let (status, err) = get_lnurl_invoice(
+            Path(username.clone()),
+            Query(params),
+            Extension(ctx.state.clone()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            err.0["reason"],
+            InvoiceRequestError::AmountTooLarge(50_000).to_string()
+        );
+        let invoice = Invoice::peek_next_invoice(&username, &mut ctx.conn).unwrap();
+        assert_eq!(invoice.map(|inv| inv.invoice()), Some(user_invoice));
+
+        let params = HashMap::from([(String::from("amount"), String::from("50000"))]);
+        let res = get_lnurl_invoice(
+            Path(username.clone()),
+            Query(params),
+            Extension(ctx.state.clone()),
+        )
+        .await
+        .unwrap();
+        let hold_invoice = Bolt11Invoice::from_str(&res.pr).unwrap();
+        assert_eq!(hold_invoice.amount_milli_satoshis(), Some(50_000));
+
+        // nothing can be sent
+        ctx.node.set_outbound_liquidity(0);
+        refresh_liquidity(&ctx.state).await;
+        let (status, _) = get_lnurlp(Path(username), Extension(ctx.state.clone()))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -1,5 +1,6 @@
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 
 use axum::http::{StatusCode, Uri};
@@ -13,46 +14,69 @@ use diesel::SqliteConnection;
 use diesel_migrations::MigrationHarness;
 use tokio::task::spawn;
 use tonic_openssl_lnd::lnrpc::{GetInfoRequest, GetInfoResponse};
-use tonic_openssl_lnd::LndInvoicesClient;
 
 use crate::config::*;
+use crate::fees::FeePolicy;
+use crate::forwards::ActiveForwards;
+use crate::health::SubscriptionHealth;
+use crate::lightning::lnd::LndBackend;
+use crate::lightning::LightningBackend;
+use crate::limits::InFlightLimits;
+use crate::liquidity::{start_liquidity_refresh, LiquidityCache};
+use crate::maintenance::{run_maintenance, start_invoice_sweeper, start_maintenance};
+use crate::models::ledger::LedgerEntry;
+use crate::models::payment::{ArchivedPayment, Payment, PaymentState};
+use crate::models::user::User;
 use crate::models::MIGRATIONS;
+use crate::nostr::{start_zap_publisher, ZapPublisher};
+use crate::reachability::ReachabilityCache;
 use crate::routes::index;
 use crate::subscriber::*;
 
 mod config;
+mod fees;
+mod forwards;
+mod health;
+mod lightning;
+mod limits;
+mod liquidity;
+mod maintenance;
 mod models;
 mod nostr;
+mod payer_data;
+mod reachability;
+mod relay_pool;
 mod routes;
+mod routing;
 mod subscriber;
+#[cfg(test)]
+mod test_utils;
 
 #[derive(Clone)]
 pub struct State {
     connection_string: String,
     config: Config,
-    invoice_client: LndInvoicesClient,
+    lightning: Arc<dyn LightningBackend>,
     db_pool: Pool<ConnectionManager<SqliteConnection>>,
+    zap_publisher: Arc<ZapPublisher>,
+    subscription_health: Arc<SubscriptionHealth>,
+    reachability: Arc<ReachabilityCache>,
+    liquidity: Arc<LiquidityCache>,
+    forwards: Arc<ActiveForwards>,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let config: Config = Config::parse();
+    let mut config: Config = Config::parse();
+    config.apply_deprecated_fee_rate();
 
-    let mut client = tonic_openssl_lnd::connect(
-        config.lnd_host.clone(),
-        config.lnd_port,
-        config.cert_file(),
-        config.macaroon_file(),
-    )
-    .await
-    .expect("failed to connect");
-
-    let mut ln_client = client.lightning().clone();
-    let lnd_info: GetInfoResponse = ln_client
-        .get_info(GetInfoRequest {})
-        .await
-        .expect("Failed to get lnd info")
-        .into_inner();
+    // Fail early on an invalid fee policy
+    FeePolicy::new(
+        config.base_fee,
+        config.fee_ppm,
+        config.min_fee,
+        config.max_fee,
+    )?;
 
     // Create the database if it doesn't exist
     if let Some(parent_dir) = PathBuf::from(&config.db_path).parent() {
@@ -76,6 +100,91 @@ async fn main() -> anyhow::Result<()> {
         .run_pending_migrations(MIGRATIONS)
         .expect("migrations could not run");
 
+    match config.command.clone() {
+        Some(Command::Maintenance) => {
+            let report = run_maintenance(&config, connection)?;
+            println!("Maintenance {report}");
+            return Ok(());
+        }
+        Some(Command::Payments { hash, state }) => {
+            let state = state.as_deref().map(PaymentState::from_str).transpose()?;
+            if let Some(hash) = hash.as_deref() {
+                for payment in ArchivedPayment::get_by_payment_hash(hash, connection)? {
+                    println!("{payment}");
+                }
+            }
+            let payments = Payment::find(hash.as_deref(), state, connection)?;
+            for payment in payments.iter() {
+                println!("{payment}");
+            }
+            println!("{} payments", payments.len());
+            return Ok(());
+        }
+        Some(Command::Ledger { username }) => {
+            let entries = LedgerEntry::get(username.as_deref(), connection)?;
+            for entry in entries.iter() {
+                println!("{entry}");
+            }
+            println!("{} ledger entries", entries.len());
+            return Ok(());
+        }
+        Some(Command::SetUserFee {
+            username,
+            base_fee,
+            fee_ppm,
+            min_fee,
+            max_fee,
+        }) => {
+            let fee_policy = FeePolicy::new(base_fee, fee_ppm, min_fee, max_fee)?;
+            User::set_fee_policy(&username, Some(&fee_policy), connection)?;
+            println!("Fee for {username} set to {fee_policy}");
+            return Ok(());
+        }
+        Some(Command::ClearUserFee { username }) => {
+            User::set_fee_policy(&username, None, connection)?;
+            println!("Fee for {username} set to {}", config.fee_policy());
+            return Ok(());
+        }
+        Some(Command::SetUserLimits {
+            username,
+            max_payments,
+            max_msats,
+        }) => {
+            let limits = InFlightLimits {
+                max_payments,
+                max_msats,
+            };
+            User::set_in_flight_limits(&username, Some(&limits), connection)?;
+            println!("In-flight limits for {username} set to {limits}");
+            return Ok(());
+        }
+        Some(Command::ClearUserLimits { username }) => {
+            User::set_in_flight_limits(&username, None, connection)?;
+            println!(
+                "In-flight limits for {username} set to {}",
+                config.user_in_flight_limits()
+            );
+            return Ok(());
+        }
+        None => {}
+    }
+
+    let mut client = tonic_openssl_lnd::connect(
+        config.lnd_host.clone(),
+        config.lnd_port,
+        config.cert_file(),
+        config.macaroon_file(),
+    )
+    .await
+    .expect("failed to connect");
+
+    let mut ln_client = client.lightning().clone();
+    let lnd_info: GetInfoResponse = ln_client
+        .get_info(GetInfoRequest {})
+        .await
+        .expect("Failed to get lnd info")
+        .into_inner();
+
     let lnd_network_str = &lnd_info.chains.first().unwrap().network;
     match lnd_network_str.to_lowercase().as_str() {
         "mainnet" | "bitcoin" => {
@@ -105,6 +214,12 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 
+    let lightning: Arc<dyn LightningBackend> = Arc::new(LndBackend::new(
+        client.lightning().clone(),
+        client.invoices().clone(),
+        client.router().clone(),
+    ));
+
     let state = State {
         connection_string: lnd_info
             .uris
@@ -112,30 +227,33 @@ async fn main() -> anyhow::Result<()> {
             .expect("Lightning node needs a public uri")
             .clone(),
         config: config.clone(),
-        invoice_client: client.invoices().clone(),
+        lightning,
         db_pool: db_pool.clone(),
+        zap_publisher: Arc::new(ZapPublisher::new(&config)),
+        subscription_health: Arc::new(SubscriptionHealth::default()),
+        reachability: Arc::new(ReachabilityCache::default()),
+        liquidity: Arc::new(LiquidityCache::default()),
+        forwards: Arc::new(ActiveForwards::default()),
     };
 
-    let lightning_client = client.lightning().clone();
-    let router_client = client.router().clone();
-    let invoice_client = client.invoices().clone();
+    // Catch up on payments that progressed while we were down
+    reconcile_payments(&state).await?;
+    start_active_invoice_subscriptions(state.clone()).await?;
 
-    start_active_invoice_subscriptions(
-        router_client.clone(),
-        invoice_client.clone(),
-        config.clone(),
-        db_pool.clone(),
-    )
-    .await?;
+    // Publish zap receipts, including ones that failed or were interrupted
+    spawn(start_zap_publisher(state.zap_publisher.clone(), db_pool));
 
     // Invoice event stream
-    spawn(start_invoice_subscription(
-        lightning_client,
-        router_client,
-        invoice_client,
-        config.clone(),
-        db_pool,
-    ));
+    spawn(start_invoice_subscription(state.clone()));
+
+    // Return invoices of abandoned payments to the pool
+    spawn(start_invoice_sweeper(state.clone()));
+
+    // Garbage collect old invoices and payments
+    spawn(start_maintenance(state.clone()));
+
+    // Track what we can send so payers aren't served invoices we can't forward
+    spawn(start_liquidity_refresh(state.clone()));
 
     let addr: std::net::SocketAddr = format!("{}:{}", config.bind, config.port)
         .parse()
@@ -143,13 +261,41 @@ async fn main() -> anyhow::Result<()> {
 
     println!("Webserver running on http://{}", addr);
 
+    let operator_addr: std::net::SocketAddr =
+        format!("{}:{}", config.operator_bind, config.operator_port)
+            .parse()
+            .expect("Failed to parse bind/port for operator endpoints");
+
+    println!("Operator endpoints running on http://{}", operator_addr);
+
+    // these expose our relays and lnd connection, keep them off the public address
+    let operator_router = Router::new()
+        .route("/relay-stats", get(routes::relay_stats))
+        .route("/health", get(routes::health))
+        .fallback(fallback)
+        .layer(Extension(state.clone()));
+
+    let operator_server =
+        axum::Server::bind(&operator_addr).serve(operator_router.into_make_service());
+    spawn(async move {
+        if let Err(e) = operator_server.await {
+            eprintln!("operator endpoints error: {}", e);
+        }
+    });
+
     let server_router = Router::new()
         .route("/", get(index))
         .route("/create-user", post(routes::create_user))
         .route("/check-user", get(routes::check_user))
         .route("/.well-known/lnurlp/:username", get(routes::get_lnurlp))
         .route("/lnurlp/:username", get(routes::get_lnurl_invoice))
+        .route(
+            "/lnurlp/:username/verify/:request_id",
+            get(routes::verify_payment),
+        )
         .route("/add-invoices", post(routes::add_invoices))
+        .route("/update-settings", post(routes::update_settings))
+        .route("/payments", get(routes::get_payments))
         .fallback(fallback)
         .layer(Extension(state));
 
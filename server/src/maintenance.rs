@@ -0,0 +1,485 @@
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use bitcoin::hashes::hex::ToHex;
+use diesel::{Connection, SqliteConnection};
+
+use crate::config::Config;
+use crate::lightning::InvoiceState;
+use crate::models::invoice::Invoice;
+use crate::models::ledger::LedgerEntry;
+use crate::models::payment::{ArchivedPayment, Payment, PaymentState};
+use crate::models::zap::Zap;
+use crate::subscriber::handle_accepted_invoice;
+use crate::State;
+
+/// How often reservations of unpaid invoices are checked for expiry
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// How often old invoices and payments are garbage collected
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(3_600);
+
+const SECONDS_PER_DAY: i64 = 86_400;
+/// Rows deleted per statement, to stay below sqlite's variable limit
+const DELETE_BATCH_SIZE: usize = 500;
+
+pub async fn start_invoice_sweeper(state: State) {
+    loop {
+        match release_expired_invoices(&state).await {
+            Ok(report) if report == SweepReport::default() => {}
+            Ok(report) => println!("Sweep {report}"),
+            Err(e) => println!("Failed to release expired invoices: {e}"),
+        }
+
+        tokio::time::sleep(SWEEP_INTERVAL).await;
+    }
+}
+
+/// What a sweep did with the reservations of unpaid invoices
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SweepReport {
+    /// Invoices returned to the user's pool
+    pub released: usize,
+    /// Invoices kept out of the pool while lnd still knows their payment hash
+    pub held_back: usize,
+}
+
+impl fmt::Display for SweepReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "released {} and held back {} expired invoices",
+            self.released, self.held_back
+        )
+    }
+}
+
+enum Sweep {
+    Released,
+    HeldBack,
+    Skipped,
+}
+
+/// Time after the wrapped invoice expired before we cancel its hold invoice,
+/// lnd cancels expired invoices on its own and payments can arrive late
+const CANCEL_GRACE_SECS: i64 = 60;
+
+/// Clears the reservations of invoices served to payers that never paid,
+/// once their hold invoice can no longer be paid.
+///
+/// A hold invoice can't be created twice for a payment hash, so invoices only
+/// return to the user's pool once lnd forgot the cancelled hold invoice, with
+/// `gc-canceled-invoices-on-the-fly` or on its next start with
+/// `gc-canceled-invoices-on-startup`. Until then their request is ended but
+/// the invoice is held back.
+pub async fn release_expired_invoices(state: &State) -> anyhow::Result<SweepReport> {
+    let expired = Invoice::get_expired_reservations(&mut state.db_pool.get()?)?;
+    let mut report = SweepReport::default();
+
+    for inv in expired {
+        match release_expired_invoice(&inv, state).await {
+            Ok(Sweep::Released) => report.released += 1,
+            Ok(Sweep::HeldBack) => report.held_back += 1,
+            Ok(Sweep::Skipped) => {}
+            Err(e) => println!(
+                "Failed to release invoice {}: {e}",
+                inv.payment_hash().to_hex()
+            ),
+        }
+    }
+
+    Ok(report)
+}
+
+async fn release_expired_invoice(inv: &Invoice, state: &State) -> anyhow::Result<Sweep> {
+    let invoice_hash = inv.payment_hash();
+    let hash = invoice_hash.to_hex();
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs() as i64;
+
+    // keeps handlers of accepted events from forwarding the payment while we
+    // cancel its hold invoice, we leave it alone if one already is
+    let Some(forward) = state.forwards.start(invoice_hash) else {
+        return Ok(Sweep::Skipped);
+    };
+    let payment = Payment::get_by_payment_hash(&hash, &mut state.db_pool.get()?)?;
+    if payment.map_or(false, |payment| {
+        matches!(
+            payment.state(),
+            PaymentState::Forwarding | PaymentState::Forwarded
+        )
+    }) {
+        return Ok(Sweep::Skipped);
+    }
+
+    let known_to_lnd = match state.lightning.lookup_invoice(invoice_hash).await? {
+        None => false,
+        Some(update) => match update.state {
+            InvoiceState::Canceled => true,
+            // give lnd and late payers some time
+            InvoiceState::Open
+                if inv.wrapped_expiry.unwrap_or_default() + CANCEL_GRACE_SECS > now =>
+            {
+                return Ok(Sweep::Skipped)
+            }
+            InvoiceState::Open => {
+                state.lightning.cancel_invoice(invoice_hash).await?;
+                state
+                    .lightning
+                    .lookup_invoice(invoice_hash)
+                    .await?
+                    .is_some()
+            }
+            // paid before we looked, its handler may have dropped the accepted
+            // event while we held the payment
+            InvoiceState::Accepted => {
+                drop(forward);
+                tokio::spawn(handle_accepted_invoice(update, state.clone()));
+                return Ok(Sweep::Skipped);
+            }
+            // already settled, the subscriptions handle these
+            InvoiceState::Settled => return Ok(Sweep::Skipped),
+        },
+    };
+
+    let wrapped = inv
+        .wrapped_invoice()
+        .map(|wrapped| wrapped.to_string())
+        .unwrap_or_default();
+    let db = &mut state.db_pool.get()?;
+    db.transaction(|conn| {
+        // expired invoices can't be served again either way
+        if known_to_lnd && inv.expires_at > now {
+            Payment::mark_cancelled(&hash, "wrapped invoice expired", conn)?;
+            if !Payment::archive(&hash, &wrapped, conn)? {
+                return Ok(Sweep::Skipped);
+            }
+            Zap::delete(&hash, conn)?;
+            return Ok(Sweep::HeldBack);
+        }
+
+        if !Invoice::release_reservation(&hash, conn)? {
+            return Ok(Sweep::Skipped);
+        }
+        Zap::delete(&hash, conn)?;
+        Payment::mark_cancelled(&hash, "wrapped invoice expired", conn)?;
+        // the next request for the hash gets a payment of its own
+        Payment::archive(&hash, &wrapped, conn)?;
+
+        Ok(Sweep::Released)
+    })
+}
+
+/// What a maintenance run removed from the database
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    /// Invoices that expired without being used
+    pub expired_invoices: usize,
+    /// Reserved invoices whose payment was cancelled or failed
+    pub abandoned_invoices: usize,
+    /// Settled payments compacted into the ledger
+    pub compacted_payments: usize,
+    /// Payments of earlier requests removed from the history
+    pub pruned_history: usize,
+}
+
+impl fmt::Display for MaintenanceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "removed {} expired and {} abandoned invoices, \
+            compacted {} settled payments into the ledger, \
+            pruned {} payments from the history",
+            self.expired_invoices,
+            self.abandoned_invoices,
+            self.compacted_payments,
+            self.pruned_history
+        )
+    }
+}
+
+pub async fn start_maintenance(state: State) {
+    loop {
+        let report = state
+            .db_pool
+            .get()
+            .map_err(anyhow::Error::from)
+            .and_then(|mut db| run_maintenance(&state.config, &mut db));
+
+        match report {
+            Ok(report) => println!("Maintenance {report}"),
+            Err(e) => println!("Failed to run maintenance: {e}"),
+        }
+
+        tokio::time::sleep(MAINTENANCE_INTERVAL).await;
+    }
+}
+
+/// Deletes invoices that expired unused or whose payment ended without being
+/// released, and compacts old settled payments into the ledger, once they
+/// are older than the configured retention.
+///
+/// Compacted payments no longer show up in the user's payment history, settled
+/// payments are kept until their zap receipt is published.
+pub fn run_maintenance(
+    config: &Config,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<MaintenanceReport> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs() as i64;
+    let invoice_cutoff = now - config.invoice_retention_days as i64 * SECONDS_PER_DAY;
+    let payment_cutoff = now - config.payment_retention_days as i64 * SECONDS_PER_DAY;
+
+    conn.transaction(|conn| {
+        let expired: Vec<String> = Invoice::get_expired_unused(invoice_cutoff, conn)?
+            .iter()
+            .map(|inv| inv.payment_hash().to_hex())
+            .collect();
+        delete_invoices(&expired, conn)?;
+
+        let mut abandoned = Vec::new();
+        for inv in Invoice::get_abandoned_reservations(invoice_cutoff, conn)? {
+            let hash = inv.payment_hash().to_hex();
+            let wrapped = inv
+                .wrapped_invoice()
+                .map(|wrapped| wrapped.to_string())
+                .unwrap_or_default();
+            Payment::archive(&hash, &wrapped, conn)?;
+            abandoned.push(hash);
+        }
+        delete_invoices(&abandoned, conn)?;
+
+        let mut settled = Vec::new();
+        for payment in Payment::get_settled_before(payment_cutoff, conn)? {
+            let hash = payment.payment_hash().to_hex();
+            // the zap publisher still needs the invoice
+            if Zap::is_unpublished(&hash, conn)? {
+                continue;
+            }
+            LedgerEntry::from_payment(&payment).record(conn)?;
+            settled.push(hash);
+        }
+        delete_invoices(&settled, conn)?;
+
+        let pruned_history = ArchivedPayment::delete_before(payment_cutoff, conn)?;
+
+        Ok(MaintenanceReport {
+            expired_invoices: expired.len(),
+            abandoned_invoices: abandoned.len(),
+            compacted_payments: settled.len(),
+            pruned_history,
+        })
+    })
+}
+
+/// Deletes invoices along with their payment and zap request
+fn delete_invoices(payment_hashes: &[String], conn: &mut SqliteConnection) -> anyhow::Result<()> {
+    for batch in payment_hashes.chunks(DELETE_BATCH_SIZE) {
+        Payment::delete_all(batch, conn)?;
+        for hash in batch {
+            Zap::delete(hash, conn)?;
+        }
+        Invoice::delete_all(batch, conn)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+
+    use super::*;
+    use crate::models::schema::invoices;
+    use crate::routes::{get_lnurl_invoice_impl, verify_payment_impl, InvoiceParams};
+    use crate::test_utils::TestContext;
+
+    #[tokio::test]
+    async fn test_release_expired_invoices() {
+        let mut ctx = TestContext::new(Config::dummy());
+
+        // lnd forgets the hold invoice once it is cancelled
+        ctx.node.set_gc_canceled(true);
+
+        let username = String::from("test_user");
+        ctx.create_user(&username);
+
+        ctx.add_user_invoice(&username, [7u8; 32]);
+
+        let params = InvoiceParams {
+            amount_msats: 10_000,
+            comment: Some(String::from("thanks!")),
+            ..Default::default()
+        };
+        let hold_invoice = get_lnurl_invoice_impl(
+            username.clone(),
+            params,
+            ctx.node.as_ref(),
+            &ctx.state.config,
+            &mut ctx.conn,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        let hash = *hold_invoice.payment_hash();
+        assert_eq!(
+            Invoice::get_num_invoices_available(&username, &mut ctx.conn).unwrap(),
+            0
+        );
+
+        // the wrapped invoice has not expired yet
+        assert_eq!(
+            release_expired_invoices(&ctx.state).await.unwrap(),
+            SweepReport::default()
+        );
+        assert_eq!(ctx.node.invoice_state(&hash), Some(InvoiceState::Open));
+
+        // lnd and late payers get some time after the wrapped invoice expired
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        diesel::update(invoices::table.find(hash.to_hex()))
+            .set(invoices::wrapped_expiry.eq(Some(now - 10)))
+            .execute(&mut ctx.conn)
+            .unwrap();
+        assert_eq!(
+            release_expired_invoices(&ctx.state).await.unwrap(),
+            SweepReport::default()
+        );
+        assert_eq!(ctx.node.invoice_state(&hash), Some(InvoiceState::Open));
+
+        diesel::update(invoices::table.find(hash.to_hex()))
+            .set(invoices::wrapped_expiry.eq(Some(0)))
+            .execute(&mut ctx.conn)
+            .unwrap();
+
+        // payments being forwarded are left alone
+        let forward = ctx.state.forwards.start(hash).unwrap();
+        assert_eq!(
+            release_expired_invoices(&ctx.state).await.unwrap(),
+            SweepReport::default()
+        );
+        assert_eq!(ctx.node.invoice_state(&hash), Some(InvoiceState::Open));
+        drop(forward);
+
+        assert_eq!(
+            release_expired_invoices(&ctx.state).await.unwrap(),
+            SweepReport {
+                released: 1,
+                held_back: 0
+            }
+        );
+        assert_eq!(ctx.node.invoice_state(&hash), None);
+        assert_eq!(
+            Invoice::get_num_invoices_available(&username, &mut ctx.conn).unwrap(),
+            1
+        );
+
+        let invoice = Invoice::get_by_payment_hash(&hash.to_hex(), &mut ctx.conn)
+            .unwrap()
+            .unwrap();
+        assert!(!invoice.is_used());
+        assert_eq!(invoice.comment, None);
+        assert_eq!(invoice.wrapped_invoice(), None);
+
+        // the payment of the abandoned request is kept
+        assert!(ctx.payment(&hash).is_none());
+        let history = ArchivedPayment::get_by_payment_hash(&hash.to_hex(), &mut ctx.conn).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].state(), PaymentState::Cancelled);
+        let request_id = history[0].request_id.clone();
+
+        // nothing left to release
+        assert_eq!(
+            release_expired_invoices(&ctx.state).await.unwrap(),
+            SweepReport::default()
+        );
+
+        // the invoice is served again to another payer
+        let params = InvoiceParams {
+            amount_msats: 20_000,
+            ..Default::default()
+        };
+        let served = get_lnurl_invoice_impl(
+            username.clone(),
+            params,
+            ctx.node.as_ref(),
+            &ctx.state.config,
+            &mut ctx.conn,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(*served.payment_hash(), hash);
+        let payment = ctx.payment(&hash).unwrap();
+        assert_ne!(payment.request_id, request_id);
+
+        let update = ctx.node.accept_htlc(hash).unwrap();
+        handle_accepted_invoice(update, ctx.state.clone()).await;
+        let verify = verify_payment_impl(&username, &payment.request_id, &mut ctx.conn)
+            .unwrap()
+            .unwrap();
+        assert!(verify.settled);
+
+        // the first payer's request still reports its own unpaid invoice
+        let verify = verify_payment_impl(&username, &request_id, &mut ctx.conn)
+            .unwrap()
+            .unwrap();
+        assert!(!verify.settled);
+        assert_eq!(verify.preimage, None);
+        assert_eq!(verify.pr, hold_invoice.to_string());
+
+        // invoices lnd still knows can't be served again and are held back
+        ctx.node.set_gc_canceled(false);
+        let hash = ctx.serve_invoice(&username, [8u8; 32], 10_000).await;
+        diesel::update(invoices::table.find(hash.to_hex()))
+            .set(invoices::wrapped_expiry.eq(Some(0)))
+            .execute(&mut ctx.conn)
+            .unwrap();
+
+        assert_eq!(
+            release_expired_invoices(&ctx.state).await.unwrap(),
+            SweepReport {
+                released: 0,
+                held_back: 1
+            }
+        );
+        assert_eq!(ctx.node.invoice_state(&hash), Some(InvoiceState::Canceled));
+        assert!(Invoice::get_by_payment_hash(&hash.to_hex(), &mut ctx.conn)
+            .unwrap()
+            .is_some());
+        let history = ArchivedPayment::get_by_payment_hash(&hash.to_hex(), &mut ctx.conn).unwrap();
+        assert_eq!(history[0].state(), PaymentState::Cancelled);
+        assert_eq!(
+            Invoice::get_num_invoices_available(&username, &mut ctx.conn).unwrap(),
+            0
+        );
+
+        // until the user's invoice expires
+        assert_eq!(
+            release_expired_invoices(&ctx.state).await.unwrap(),
+            SweepReport::default()
+        );
+        diesel::update(invoices::table.find(hash.to_hex()))
+            .set(invoices::expires_at.eq(0))
+            .execute(&mut ctx.conn)
+            .unwrap();
+        assert_eq!(
+            release_expired_invoices(&ctx.state).await.unwrap(),
+            SweepReport {
+                released: 1,
+                held_back: 0
+            }
+        );
+        let invoice = Invoice::get_by_payment_hash(&hash.to_hex(), &mut ctx.conn)
+            .unwrap()
+            .unwrap();
+        assert!(!invoice.is_used());
+        assert_eq!(
+            Invoice::get_num_invoices_available(&username, &mut ctx.conn).unwrap(),
+            0
+        );
+    }
+}
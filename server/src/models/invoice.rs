@@ -1,12 +1,17 @@
 use std::str::FromStr;
 use std::time::SystemTime;
 
-use bitcoin::hashes::hex::ToHex;
+use std::convert::TryInto;
+
+use anyhow::anyhow;
+use bitcoin::hashes::hex::{FromHex, ToHex};
 use bitcoin::hashes::sha256::Hash as Sha256;
 use diesel::prelude::*;
 use lightning_invoice::Bolt11Invoice;
+use zap_tunnel_client::{AesPayload, PayerData};
 
-use super::schema::invoices;
+use super::payment::PaymentState;
+use super::schema::{invoices, payments};
 
 #[derive(Queryable, AsChangeset, Insertable, Identifiable, Debug, Clone, PartialEq)]
 #[diesel(primary_key(payment_hash))]
@@ -17,6 +22,19 @@ pub struct Invoice {
     pub wrapped_expiry: Option<i64>,
     fees_earned: Option<i64>,
     username: Option<String>,
+    preimage: Option<String>,
+    /// Amount of the wrapped invoice served to the payer
+    pub amount_msats: Option<i64>,
+    /// LUD-12 comment from the payer
+    pub comment: Option<String>,
+    /// LUD-18 payer data, as sent by the payer
+    payer_data: Option<String>,
+    /// Hold invoice served to the payer
+    wrapped_invoice: Option<String>,
+    /// LUD-10 success action encrypted by the user with the preimage
+    aes_payload: Option<String>,
+    /// When the hold invoice was settled
+    settled_at: Option<i64>,
 }
 
 pub const DEFAULT_INVOICE_EXPIRY: i64 = 360;
@@ -35,6 +53,13 @@ impl Invoice {
             wrapped_expiry: None,
             fees_earned: None,
             username: username.map(String::from),
+            preimage: None,
+            amount_msats: None,
+            comment: None,
+            payer_data: None,
+            wrapped_invoice: None,
+            aes_payload: None,
+            settled_at: None,
         }
     }
 
@@ -54,14 +79,74 @@ impl Invoice {
         self.fees_earned.is_some()
     }
 
+    pub fn is_settled(&self) -> bool {
+        self.settled_at.is_some()
+    }
+
     pub fn username(&self) -> Option<String> {
         self.username.clone()
     }
 
+    /// Preimage revealed by paying the invoice
+    pub fn preimage(&self) -> Option<[u8; 32]> {
+        self.preimage.as_ref().map(|p| {
+            let bytes = Vec::from_hex(p).expect("invalid preimage");
+            bytes
+                .as_slice()
+                .try_into()
+                .expect("invalid preimage length")
+        })
+    }
+
+    /// LUD-18 payer data sent by the payer
+    pub fn payer_data(&self) -> Option<PayerData> {
+        self.payer_data
+            .as_ref()
+            .map(|json| serde_json::from_str(json).expect("invalid payer data"))
+    }
+
+    /// Hold invoice served to the payer
+    pub fn wrapped_invoice(&self) -> Option<Bolt11Invoice> {
+        self.wrapped_invoice
+            .as_ref()
+            .map(|inv| Bolt11Invoice::from_str(inv).expect("invalid wrapped invoice"))
+    }
+
+    /// LUD-10 success action encrypted by the user with the preimage
+    pub fn aes_payload(&self) -> Option<AesPayload> {
+        self.aes_payload
+            .as_ref()
+            .map(|json| serde_json::from_str(json).expect("invalid aes payload"))
+    }
+
+    pub fn set_aes_payload(&mut self, aes_payload: &AesPayload) -> anyhow::Result<()> {
+        self.aes_payload = Some(serde_json::to_string(aes_payload)?);
+        Ok(())
+    }
+
     pub fn set_wrapped_expiry(&mut self, wrapped_expiry: i64) {
         self.wrapped_expiry = Some(wrapped_expiry);
     }
 
+    /// The invoice [`Invoice::get_next_invoice`] would reserve, without reserving it
+    pub fn peek_next_invoice(
+        username: &str,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<Option<Self>> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs() as i64;
+
+        Ok(invoices::table
+            .filter(invoices::username.eq(username))
+            .filter(invoices::fees_earned.is_null())
+            .filter(invoices::wrapped_expiry.is_null())
+            .filter(invoices::expires_at.gt(now))
+            .order(invoices::expires_at.asc())
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
     pub fn get_next_invoice(username: &str, conn: &mut SqliteConnection) -> anyhow::Result<Self> {
         let now = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)?
@@ -70,13 +155,8 @@ impl Invoice {
         let w_expiry = now + DEFAULT_INVOICE_EXPIRY;
 
         conn.transaction(|conn| {
-            let mut inv = invoices::table
-                .filter(invoices::username.eq(username))
-                .filter(invoices::fees_earned.is_null())
-                .filter(invoices::wrapped_expiry.is_null())
-                .filter(invoices::expires_at.gt(now))
-                .order(invoices::expires_at.asc())
-                .first::<Self>(conn)?;
+            let mut inv = Self::peek_next_invoice(username, conn)?
+                .ok_or(anyhow!("No invoices left for {username}"))?;
 
             inv.set_wrapped_expiry(w_expiry);
 
@@ -89,13 +169,80 @@ impl Invoice {
         })
     }
 
+    pub fn get_by_payment_hash(
+        payment_hash: &str,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<Option<Self>> {
+        Ok(invoices::table
+            .find(payment_hash)
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    /// Records the hold invoice served for a reserved invoice
+    /// and the details of the payer's request
+    pub fn set_request_details(
+        wrapped_invoice: &Bolt11Invoice,
+        comment: Option<&str>,
+        payer_data: Option<&str>,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<()> {
+        let amount_msats = wrapped_invoice.amount_milli_satoshis().map(|a| a as i64);
+
+        diesel::update(invoices::table.find(wrapped_invoice.payment_hash().to_hex()))
+            .set((
+                invoices::wrapped_invoice.eq(Some(wrapped_invoice.to_string())),
+                invoices::amount_msats.eq(amount_msats),
+                invoices::comment.eq(comment),
+                invoices::payer_data.eq(payer_data),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Invoices of the user that have been paid
+    pub fn get_paid_invoices(
+        username: &str,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<Vec<Self>> {
+        let invoices = invoices::table
+            .filter(invoices::username.eq(username))
+            .filter(invoices::fees_earned.is_not_null())
+            .load::<Self>(conn)?;
+
+        Ok(invoices)
+    }
+
+    pub fn fees_earned(&self) -> Option<i64> {
+        self.fees_earned
+    }
+
+    /// Records that the user's invoice was paid, this must happen
+    /// before settling so the preimage survives a crash.
     pub fn mark_invoice_paid(
         payment_hash: &str,
         fees_earned: i64,
+        preimage: &[u8; 32],
         conn: &mut SqliteConnection,
     ) -> anyhow::Result<()> {
         diesel::update(invoices::table.filter(invoices::payment_hash.eq(&payment_hash)))
-            .set(invoices::fees_earned.eq(Some(fees_earned)))
+            .set((
+                invoices::fees_earned.eq(Some(fees_earned)),
+                invoices::preimage.eq(Some(preimage.to_hex())),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    pub fn mark_settled(payment_hash: &str, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs() as i64;
+
+        diesel::update(invoices::table.find(payment_hash))
+            .set(invoices::settled_at.eq(Some(now)))
             .execute(conn)?;
 
         Ok(())
@@ -138,6 +285,88 @@ impl Invoice {
         Ok(invoices)
     }
 
+    /// Reserved invoices whose wrapped invoice expired without being paid
+    pub fn get_expired_reservations(conn: &mut SqliteConnection) -> anyhow::Result<Vec<Self>> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs() as i64;
+
+        let invoices = invoices::table
+            .filter(invoices::fees_earned.is_null())
+            .filter(invoices::wrapped_expiry.is_not_null())
+            .filter(invoices::wrapped_expiry.le(now))
+            .load::<Self>(conn)?;
+
+        Ok(invoices)
+    }
+
+    /// Returns a reserved invoice to the user's pool and forgets the request it
+    /// was served for, returns false if it was paid or released in the meantime.
+    pub fn release_reservation(
+        payment_hash: &str,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<bool> {
+        let released = diesel::update(invoices::table.find(payment_hash))
+            .filter(invoices::fees_earned.is_null())
+            .filter(invoices::wrapped_expiry.is_not_null())
+            .set((
+                invoices::wrapped_expiry.eq(None::<i64>),
+                invoices::amount_msats.eq(None::<i64>),
+                invoices::comment.eq(None::<String>),
+                invoices::payer_data.eq(None::<String>),
+                invoices::wrapped_invoice.eq(None::<String>),
+            ))
+            .execute(conn)?;
+
+        Ok(released == 1)
+    }
+
+    /// Reserved invoices served before `before` whose payment was cancelled or
+    /// failed, that the sweeper never released
+    pub fn get_abandoned_reservations(
+        before: i64,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<Vec<Self>> {
+        let ended = payments::table
+            .select(payments::payment_hash)
+            .filter(payments::state.eq_any([
+                PaymentState::Cancelled.as_str(),
+                PaymentState::Failed.as_str(),
+            ]));
+
+        let invoices = invoices::table
+            .filter(invoices::fees_earned.is_null())
+            .filter(invoices::wrapped_expiry.lt(before))
+            .filter(invoices::payment_hash.eq_any(ended))
+            .load::<Self>(conn)?;
+
+        Ok(invoices)
+    }
+
+    /// Invoices that expired before `before` without ever being paid
+    pub fn get_expired_unused(
+        before: i64,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<Vec<Self>> {
+        let invoices = invoices::table
+            .filter(invoices::fees_earned.is_null())
+            .filter(invoices::wrapped_expiry.is_null())
+            .filter(invoices::expires_at.lt(before))
+            .load::<Self>(conn)?;
+
+        Ok(invoices)
+    }
+
+    pub fn delete_all(
+        payment_hashes: &[String],
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<usize> {
+        Ok(
+            diesel::delete(invoices::table.filter(invoices::payment_hash.eq_any(payment_hashes)))
+                .execute(conn)?,
+        )
+    }
+
     #[cfg(test)]
     pub fn update_expiry(&self, conn: &mut SqliteConnection) -> anyhow::Result<()> {
         let now = SystemTime::now()
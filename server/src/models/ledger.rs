@@ -0,0 +1,85 @@
+use std::fmt;
+
+use diesel::prelude::*;
+use diesel::upsert::excluded;
+
+use super::payment::Payment;
+use super::schema::ledger;
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// Totals of a user's settled payments for a day,
+/// kept after the payments themselves are garbage collected
+#[derive(Queryable, Insertable, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = ledger)]
+pub struct LedgerEntry {
+    pub username: String,
+    /// Start of the day, as a unix timestamp
+    pub day: i64,
+    pub payments: i64,
+    pub amount_msats: i64,
+    pub fees_earned_msats: i64,
+    pub routing_fee_msats: i64,
+}
+
+impl fmt::Display for LedgerEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} day {}: {} payments, {} msats, {} msats earned, {} msats routing fees",
+            self.username,
+            self.day,
+            self.payments,
+            self.amount_msats,
+            self.fees_earned_msats,
+            self.routing_fee_msats
+        )
+    }
+}
+
+impl LedgerEntry {
+    /// Ledger entry for a single settled payment
+    pub fn from_payment(payment: &Payment) -> Self {
+        let settled_at = payment.settled_at.unwrap_or(payment.updated_at);
+
+        Self {
+            username: payment.username.clone().unwrap_or_default(),
+            day: settled_at - settled_at.rem_euclid(SECONDS_PER_DAY),
+            payments: 1,
+            amount_msats: payment.amount_msats,
+            fees_earned_msats: payment.fees_earned_msats.unwrap_or_default(),
+            routing_fee_msats: payment.routing_fee_msats.unwrap_or_default(),
+        }
+    }
+
+    /// Recorded totals, of all users when `username` is None, oldest first
+    pub fn get(username: Option<&str>, conn: &mut SqliteConnection) -> anyhow::Result<Vec<Self>> {
+        let mut query = ledger::table
+            .order((ledger::day.asc(), ledger::username.asc()))
+            .into_boxed();
+        if let Some(username) = username {
+            query = query.filter(ledger::username.eq(username));
+        }
+
+        Ok(query.load::<Self>(conn)?)
+    }
+
+    /// Adds the entry to the totals already recorded for its day
+    pub fn record(&self, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+        diesel::insert_into(ledger::table)
+            .values(self)
+            .on_conflict((ledger::username, ledger::day))
+            .do_update()
+            .set((
+                ledger::payments.eq(ledger::payments + excluded(ledger::payments)),
+                ledger::amount_msats.eq(ledger::amount_msats + excluded(ledger::amount_msats)),
+                ledger::fees_earned_msats
+                    .eq(ledger::fees_earned_msats + excluded(ledger::fees_earned_msats)),
+                ledger::routing_fee_msats
+                    .eq(ledger::routing_fee_msats + excluded(ledger::routing_fee_msats)),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+}
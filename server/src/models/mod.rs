@@ -1,6 +1,8 @@
 use diesel_migrations::{embed_migrations, EmbeddedMigrations};
 
 pub mod invoice;
+pub mod ledger;
+pub mod payment;
 pub mod schema;
 pub mod user;
 pub mod zap;
@@ -10,6 +12,7 @@ pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 #[cfg(test)]
 mod test {
     use crate::models::invoice::*;
+    use crate::models::payment::*;
     use crate::models::user::*;
     use crate::models::zap::*;
     use bitcoin::hashes::hex::ToHex;
@@ -118,6 +121,7 @@ mod test {
         assert_eq!(invoice_db.expires_at, expiry);
         assert_eq!(invoice_db.wrapped_expiry, None);
         assert_eq!(invoice_db.username(), Some(test_username));
+        assert_eq!(invoice_db.preimage(), None);
 
         teardown_database(&db_name);
     }
@@ -196,6 +200,96 @@ mod test {
             "d9cc14d50fcb8c27539aacf776882942c1a11ea4472f8cdec1dea82fab66279d"
         );
         assert_eq!(zap.note_id(), None);
+        assert!(zap.published_relays().is_empty());
+
+        teardown_database(&db_name);
+    }
+
+    #[test]
+    fn test_unpublished_zaps() {
+        use super::schema::invoices::dsl::*;
+        use super::schema::zaps::dsl::*;
+        let db_name = gen_tmp_db_name();
+        let conn = &mut create_database(&db_name);
+
+        let inv: Bolt11Invoice = Bolt11Invoice::from_str(INVOICE_STR).unwrap();
+        let zap_request = nostr::Event::from_json("{\"pubkey\":\"32e1827635450ebb3c5a7d12c1f8e7b2b514439ac10a67eef3d9fd9c5c68e245\",\"content\":\"\",\"id\":\"d9cc14d50fcb8c27539aacf776882942c1a11ea4472f8cdec1dea82fab66279d\",\"created_at\":1674164539,\"sig\":\"77127f636577e9029276be060332ea565deaf89ff215a494ccff16ae3f757065e2bc59b2e8c113dd407917a010b3abd36c8d7ad84c0e3ab7dab3a0b0caa9835d\",\"kind\":9734,\"tags\":[[\"e\",\"3624762a1274dd9636e0c552b53086d70bc88c165bc4dc0f9e836a1eaf86c3b8\"],[\"p\",\"32e1827635450ebb3c5a7d12c1f8e7b2b514439ac10a67eef3d9fd9c5c68e245\"],[\"relays\",\"wss://relay.damus.io\"]]}").unwrap();
+
+        diesel::insert_into(invoices::table())
+            .values(&Invoice::new(&inv, None))
+            .execute(conn)
+            .unwrap();
+        let new_zap = Zap::create(Zap::new(&inv, zap_request, None), conn).unwrap();
+
+        // not paid yet
+        assert!(Zap::get_unpublished(0, conn).unwrap().is_empty());
+
+        Invoice::mark_invoice_paid(&inv.payment_hash().to_hex(), 1_000, &[1u8; 32], conn).unwrap();
+        let pending = Zap::get_unpublished(0, conn).unwrap();
+        assert_eq!(pending, vec![new_zap.clone()]);
+
+        // failed attempt is retried after the delay
+        new_zap.record_failure("relays down", 100, conn).unwrap();
+        assert!(Zap::get_unpublished(99, conn).unwrap().is_empty());
+        let pending = Zap::get_unpublished(100, conn).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].attempts, 1);
+        assert_eq!(pending[0].last_error, Some(String::from("relays down")));
+
+        // published zaps are not retried
+        new_zap
+            .mark_published(
+                "d9cc14d50fcb8c27539aacf776882942c1a11ea4472f8cdec1dea82fab66279d",
+                &[String::from("wss://relay.damus.io")],
+                conn,
+            )
+            .unwrap();
+        assert!(Zap::get_unpublished(100, conn).unwrap().is_empty());
+        let zap = zaps
+            .filter(super::schema::zaps::payment_hash.is(inv.payment_hash().to_hex()))
+            .first::<Zap>(conn)
+            .unwrap();
+        assert_eq!(
+            zap.published_relays(),
+            vec![String::from("wss://relay.damus.io")]
+        );
+
+        teardown_database(&db_name);
+    }
+
+    #[test]
+    fn test_payment_transitions() {
+        let db_name = gen_tmp_db_name();
+        let conn = &mut create_database(&db_name);
+
+        let inv: Bolt11Invoice = Bolt11Invoice::from_str(INVOICE_STR).unwrap();
+        let hash = inv.payment_hash().to_hex();
+
+        let payment = Payment::create_reserved(&hash, "test_user", 10_000, conn).unwrap();
+        assert_eq!(payment.state(), PaymentState::Reserved);
+
+        // can't skip states
+        assert!(!Payment::mark_forwarding(&hash, 9_000, 1_000, conn).unwrap());
+        assert!(!Payment::mark_failed(&hash, "no route", conn).unwrap());
+
+        assert!(Payment::mark_htlc_accepted(&hash, conn).unwrap());
+        assert!(Payment::mark_forwarding(&hash, 9_000, 1_000, conn).unwrap());
+        // a forwarding payment can't be claimed again or cancelled
+        assert!(!Payment::mark_forwarding(&hash, 9_000, 1_000, conn).unwrap());
+        assert!(!Payment::mark_cancelled(&hash, "error", conn).unwrap());
+
+        assert!(Payment::mark_failed(&hash, "no route", conn).unwrap());
+        assert!(!Payment::mark_settled(&hash, conn).unwrap());
+
+        let payment = Payment::get_by_payment_hash(&hash, conn).unwrap().unwrap();
+        assert_eq!(payment.state(), PaymentState::Failed);
+        assert_eq!(payment.forward_amount_msats, Some(9_000));
+        assert_eq!(payment.fee_limit_msats, Some(1_000));
+        assert_eq!(payment.failure_reason, Some(String::from("no route")));
+        assert!(payment.htlc_accepted_at.is_some());
+        assert!(payment.forwarding_at.is_some());
+        assert!(payment.failed_at.is_some());
+        assert_eq!(payment.forwarded_at, None);
 
         teardown_database(&db_name);
     }
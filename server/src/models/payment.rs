@@ -0,0 +1,620 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use anyhow::anyhow;
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::secp256k1::rand;
+use diesel::prelude::*;
+
+use super::schema::{payment_history, payments};
+use crate::limits::InFlight;
+
+/// Lifecycle of a payment through a wrapped invoice
+///
+/// reserved -> htlc_accepted -> forwarding -> forwarded -> settled,
+/// reserved and htlc_accepted payments can be cancelled
+/// and forwarding payments can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PaymentState {
+    /// A wrapped invoice was served to a payer
+    Reserved,
+    /// The payer's HTLC is held by the hold invoice
+    HtlcAccepted,
+    /// We are paying the user's invoice
+    Forwarding,
+    /// The user's invoice was paid and the preimage is known
+    Forwarded,
+    /// The hold invoice was settled
+    Settled,
+    /// The hold invoice was cancelled without forwarding
+    Cancelled,
+    /// Paying the user's invoice failed and the hold invoice was cancelled
+    Failed,
+}
+
+impl PaymentState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentState::Reserved => "reserved",
+            PaymentState::HtlcAccepted => "htlc_accepted",
+            PaymentState::Forwarding => "forwarding",
+            PaymentState::Forwarded => "forwarded",
+            PaymentState::Settled => "settled",
+            PaymentState::Cancelled => "cancelled",
+            PaymentState::Failed => "failed",
+        }
+    }
+}
+
+impl fmt::Display for PaymentState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for PaymentState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reserved" => Ok(PaymentState::Reserved),
+            "htlc_accepted" => Ok(PaymentState::HtlcAccepted),
+            "forwarding" => Ok(PaymentState::Forwarding),
+            "forwarded" => Ok(PaymentState::Forwarded),
+            "settled" => Ok(PaymentState::Settled),
+            "cancelled" => Ok(PaymentState::Cancelled),
+            "failed" => Ok(PaymentState::Failed),
+            _ => Err(anyhow!("Unknown payment state: {s}")),
+        }
+    }
+}
+
+fn now() -> anyhow::Result<i64> {
+    Ok(SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs() as i64)
+}
+
+#[derive(Queryable, Insertable, Debug, Clone, PartialEq)]
+#[diesel(primary_key(payment_hash))]
+pub struct Payment {
+    payment_hash: String,
+    pub username: Option<String>,
+    state: String,
+    /// Amount of the wrapped invoice
+    pub amount_msats: i64,
+    /// Amount sent to the user's invoice
+    pub forward_amount_msats: Option<i64>,
+    pub fee_limit_msats: Option<i64>,
+    /// Routing fee we paid to reach the user
+    pub routing_fee_msats: Option<i64>,
+    pub fees_earned_msats: Option<i64>,
+    pub failure_reason: Option<String>,
+    pub reserved_at: i64,
+    pub htlc_accepted_at: Option<i64>,
+    pub forwarding_at: Option<i64>,
+    pub forwarded_at: Option<i64>,
+    pub settled_at: Option<i64>,
+    /// When the payment was cancelled or failed
+    pub failed_at: Option<i64>,
+    pub updated_at: i64,
+    /// Identifies the request the wrapped invoice was served for,
+    /// payment hashes are reused when invoices are released to the pool
+    pub request_id: String,
+    /// Cancelling the hold invoice failed after the payment ended
+    pub cancel_failed: bool,
+}
+
+fn optional<T: fmt::Display>(value: &Option<T>) -> String {
+    value
+        .as_ref()
+        .map_or_else(|| String::from("-"), |value| value.to_string())
+}
+
+impl fmt::Display for Payment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} user: {}, amount: {} msats, forwarded: {} msats, routing fee: {} msats, \
+            earned: {} msats, reserved at: {}, updated at: {}, request: {}",
+            self.payment_hash,
+            self.state,
+            optional(&self.username),
+            self.amount_msats,
+            optional(&self.forward_amount_msats),
+            optional(&self.routing_fee_msats),
+            optional(&self.fees_earned_msats),
+            self.reserved_at,
+            self.updated_at,
+            self.request_id,
+        )?;
+        match self.failure_reason.as_ref() {
+            Some(reason) => write!(f, ", failure: {reason}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Payment {
+    pub fn payment_hash(&self) -> Sha256 {
+        Sha256::from_str(&self.payment_hash).expect("invalid payment hash")
+    }
+
+    pub fn state(&self) -> PaymentState {
+        PaymentState::from_str(&self.state).expect("invalid payment state")
+    }
+
+    /// Records a wrapped invoice being served to a payer
+    pub fn create_reserved(
+        payment_hash: &str,
+        username: &str,
+        amount_msats: u64,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<Self> {
+        let now = now()?;
+        let payment = Self {
+            payment_hash: payment_hash.to_string(),
+            username: Some(username.to_string()),
+            state: PaymentState::Reserved.as_str().to_string(),
+            amount_msats: amount_msats as i64,
+            forward_amount_msats: None,
+            fee_limit_msats: None,
+            routing_fee_msats: None,
+            fees_earned_msats: None,
+            failure_reason: None,
+            reserved_at: now,
+            htlc_accepted_at: None,
+            forwarding_at: None,
+            forwarded_at: None,
+            settled_at: None,
+            failed_at: None,
+            updated_at: now,
+            request_id: rand::random::<[u8; 16]>().to_hex(),
+            cancel_failed: false,
+        };
+
+        diesel::insert_into(payments::table)
+            .values(&payment)
+            .execute(conn)?;
+
+        Ok(payment)
+    }
+
+    pub fn get_by_payment_hash(
+        payment_hash: &str,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<Option<Self>> {
+        Ok(payments::table
+            .find(payment_hash)
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    pub fn get_by_request_id(
+        request_id: &str,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<Option<Self>> {
+        Ok(payments::table
+            .filter(payments::request_id.eq(request_id))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    /// Moves a cancelled or failed payment to the payment history, so its
+    /// invoice can be served again or removed, returns false if it didn't end.
+    pub fn archive(
+        payment_hash: &str,
+        wrapped_invoice: &str,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<bool> {
+        conn.transaction(|conn| {
+            let ended = [
+                PaymentState::Cancelled.as_str(),
+                PaymentState::Failed.as_str(),
+            ];
+            let payment = payments::table
+                .find(payment_hash)
+                .filter(payments::state.eq_any(ended))
+                .first::<Self>(conn)
+                .optional()?;
+            let Some(payment) = payment else {
+                return Ok(false);
+            };
+
+            diesel::insert_into(payment_history::table)
+                .values(&ArchivedPayment::new(payment, wrapped_invoice))
+                .execute(conn)?;
+            diesel::delete(payments::table.find(payment_hash)).execute(conn)?;
+
+            Ok(true)
+        })
+    }
+
+    /// Payments to `payment_hash` and in `state`, any when unset, oldest first
+    pub fn find(
+        payment_hash: Option<&str>,
+        state: Option<PaymentState>,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<Vec<Self>> {
+        let mut query = payments::table
+            .order(payments::reserved_at.asc())
+            .into_boxed();
+        if let Some(payment_hash) = payment_hash {
+            query = query.filter(payments::payment_hash.eq(payment_hash));
+        }
+        if let Some(state) = state {
+            query = query.filter(payments::state.eq(state.as_str()));
+        }
+
+        Ok(query.load::<Self>(conn)?)
+    }
+
+    /// Payments that have not reached a final state
+    pub fn get_unfinished(conn: &mut SqliteConnection) -> anyhow::Result<Vec<Self>> {
+        let finished = [
+            PaymentState::Settled.as_str(),
+            PaymentState::Cancelled.as_str(),
+            PaymentState::Failed.as_str(),
+        ];
+
+        Ok(payments::table
+            .filter(payments::state.ne_all(finished))
+            .load::<Self>(conn)?)
+    }
+
+    /// Ended payments whose hold invoice still has to be cancelled
+    pub fn get_cancel_failed(conn: &mut SqliteConnection) -> anyhow::Result<Vec<Self>> {
+        Ok(payments::table
+            .filter(payments::cancel_failed.eq(true))
+            .load::<Self>(conn)?)
+    }
+
+    /// Settled payments that were settled before `before`
+    pub fn get_settled_before(
+        before: i64,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<Vec<Self>> {
+        Ok(payments::table
+            .filter(payments::state.eq(PaymentState::Settled.as_str()))
+            .filter(payments::settled_at.lt(before))
+            .load::<Self>(conn)?)
+    }
+
+    /// Payments in the `states` and the sum of their amounts,
+    /// of all users when `username` is None
+    pub fn in_flight(
+        username: Option<&str>,
+        states: &[PaymentState],
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<InFlight> {
+        let states: Vec<&str> = states.iter().map(|s| s.as_str()).collect();
+        let mut query = payments::table
+            .filter(payments::state.eq_any(states))
+            .select(payments::amount_msats)
+            .into_boxed();
+        if let Some(username) = username {
+            query = query.filter(payments::username.eq(username));
+        }
+        let amounts = query.load::<i64>(conn)?;
+
+        Ok(InFlight {
+            payments: amounts.len() as u64,
+            msats: amounts.iter().map(|amount| *amount as u64).sum(),
+        })
+    }
+
+    pub fn delete_all(
+        payment_hashes: &[String],
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<usize> {
+        Ok(
+            diesel::delete(payments::table.filter(payments::payment_hash.eq_any(payment_hashes)))
+                .execute(conn)?,
+        )
+    }
+
+    /// Moves the payment to `to` if it is in one of the `from` states,
+    /// returns false if the payment was not in one of them.
+    fn transition(
+        payment_hash: &str,
+        from: &[PaymentState],
+        to: PaymentState,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<bool> {
+        let now = now()?;
+        let from: Vec<&str> = from.iter().map(|s| s.as_str()).collect();
+        let target = payments::table
+            .filter(payments::payment_hash.eq(payment_hash))
+            .filter(payments::state.eq_any(from));
+        let state = (
+            payments::state.eq(to.as_str()),
+            payments::updated_at.eq(now),
+        );
+
+        let updated = match to {
+            PaymentState::Reserved => return Err(anyhow!("Payments cannot go back to reserved")),
+            PaymentState::HtlcAccepted => diesel::update(target)
+                .set((state, payments::htlc_accepted_at.eq(Some(now))))
+                .execute(conn)?,
+            PaymentState::Forwarding => diesel::update(target)
+                .set((state, payments::forwarding_at.eq(Some(now))))
+                .execute(conn)?,
+            PaymentState::Forwarded => diesel::update(target)
+                .set((state, payments::forwarded_at.eq(Some(now))))
+                .execute(conn)?,
+            PaymentState::Settled => diesel::update(target)
+                .set((state, payments::settled_at.eq(Some(now))))
+                .execute(conn)?,
+            PaymentState::Cancelled | PaymentState::Failed => diesel::update(target)
+                .set((state, payments::failed_at.eq(Some(now))))
+                .execute(conn)?,
+        };
+
+        Ok(updated == 1)
+    }
+
+    pub fn mark_htlc_accepted(
+        payment_hash: &str,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<bool> {
+        Self::transition(
+            payment_hash,
+            &[PaymentState::Reserved],
+            PaymentState::HtlcAccepted,
+            conn,
+        )
+    }
+
+    pub fn mark_forwarding(
+        payment_hash: &str,
+        forward_amount_msats: u64,
+        fee_limit_msats: u64,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<bool> {
+        conn.transaction(|conn| {
+            let moved = Self::transition(
+                payment_hash,
+                &[PaymentState::HtlcAccepted],
+                PaymentState::Forwarding,
+                conn,
+            )?;
+            if moved {
+                diesel::update(payments::table.find(payment_hash))
+                    .set((
+                        payments::forward_amount_msats.eq(Some(forward_amount_msats as i64)),
+                        payments::fee_limit_msats.eq(Some(fee_limit_msats as i64)),
+                    ))
+                    .execute(conn)?;
+            }
+            Ok(moved)
+        })
+    }
+
+    /// Records the fee limit of the retry of a payment being forwarded
+    pub fn set_fee_limit(
+        payment_hash: &str,
+        fee_limit_msats: u64,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<bool> {
+        let updated = diesel::update(payments::table.find(payment_hash))
+            .filter(payments::state.eq(PaymentState::Forwarding.as_str()))
+            .set((
+                payments::fee_limit_msats.eq(Some(fee_limit_msats as i64)),
+                payments::updated_at.eq(now()?),
+            ))
+            .execute(conn)?;
+
+        Ok(updated == 1)
+    }
+
+    /// Records whether cancelling the hold invoice of an ended payment failed
+    pub fn set_cancel_failed(
+        payment_hash: &str,
+        cancel_failed: bool,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<bool> {
+        let ended = [
+            PaymentState::Cancelled.as_str(),
+            PaymentState::Failed.as_str(),
+        ];
+        let updated = diesel::update(payments::table.find(payment_hash))
+            .filter(payments::state.eq_any(ended))
+            .set(payments::cancel_failed.eq(cancel_failed))
+            .execute(conn)?;
+
+        Ok(updated == 1)
+    }
+
+    pub fn mark_forwarded(
+        payment_hash: &str,
+        routing_fee_msats: u64,
+        fees_earned_msats: i64,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<bool> {
+        conn.transaction(|conn| {
+            let moved = Self::transition(
+                payment_hash,
+                &[PaymentState::Forwarding],
+                PaymentState::Forwarded,
+                conn,
+            )?;
+            if moved {
+                diesel::update(payments::table.find(payment_hash))
+                    .set((
+                        payments::routing_fee_msats.eq(Some(routing_fee_msats as i64)),
+                        payments::fees_earned_msats.eq(Some(fees_earned_msats)),
+                    ))
+                    .execute(conn)?;
+            }
+            Ok(moved)
+        })
+    }
+
+    pub fn mark_settled(payment_hash: &str, conn: &mut SqliteConnection) -> anyhow::Result<bool> {
+        Self::transition(
+            payment_hash,
+            &[PaymentState::Forwarded],
+            PaymentState::Settled,
+            conn,
+        )
+    }
+
+    /// Records the hold invoice being cancelled before forwarding
+    pub fn mark_cancelled(
+        payment_hash: &str,
+        reason: &str,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<bool> {
+        Self::end_with_failure(
+            payment_hash,
+            &[PaymentState::Reserved, PaymentState::HtlcAccepted],
+            PaymentState::Cancelled,
+            reason,
+            conn,
+        )
+    }
+
+    /// Records paying the user's invoice failing
+    pub fn mark_failed(
+        payment_hash: &str,
+        reason: &str,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<bool> {
+        Self::end_with_failure(
+            payment_hash,
+            &[PaymentState::Forwarding],
+            PaymentState::Failed,
+            reason,
+            conn,
+        )
+    }
+
+    fn end_with_failure(
+        payment_hash: &str,
+        from: &[PaymentState],
+        to: PaymentState,
+        reason: &str,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<bool> {
+        conn.transaction(|conn| {
+            let moved = Self::transition(payment_hash, from, to, conn)?;
+            if moved {
+                diesel::update(payments::table.find(payment_hash))
+                    .set(payments::failure_reason.eq(Some(reason)))
+                    .execute(conn)?;
+            }
+            Ok(moved)
+        })
+    }
+}
+
+/// A payment of an earlier request for a payment hash that is no longer served
+#[derive(Queryable, Insertable, Debug, Clone, PartialEq)]
+#[diesel(table_name = payment_history)]
+#[diesel(primary_key(request_id))]
+pub struct ArchivedPayment {
+    pub request_id: String,
+    payment_hash: String,
+    pub username: Option<String>,
+    state: String,
+    pub amount_msats: i64,
+    pub forward_amount_msats: Option<i64>,
+    pub fee_limit_msats: Option<i64>,
+    pub routing_fee_msats: Option<i64>,
+    pub fees_earned_msats: Option<i64>,
+    pub failure_reason: Option<String>,
+    pub reserved_at: i64,
+    pub htlc_accepted_at: Option<i64>,
+    pub forwarding_at: Option<i64>,
+    pub forwarded_at: Option<i64>,
+    pub settled_at: Option<i64>,
+    pub failed_at: Option<i64>,
+    pub updated_at: i64,
+    /// The wrapped invoice served for the request
+    pub wrapped_invoice: String,
+}
+
+impl fmt::Display for ArchivedPayment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} (earlier request) user: {}, amount: {} msats, reserved at: {}, \
+            updated at: {}, request: {}",
+            self.payment_hash,
+            self.state,
+            optional(&self.username),
+            self.amount_msats,
+            self.reserved_at,
+            self.updated_at,
+            self.request_id,
+        )?;
+        match self.failure_reason.as_ref() {
+            Some(reason) => write!(f, ", failure: {reason}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ArchivedPayment {
+    fn new(payment: Payment, wrapped_invoice: &str) -> Self {
+        Self {
+            request_id: payment.request_id,
+            payment_hash: payment.payment_hash,
+            username: payment.username,
+            state: payment.state,
+            amount_msats: payment.amount_msats,
+            forward_amount_msats: payment.forward_amount_msats,
+            fee_limit_msats: payment.fee_limit_msats,
+            routing_fee_msats: payment.routing_fee_msats,
+            fees_earned_msats: payment.fees_earned_msats,
+            failure_reason: payment.failure_reason,
+            reserved_at: payment.reserved_at,
+            htlc_accepted_at: payment.htlc_accepted_at,
+            forwarding_at: payment.forwarding_at,
+            forwarded_at: payment.forwarded_at,
+            settled_at: payment.settled_at,
+            failed_at: payment.failed_at,
+            updated_at: payment.updated_at,
+            wrapped_invoice: wrapped_invoice.to_string(),
+        }
+    }
+
+    pub fn payment_hash(&self) -> Sha256 {
+        Sha256::from_str(&self.payment_hash).expect("invalid payment hash")
+    }
+
+    pub fn state(&self) -> PaymentState {
+        PaymentState::from_str(&self.state).expect("invalid payment state")
+    }
+
+    pub fn get_by_request_id(
+        request_id: &str,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<Option<Self>> {
+        Ok(payment_history::table
+            .find(request_id)
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    /// Removes archived payments last updated before `before`
+    pub fn delete_before(before: i64, conn: &mut SqliteConnection) -> anyhow::Result<usize> {
+        Ok(
+            diesel::delete(payment_history::table.filter(payment_history::updated_at.lt(before)))
+                .execute(conn)?,
+        )
+    }
+
+    /// Earlier requests for a payment hash, oldest first
+    pub fn get_by_payment_hash(
+        payment_hash: &str,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<Vec<Self>> {
+        Ok(payment_history::table
+            .filter(payment_history::payment_hash.eq(payment_hash))
+            .order(payment_history::reserved_at.asc())
+            .load::<Self>(conn)?)
+    }
+}
@@ -8,6 +8,70 @@ diesel::table! {
         wrapped_expiry -> Nullable<BigInt>,
         fees_earned -> Nullable<BigInt>,
         username -> Nullable<Text>,
+        preimage -> Nullable<Text>,
+        amount_msats -> Nullable<BigInt>,
+        comment -> Nullable<Text>,
+        payer_data -> Nullable<Text>,
+        wrapped_invoice -> Nullable<Text>,
+        aes_payload -> Nullable<Text>,
+        settled_at -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    ledger (username, day) {
+        username -> Text,
+        day -> BigInt,
+        payments -> BigInt,
+        amount_msats -> BigInt,
+        fees_earned_msats -> BigInt,
+        routing_fee_msats -> BigInt,
+    }
+}
+
+diesel::table! {
+    payments (payment_hash) {
+        payment_hash -> Text,
+        username -> Nullable<Text>,
+        state -> Text,
+        amount_msats -> BigInt,
+        forward_amount_msats -> Nullable<BigInt>,
+        fee_limit_msats -> Nullable<BigInt>,
+        routing_fee_msats -> Nullable<BigInt>,
+        fees_earned_msats -> Nullable<BigInt>,
+        failure_reason -> Nullable<Text>,
+        reserved_at -> BigInt,
+        htlc_accepted_at -> Nullable<BigInt>,
+        forwarding_at -> Nullable<BigInt>,
+        forwarded_at -> Nullable<BigInt>,
+        settled_at -> Nullable<BigInt>,
+        failed_at -> Nullable<BigInt>,
+        updated_at -> BigInt,
+        request_id -> Text,
+        cancel_failed -> Bool,
+    }
+}
+
+diesel::table! {
+    payment_history (request_id) {
+        request_id -> Text,
+        payment_hash -> Text,
+        username -> Nullable<Text>,
+        state -> Text,
+        amount_msats -> BigInt,
+        forward_amount_msats -> Nullable<BigInt>,
+        fee_limit_msats -> Nullable<BigInt>,
+        routing_fee_msats -> Nullable<BigInt>,
+        fees_earned_msats -> Nullable<BigInt>,
+        failure_reason -> Nullable<Text>,
+        reserved_at -> BigInt,
+        htlc_accepted_at -> Nullable<BigInt>,
+        forwarding_at -> Nullable<BigInt>,
+        forwarded_at -> Nullable<BigInt>,
+        settled_at -> Nullable<BigInt>,
+        failed_at -> Nullable<BigInt>,
+        updated_at -> BigInt,
+        wrapped_invoice -> Text,
     }
 }
 
@@ -15,6 +79,13 @@ diesel::table! {
     users (username) {
         username -> Text,
         pubkey -> Text,
+        min_sendable -> Nullable<BigInt>,
+        max_sendable -> Nullable<BigInt>,
+        payer_data -> Nullable<Text>,
+        success_action -> Nullable<Text>,
+        routing_policy -> Nullable<Text>,
+        fee_policy -> Nullable<Text>,
+        in_flight_limits -> Nullable<Text>,
     }
 }
 
@@ -24,9 +95,22 @@ diesel::table! {
         invoice -> Text,
         request -> Text,
         note_id -> Nullable<Text>,
+        published_relays -> Nullable<Text>,
+        attempts -> Integer,
+        last_error -> Nullable<Text>,
+        next_attempt_at -> Nullable<BigInt>,
     }
 }
 
 diesel::joinable!(invoices -> users (username));
+diesel::joinable!(payments -> invoices (payment_hash));
+diesel::joinable!(payments -> users (username));
 
-diesel::allow_tables_to_appear_in_same_query!(invoices, users, zaps,);
+diesel::allow_tables_to_appear_in_same_query!(
+    invoices,
+    ledger,
+    payment_history,
+    payments,
+    users,
+    zaps,
+);
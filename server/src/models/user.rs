@@ -1,18 +1,33 @@
 #[cfg(test)]
 use std::str::FromStr;
 
+use anyhow::anyhow;
 use bitcoin::hashes::hex::ToHex;
 use bitcoin::secp256k1::PublicKey;
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
 
+use zap_tunnel_client::{PayerDataSchema, RoutingPolicy, SuccessAction, UserSettings};
+
 use super::schema::users;
+use crate::config::Config;
+use crate::fees::FeePolicy;
+use crate::limits::InFlightLimits;
 
 #[derive(Queryable, Insertable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[diesel(primary_key(username))]
 pub struct User {
     pub username: String,
     pubkey: String,
+    min_sendable: Option<i64>,
+    max_sendable: Option<i64>,
+    payer_data: Option<String>,
+    success_action: Option<String>,
+    routing_policy: Option<String>,
+    /// Fee set by the operator for this user, overrides the server's fee
+    fee_policy: Option<String>,
+    /// In-flight limits set by the operator for this user, override the server's defaults
+    in_flight_limits: Option<String>,
 }
 
 impl User {
@@ -20,9 +35,152 @@ impl User {
         Self {
             username: String::from(username),
             pubkey: pubkey.to_hex(),
+            min_sendable: None,
+            max_sendable: None,
+            payer_data: None,
+            success_action: None,
+            routing_policy: None,
+            fee_policy: None,
+            in_flight_limits: None,
         }
     }
 
+    /// Minimum amount the user can receive, never below the server's minimum
+    pub fn min_sendable(&self, config: &Config) -> u64 {
+        let server_min = config.min_sendable_for(&self.fee_policy(config));
+
+        self.min_sendable
+            .map_or(server_min, |min| (min as u64).max(server_min))
+    }
+
+    /// Maximum amount the user can receive, never above the server's maximum
+    pub fn max_sendable(&self, config: &Config) -> u64 {
+        self.max_sendable.map_or(config.max_sendable, |max| {
+            (max as u64).min(config.max_sendable)
+        })
+    }
+
+    /// LUD-18 payer data the user asks payers for
+    pub fn payer_data(&self) -> Option<PayerDataSchema> {
+        self.payer_data
+            .as_ref()
+            .map(|json| serde_json::from_str(json).expect("invalid payer data schema"))
+    }
+
+    /// LUD-09 action shown to the payer after paying
+    pub fn success_action(&self) -> Option<SuccessAction> {
+        self.success_action
+            .as_ref()
+            .map(|json| serde_json::from_str(json).expect("invalid success action"))
+    }
+
+    /// Overrides of the server's routing policy for payments to the user
+    pub fn routing_policy(&self) -> Option<RoutingPolicy> {
+        self.routing_policy
+            .as_ref()
+            .map(|json| serde_json::from_str(json).expect("invalid routing policy"))
+    }
+
+    /// Fee charged for routing payments to the user
+    pub fn fee_policy(&self, config: &Config) -> FeePolicy {
+        self.fee_policy
+            .as_ref()
+            .map_or(config.fee_policy(), |json| {
+                serde_json::from_str(json).expect("invalid fee policy")
+            })
+    }
+
+    /// Sets the fee charged to the user, None to charge the server's fee
+    pub fn set_fee_policy(
+        username: &str,
+        fee_policy: Option<&FeePolicy>,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<()> {
+        let fee_policy = fee_policy.map(serde_json::to_string).transpose()?;
+
+        let updated = diesel::update(users::table.find(username))
+            .set(users::fee_policy.eq(fee_policy))
+            .execute(conn)?;
+        if updated == 0 {
+            return Err(anyhow!("User {username} not found"));
+        }
+
+        Ok(())
+    }
+
+    /// Limits on the payments to the user that can be in flight at once
+    pub fn in_flight_limits(&self, config: &Config) -> InFlightLimits {
+        self.in_flight_limits
+            .as_ref()
+            .map_or(config.user_in_flight_limits(), |json| {
+                serde_json::from_str(json).expect("invalid in-flight limits")
+            })
+    }
+
+    /// Sets the user's in-flight limits, None for the server's defaults
+    pub fn set_in_flight_limits(
+        username: &str,
+        limits: Option<&InFlightLimits>,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<()> {
+        let limits = limits.map(serde_json::to_string).transpose()?;
+
+        let updated = diesel::update(users::table.find(username))
+            .set(users::in_flight_limits.eq(limits))
+            .execute(conn)?;
+        if updated == 0 {
+            return Err(anyhow!("User {username} not found"));
+        }
+
+        Ok(())
+    }
+
+    pub fn settings(&self) -> UserSettings {
+        UserSettings {
+            min_sendable: self.min_sendable.map(|min| min as u64),
+            max_sendable: self.max_sendable.map(|max| max as u64),
+            payer_data: self.payer_data(),
+            success_action: self.success_action(),
+            routing_policy: self.routing_policy(),
+        }
+    }
+
+    pub fn update_settings(
+        &mut self,
+        settings: &UserSettings,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<()> {
+        self.min_sendable = settings.min_sendable.map(|min| min as i64);
+        self.max_sendable = settings.max_sendable.map(|max| max as i64);
+        self.payer_data = settings
+            .payer_data
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()?;
+        self.success_action = settings
+            .success_action
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()?;
+        self.routing_policy = settings
+            .routing_policy
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()?;
+
+        diesel::update(users::table.find(&self.username))
+            .set((
+                users::min_sendable.eq(self.min_sendable),
+                users::max_sendable.eq(self.max_sendable),
+                users::payer_data.eq(&self.payer_data),
+                users::success_action.eq(&self.success_action),
+                users::routing_policy.eq(&self.routing_policy),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
     #[cfg(test)]
     pub(crate) fn pubkey(&self) -> PublicKey {
         PublicKey::from_str(&self.pubkey).expect("invalid pubkey")
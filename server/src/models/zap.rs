@@ -6,7 +6,7 @@ use diesel::prelude::*;
 use lightning_invoice::Bolt11Invoice;
 use nostr::prelude::Event;
 
-use super::schema::zaps;
+use super::schema::{invoices, zaps};
 
 #[derive(Queryable, AsChangeset, Insertable, Debug, Clone, PartialEq)]
 #[diesel(primary_key(payment_hash))]
@@ -15,6 +15,11 @@ pub struct Zap {
     invoice: String,
     pub request: String,
     note_id: Option<String>,
+    published_relays: Option<String>,
+    /// Number of failed attempts to publish the zap receipt
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: Option<i64>,
 }
 
 impl Zap {
@@ -24,6 +29,10 @@ impl Zap {
             invoice: invoice.to_string(),
             request: request.as_json(),
             note_id: note_id.map(|hash| hash.to_hex()),
+            published_relays: None,
+            attempts: 0,
+            last_error: None,
+            next_attempt_at: None,
         }
     }
 
@@ -45,6 +54,14 @@ impl Zap {
             .map(|hash| Sha256::from_str(hash).expect("invalid note id"))
     }
 
+    /// Relays that accepted the zap receipt
+    pub fn published_relays(&self) -> Vec<String> {
+        self.published_relays
+            .as_ref()
+            .map(|relays| serde_json::from_str(relays).expect("invalid published relays"))
+            .unwrap_or_default()
+    }
+
     pub fn create(zap: Zap, conn: &mut SqliteConnection) -> Result<Self, diesel::result::Error> {
         diesel::insert_into(zaps::table)
             .values(&zap)
@@ -52,4 +69,76 @@ impl Zap {
 
         Ok(zap)
     }
+
+    /// Removes the zap request of an invoice
+    pub fn delete(payment_hash: &str, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+        diesel::delete(zaps::table.find(payment_hash)).execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Whether the invoice has a zap request whose receipt was not published yet
+    pub fn is_unpublished(payment_hash: &str, conn: &mut SqliteConnection) -> anyhow::Result<bool> {
+        let zap = zaps::table
+            .find(payment_hash)
+            .filter(zaps::note_id.is_null())
+            .select(zaps::payment_hash)
+            .first::<String>(conn)
+            .optional()?;
+
+        Ok(zap.is_some())
+    }
+
+    /// Zaps for paid invoices whose receipt has not been published
+    /// and are due for another attempt.
+    pub fn get_unpublished(now: i64, conn: &mut SqliteConnection) -> anyhow::Result<Vec<Self>> {
+        let paid = invoices::table
+            .select(invoices::payment_hash)
+            .filter(invoices::fees_earned.is_not_null());
+
+        let zaps = zaps::table
+            .filter(zaps::note_id.is_null())
+            .filter(
+                zaps::next_attempt_at
+                    .is_null()
+                    .or(zaps::next_attempt_at.le(now)),
+            )
+            .filter(zaps::payment_hash.eq_any(paid))
+            .load::<Self>(conn)?;
+
+        Ok(zaps)
+    }
+
+    pub fn mark_published(
+        &self,
+        note_id: &str,
+        relays: &[String],
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<()> {
+        diesel::update(zaps::table.find(&self.payment_hash))
+            .set((
+                zaps::note_id.eq(note_id),
+                zaps::published_relays.eq(serde_json::to_string(relays)?),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    pub fn record_failure(
+        &self,
+        error: &str,
+        next_attempt_at: i64,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<()> {
+        diesel::update(zaps::table.find(&self.payment_hash))
+            .set((
+                zaps::attempts.eq(zaps::attempts + 1),
+                zaps::last_error.eq(error),
+                zaps::next_attempt_at.eq(next_attempt_at),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
 }
@@ -1,102 +1,529 @@
-use crate::models::schema::zaps::*;
+use anyhow::anyhow;
+
+use crate::config::Config;
+use crate::models::invoice::Invoice;
 use crate::models::zap::Zap;
+use crate::relay_pool::{RelayPool, RelayStats};
 use bitcoin::hashes::hex::ToHex;
 use bitcoin::hashes::sha256::Hash as Sha256;
-use bitcoin::hashes::Hash;
-use bitcoin::secp256k1::rand::rngs::OsRng;
-use bitcoin::secp256k1::rand::RngCore;
-use bitcoin::secp256k1::SECP256K1;
-use std::net::SocketAddr;
-
-use diesel::r2d2::{ConnectionManager, PooledConnection};
-use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl, SqliteConnection};
-use lightning::ln::PaymentSecret;
-use lightning_invoice::{Currency, InvoiceBuilder};
-use nostr::key::SecretKey;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::SqliteConnection;
+use nostr::key::XOnlyPublicKey;
 use nostr::prelude::ToBech32;
-use nostr::{EventBuilder, Keys};
-use nostr_sdk::Client;
-
-const RELAYS: [&str; 8] = [
-    "wss://nostr.mutinywallet.com",
-    "wss://relay.snort.social",
-    "wss://relay.nostr.band",
-    "wss://eden.nostr.land",
-    "wss://nos.lol",
-    "wss://nostr.fmt.wiz.biz",
-    "wss://relay.damus.io",
-    "wss://nostr.wine",
-];
-
-pub async fn handle_zap(
-    invoice_hash: &Vec<u8>,
-    nostr_keys: &Keys,
-    db: &mut PooledConnection<ConnectionManager<SqliteConnection>>,
+use nostr::{Event, EventBuilder, Keys, Kind};
+use tokio::sync::Notify;
+
+/// How often to retry zap receipts that failed to publish
+const PUBLISHER_INTERVAL: Duration = Duration::from_secs(30);
+/// Delay before the first retry of a failed zap receipt
+const RETRY_BASE_DELAY_SECS: i64 = 30;
+/// Maximum delay between retries of a failed zap receipt
+const RETRY_MAX_DELAY_SECS: i64 = 60 * 60;
+
+/// Reasons a zap request can be rejected, see NIP-57 Appendix D
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZapRequestError {
+    InvalidKind,
+    InvalidSignature,
+    MissingPTag,
+    MultiplePTags,
+    InvalidPTag,
+    MultipleETags,
+    InvalidETag,
+    MultipleATags,
+    InvalidATag,
+    InvalidAmount,
+    AmountMismatch,
+    MissingRelays,
+}
+
+impl fmt::Display for ZapRequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            ZapRequestError::InvalidKind => "Zap request must be kind 9734",
+            ZapRequestError::InvalidSignature => "Zap request has an invalid id or signature",
+            ZapRequestError::MissingPTag => "Zap request is missing a p tag",
+            ZapRequestError::MultiplePTags => "Zap request must have exactly one p tag",
+            ZapRequestError::InvalidPTag => "Zap request has an invalid p tag",
+            ZapRequestError::MultipleETags => "Zap request must have at most one e tag",
+            ZapRequestError::InvalidETag => "Zap request has an invalid e tag",
+            ZapRequestError::MultipleATags => "Zap request must have at most one a tag",
+            ZapRequestError::InvalidATag => "Zap request has an invalid a tag",
+            ZapRequestError::InvalidAmount => "Zap request has an invalid amount tag",
+            ZapRequestError::AmountMismatch => {
+                "Zap request amount does not match the amount parameter"
+            }
+            ZapRequestError::MissingRelays => "Zap request is missing a relays tag",
+        };
+        write!(f, "{reason}")
+    }
+}
+
+impl std::error::Error for ZapRequestError {}
+
+/// Checks an `a` tag value is a valid event coordinate: `<kind>:<pubkey>:<d tag>`
+fn is_valid_coordinate(coordinate: &str) -> bool {
+    let mut parts = coordinate.splitn(3, ':');
+    let kind = parts.next().and_then(|k| k.parse::<u64>().ok());
+    let pubkey = parts.next().and_then(|p| XOnlyPublicKey::from_str(p).ok());
+    let identifier = parts.next();
+
+    kind.is_some() && pubkey.is_some() && identifier.is_some()
+}
+
+/// Validates a zap request received by the lnurlp callback, per NIP-57 Appendix D
+pub fn validate_zap_request(event: &Event, amount_msats: u64) -> Result<(), ZapRequestError> {
+    if event.kind != Kind::ZapRequest {
+        return Err(ZapRequestError::InvalidKind);
+    }
+
+    event
+        .verify()
+        .map_err(|_| ZapRequestError::InvalidSignature)?;
+
+    let tags: Vec<Vec<String>> = event.tags.iter().map(|t| t.as_vec()).collect();
+    let values = |name: &str| -> Vec<&Vec<String>> {
+        tags.iter()
+            .filter(|t| t.first().map(String::as_str) == Some(name))
+            .collect()
+    };
+
+    match values("p").as_slice() {
+        [] => return Err(ZapRequestError::MissingPTag),
+        [p] => {
+            let valid = p
+                .get(1)
+                .is_some_and(|pk| XOnlyPublicKey::from_str(pk).is_ok());
+            if !valid {
+                return Err(ZapRequestError::InvalidPTag);
+            }
+        }
+        _ => return Err(ZapRequestError::MultiplePTags),
+    }
+
+    match values("e").as_slice() {
+        [] => {}
+        [e] => {
+            let valid = e.get(1).is_some_and(|id| Sha256::from_str(id).is_ok());
+            if !valid {
+                return Err(ZapRequestError::InvalidETag);
+            }
+        }
+        _ => return Err(ZapRequestError::MultipleETags),
+    }
+
+    match values("a").as_slice() {
+        [] => {}
+        [a] => {
+            let valid = a.get(1).is_some_and(|c| is_valid_coordinate(c));
+            if !valid {
+                return Err(ZapRequestError::InvalidATag);
+            }
+        }
+        _ => return Err(ZapRequestError::MultipleATags),
+    }
+
+    if let Some(amount) = values("amount").first() {
+        let amount = amount
+            .get(1)
+            .and_then(|a| a.parse::<u64>().ok())
+            .ok_or(ZapRequestError::InvalidAmount)?;
+        if amount != amount_msats {
+            return Err(ZapRequestError::AmountMismatch);
+        }
+    }
+
+    let has_relays = values("relays").iter().any(|r| r.len() > 1);
+    if !has_relays {
+        return Err(ZapRequestError::MissingRelays);
+    }
+
+    Ok(())
+}
+
+/// Most relays of a zap request a receipt is published to
+const MAX_ZAP_REQUEST_RELAYS: usize = 10;
+
+/// Relays listed in a zap request's `relays` tag, only secure websockets
+/// as payers pick them and we connect to them
+pub fn zap_request_relays(event: &Event) -> Vec<String> {
+    event
+        .tags
+        .iter()
+        .map(|t| t.as_vec())
+        .filter(|t| t.first().map(String::as_str) == Some("relays"))
+        .flat_map(|t| t.into_iter().skip(1))
+        .filter(|r| r.starts_with("wss://"))
+        .take(MAX_ZAP_REQUEST_RELAYS)
+        .collect()
+}
+
+/// Relays a zap receipt should be published to, the zap request's relays
+/// followed by the operator's default relays, without duplicates.
+pub fn receipt_relays(zap_request: &Event, default_relays: &[String]) -> Vec<String> {
+    let mut relays: Vec<String> = Vec::new();
+    for relay in zap_request_relays(zap_request)
+        .into_iter()
+        .chain(default_relays.iter().cloned())
+    {
+        let relay = relay.trim_end_matches('/').to_string();
+        if !relays.contains(&relay) {
+            relays.push(relay);
+        }
+    }
+    relays
+}
+
+/// Delay before retrying to publish a zap receipt, doubles with every attempt
+pub fn retry_delay_secs(attempts: i32) -> i64 {
+    let exponent = attempts.saturating_sub(1).clamp(0, 16) as u32;
+    (RETRY_BASE_DELAY_SECS << exponent).min(RETRY_MAX_DELAY_SECS)
+}
+
+async fn publish_zap_receipt(
+    zap: &Zap,
+    publisher: &ZapPublisher,
+    db: &mut SqliteConnection,
 ) -> anyhow::Result<()> {
-    let zap_opt: Option<Zap> = dsl::zaps
-        .filter(payment_hash.eq(invoice_hash.to_hex()))
-        .filter(note_id.is_null())
-        .first::<Zap>(db)
-        .optional()?;
-
-    if let Some(zap) = zap_opt {
-        let zap_request = zap.zap_request();
-        let zap_invoice = zap.invoice();
-
-        let preimage = &mut [0u8; 32];
-        OsRng.fill_bytes(preimage);
-        let invoice_hash = Sha256::hash(preimage);
-
-        let payment_secret = &mut [0u8; 32];
-        OsRng.fill_bytes(payment_secret);
-
-        let priv_key_bytes = &mut [0u8; 32];
-        OsRng.fill_bytes(priv_key_bytes);
-        let private_key = SecretKey::from_slice(priv_key_bytes)?;
-
-        let amt_msats = zap_invoice
-            .amount_milli_satoshis()
-            .expect("Invoice must have an amount");
-
-        let fake_invoice = InvoiceBuilder::new(Currency::Bitcoin)
-            .amount_milli_satoshis(amt_msats)
-            .invoice_description(zap_invoice.description())
-            .current_timestamp()
-            .payment_hash(invoice_hash)
-            .payment_secret(PaymentSecret(*payment_secret))
-            .min_final_cltv_expiry_delta(144)
-            .build_signed(|hash| SECP256K1.sign_ecdsa_recoverable(hash, &private_key))?;
-
-        let event = EventBuilder::new_zap_receipt(
-            fake_invoice.to_string(),
-            Some(preimage.to_hex()),
-            zap_request,
-        )
-        .to_event(nostr_keys)?;
+    let zap_request = zap.zap_request();
+    let relays = receipt_relays(&zap_request, &publisher.default_relays);
+
+    // the preimage we got from paying the user's invoice, which settled the zap's invoice
+    let preimage = Invoice::get_by_payment_hash(&zap.payment_hash().to_hex(), db)?
+        .and_then(|inv| inv.preimage())
+        .ok_or(anyhow!("No preimage for zap {}", zap.payment_hash()))?;
+
+    let event = EventBuilder::new_zap_receipt(
+        zap.invoice().to_string(),
+        Some(preimage.to_hex()),
+        zap_request,
+    )
+    .to_event(&publisher.keys)?;
 
-        // Create new client
-        let client = Client::new(nostr_keys);
-        let relays: Vec<(String, Option<SocketAddr>)> =
-            RELAYS.into_iter().map(|r| (r.to_string(), None)).collect();
-        client.add_relays(relays).await?;
-        client.connect().await;
+    let event_id = event.id;
+    let accepted = publisher.relay_pool.publish(&event, &relays).await;
 
-        let event_id = client.send_event(event).await?;
+    if accepted.is_empty() {
+        return Err(anyhow!("No relays accepted zap receipt"));
+    }
+
+    println!(
+        "Broadcasted event id: {} to {} relays!",
+        event_id.to_bech32().expect("bech32"),
+        accepted.len()
+    );
+
+    // update zap db
+    zap.mark_published(&event_id.to_hex(), &accepted, db)?;
+
+    Ok(())
+}
+
+/// Publishes the zap receipt, recording the failure so it is retried later
+async fn try_publish_zap_receipt(
+    zap: &Zap,
+    publisher: &ZapPublisher,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let result = publish_zap_receipt(zap, publisher, db).await;
 
-        client.disconnect().await?;
+    if let Err(e) = result.as_ref() {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs() as i64;
+        let attempts = zap.attempts + 1;
+        let next_attempt_at = now + retry_delay_secs(attempts);
 
         println!(
-            "Broadcasted event id: {}!",
-            event_id.to_bech32().expect("bech32")
+            "Failed to publish zap receipt for {} (attempt {attempts}): {e}",
+            zap.payment_hash()
         );
+        zap.record_failure(&e.to_string(), next_attempt_at, db)?;
+    }
 
-        // update zap db
-        diesel::update(dsl::zaps.find(invoice_hash.to_hex()))
-            .set(note_id.eq(event_id.to_hex()))
-            .execute(db)?;
+    result
+}
+
+/// Publishes zap receipts through a long-lived relay pool.
+///
+/// Receipts are published by a single background task, which is woken up
+/// whenever a zap is paid and publishes every pending receipt in one batch.
+pub struct ZapPublisher {
+    keys: Keys,
+    default_relays: Vec<String>,
+    relay_pool: RelayPool,
+    notify: Notify,
+}
+
+impl ZapPublisher {
+    pub fn new(config: &Config) -> Self {
+        let keys = config.nostr_keys();
+        Self {
+            relay_pool: RelayPool::new(&keys, config.relays.clone()),
+            keys,
+            default_relays: config.relays.clone(),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Wakes up the publisher to publish any pending zap receipts
+    pub fn notify(&self) {
+        self.notify.notify_one();
+    }
 
-        Ok(())
-    } else {
-        Ok(())
+    pub fn relay_stats(&self) -> HashMap<String, RelayStats> {
+        self.relay_pool.stats()
+    }
+}
+
+/// Background task that publishes zap receipts for paid zaps, retrying the ones
+/// that failed or were not published yet, this includes ones from before a restart.
+pub async fn start_zap_publisher(
+    publisher: Arc<ZapPublisher>,
+    db_pool: Pool<ConnectionManager<SqliteConnection>>,
+) {
+    println!("Starting zap receipt publisher");
+
+    publisher.relay_pool.connect().await;
+
+    loop {
+        if let Err(e) = publish_pending_zaps(&publisher, &db_pool).await {
+            println!("Error publishing pending zaps: {e}");
+        }
+
+        // wait until a zap is paid or it is time to retry failed receipts
+        let _ = tokio::time::timeout(PUBLISHER_INTERVAL, publisher.notify.notified()).await;
+    }
+}
+
+async fn publish_pending_zaps(
+    publisher: &ZapPublisher,
+    db_pool: &Pool<ConnectionManager<SqliteConnection>>,
+) -> anyhow::Result<()> {
+    let db = &mut db_pool.get()?;
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs() as i64;
+
+    let pending = Zap::get_unpublished(now, db)?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    println!("Publishing {} zap receipts", pending.len());
+
+    for zap in pending {
+        // errors are recorded on the zap and retried later
+        let _ = try_publish_zap_receipt(&zap, publisher, db).await;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use nostr::prelude::{Tag, TagKind};
+    use nostr::{Event, EventBuilder, Keys, Kind};
+
+    use super::*;
+
+    const ZAP_REQUEST: &str = "{\"pubkey\":\"32e1827635450ebb3c5a7d12c1f8e7b2b514439ac10a67eef3d9fd9c5c68e245\",\"content\":\"\",\"id\":\"d9cc14d50fcb8c27539aacf776882942c1a11ea4472f8cdec1dea82fab66279d\",\"created_at\":1674164539,\"sig\":\"77127f636577e9029276be060332ea565deaf89ff215a494ccff16ae3f757065e2bc59b2e8c113dd407917a010b3abd36c8d7ad84c0e3ab7dab3a0b0caa9835d\",\"kind\":9734,\"tags\":[[\"e\",\"3624762a1274dd9636e0c552b53086d70bc88c165bc4dc0f9e836a1eaf86c3b8\"],[\"p\",\"32e1827635450ebb3c5a7d12c1f8e7b2b514439ac10a67eef3d9fd9c5c68e245\"],[\"relays\",\"wss://relay.damus.io\",\"wss://nostr-relay.wlvs.space\",\"wss://nostr.fmt.wiz.biz\",\"wss://relay.nostr.bg\",\"wss://nostr.oxtr.dev\",\"wss://nostr.v0l.io\",\"wss://brb.io\",\"wss://nostr.bitcoiner.social\",\"ws://monad.jb55.com:8080\",\"wss://relay.snort.social\"]]}";
+
+    fn tag(name: &str, values: &[&str]) -> Tag {
+        Tag::Generic(
+            TagKind::Custom(name.to_string()),
+            values.iter().map(|v| v.to_string()).collect(),
+        )
+    }
+
+    fn zap_request(tags: &[Tag]) -> Event {
+        let keys = Keys::generate();
+        EventBuilder::new(Kind::ZapRequest, "", tags)
+            .to_event(&keys)
+            .unwrap()
+    }
+
+    fn default_tags(keys: &Keys) -> Vec<Tag> {
+        vec![
+            tag("p", &[&keys.public_key().to_string()]),
+            tag("relays", &["wss://nostr.mutinywallet.com"]),
+        ]
+    }
+
+    #[test]
+    fn test_valid_zap_request() {
+        let event = Event::from_json(ZAP_REQUEST).unwrap();
+        assert_eq!(validate_zap_request(&event, 21_000), Ok(()));
+
+        let keys = Keys::generate();
+        let mut tags = default_tags(&keys);
+        tags.push(tag("amount", &["21000"]));
+        tags.push(tag(
+            "a",
+            &[&format!("30023:{}:my-article", keys.public_key())],
+        ));
+        let event = zap_request(&tags);
+        assert_eq!(validate_zap_request(&event, 21_000), Ok(()));
+    }
+
+    #[test]
+    fn test_invalid_signature() {
+        let mut event = Event::from_json(ZAP_REQUEST).unwrap();
+        event.content = String::from("tampered");
+
+        assert_eq!(
+            validate_zap_request(&event, 21_000),
+            Err(ZapRequestError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn test_invalid_kind() {
+        let keys = Keys::generate();
+        let event = EventBuilder::new(Kind::TextNote, "", &default_tags(&keys))
+            .to_event(&keys)
+            .unwrap();
+
+        assert_eq!(
+            validate_zap_request(&event, 21_000),
+            Err(ZapRequestError::InvalidKind)
+        );
+    }
+
+    #[test]
+    fn test_invalid_tags() {
+        let keys = Keys::generate();
+        let pubkey = keys.public_key().to_string();
+        let relays = tag("relays", &["wss://nostr.mutinywallet.com"]);
+
+        let event = zap_request(&[relays.clone()]);
+        assert_eq!(
+            validate_zap_request(&event, 21_000),
+            Err(ZapRequestError::MissingPTag)
+        );
+
+        let event = zap_request(&[tag("p", &[&pubkey]), tag("p", &[&pubkey]), relays.clone()]);
+        assert_eq!(
+            validate_zap_request(&event, 21_000),
+            Err(ZapRequestError::MultiplePTags)
+        );
+
+        let event = zap_request(&[tag("p", &["not a pubkey"]), relays.clone()]);
+        assert_eq!(
+            validate_zap_request(&event, 21_000),
+            Err(ZapRequestError::InvalidPTag)
+        );
+
+        let mut tags = default_tags(&keys);
+        tags.push(tag("e", &["deadbeef"]));
+        assert_eq!(
+            validate_zap_request(&zap_request(&tags), 21_000),
+            Err(ZapRequestError::InvalidETag)
+        );
+
+        let event_id = "3624762a1274dd9636e0c552b53086d70bc88c165bc4dc0f9e836a1eaf86c3b8";
+        let mut tags = default_tags(&keys);
+        tags.push(tag("e", &[event_id]));
+        tags.push(tag("e", &[event_id]));
+        assert_eq!(
+            validate_zap_request(&zap_request(&tags), 21_000),
+            Err(ZapRequestError::MultipleETags)
+        );
+
+        let coordinate = format!("30023:{pubkey}:my-article");
+        let mut tags = default_tags(&keys);
+        tags.push(tag("a", &[&coordinate]));
+        tags.push(tag("a", &[&coordinate]));
+        assert_eq!(
+            validate_zap_request(&zap_request(&tags), 21_000),
+            Err(ZapRequestError::MultipleATags)
+        );
+
+        let mut tags = default_tags(&keys);
+        tags.push(tag("a", &["30023:not-a-pubkey:my-article"]));
+        assert_eq!(
+            validate_zap_request(&zap_request(&tags), 21_000),
+            Err(ZapRequestError::InvalidATag)
+        );
+
+        let event = zap_request(&[tag("p", &[&pubkey])]);
+        assert_eq!(
+            validate_zap_request(&event, 21_000),
+            Err(ZapRequestError::MissingRelays)
+        );
+    }
+
+    #[test]
+    fn test_receipt_relays() {
+        let event = Event::from_json(ZAP_REQUEST).unwrap();
+        let relays = zap_request_relays(&event);
+        assert_eq!(relays.len(), 10);
+        assert_eq!(relays[0], "wss://relay.damus.io");
+
+        let default_relays = vec![
+            String::from("wss://nostr.mutinywallet.com"),
+            String::from("wss://relay.damus.io/"),
+        ];
+        let relays = receipt_relays(&event, &default_relays);
+        assert_eq!(relays.len(), 11);
+        assert_eq!(relays[0], "wss://relay.damus.io");
+        assert_eq!(relays[10], "wss://nostr.mutinywallet.com");
+
+        let keys = Keys::generate();
+        let event = zap_request(&[tag(
+            "relays",
+            &[
+                "https://not.a.relay",
+                "ws://127.0.0.1:8080",
+                "wss://nos.lol",
+            ],
+        )]);
+        assert_eq!(
+            receipt_relays(&event, &[]),
+            vec![String::from("wss://nos.lol")]
+        );
+
+        // only a few relays of a request are used
+        let many: Vec<String> = (0..20).map(|i| format!("wss://relay{i}.com")).collect();
+        let many: Vec<&str> = many.iter().map(String::as_str).collect();
+        let relays = zap_request_relays(&zap_request(&[tag("relays", &many)]));
+        assert_eq!(relays.len(), MAX_ZAP_REQUEST_RELAYS);
+        assert_eq!(relays[0], "wss://relay0.com");
+        assert!(zap_request_relays(&zap_request(&default_tags(&keys)[..1])).is_empty());
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay_secs(1), 30);
+        assert_eq!(retry_delay_secs(2), 60);
+        assert_eq!(retry_delay_secs(5), 480);
+        assert_eq!(retry_delay_secs(8), 3_600);
+        assert_eq!(retry_delay_secs(1_000), 3_600);
+    }
+
+    #[test]
+    fn test_amount_mismatch() {
+        let keys = Keys::generate();
+
+        let mut tags = default_tags(&keys);
+        tags.push(tag("amount", &["21000"]));
+        assert_eq!(
+            validate_zap_request(&zap_request(&tags), 10_000),
+            Err(ZapRequestError::AmountMismatch)
+        );
+
+        let mut tags = default_tags(&keys);
+        tags.push(tag("amount", &["twenty one"]));
+        assert_eq!(
+            validate_zap_request(&zap_request(&tags), 21_000),
+            Err(ZapRequestError::InvalidAmount)
+        );
     }
 }
@@ -0,0 +1,316 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::ecdsa::Signature;
+use bitcoin::secp256k1::{Message, PublicKey, SECP256K1};
+use serde::Serialize;
+
+pub use zap_tunnel_client::{PayerAuth, PayerData, PayerDataField, PayerDataSchema};
+
+/// Reasons payer data can be rejected, see LUD-18
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayerDataError {
+    InvalidJson,
+    MissingField(&'static str),
+    UnexpectedField(&'static str),
+    InvalidPubkey,
+    InvalidAuth,
+}
+
+impl fmt::Display for PayerDataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayerDataError::InvalidJson => write!(f, "Payer data is not valid json"),
+            PayerDataError::MissingField(field) => {
+                write!(f, "Payer data is missing mandatory field {field}")
+            }
+            PayerDataError::UnexpectedField(field) => {
+                write!(f, "Payer data field {field} was not requested")
+            }
+            PayerDataError::InvalidPubkey => write!(f, "Payer data has an invalid pubkey"),
+            PayerDataError::InvalidAuth => write!(f, "Payer data has an invalid auth signature"),
+        }
+    }
+}
+
+impl std::error::Error for PayerDataError {}
+
+/// How long an issued auth challenge stays valid, challenges
+/// are accepted for one to two windows after being issued
+const CHALLENGE_WINDOW_SECS: u64 = 600;
+
+/// Issues the k1 challenges of LUD-18 auth and recognizes the ones we issued.
+///
+/// A challenge is an HMAC over the username and the current time window keyed
+/// with a server secret, so challenges don't need to be stored to be checked.
+#[derive(Clone)]
+pub struct AuthChallenges {
+    secret: [u8; 32],
+}
+
+impl AuthChallenges {
+    pub fn new(secret: [u8; 32]) -> Self {
+        Self { secret }
+    }
+
+    fn k1(&self, username: &str, window: u64) -> String {
+        let mut engine = HmacEngine::<sha256::Hash>::new(&self.secret);
+        engine.input(username.as_bytes());
+        engine.input(&window.to_be_bytes());
+        Hmac::<sha256::Hash>::from_engine(engine).to_hex()
+    }
+
+    /// Challenge for a payer to `username` at `now`, in seconds since the epoch
+    pub fn issue_at(&self, username: &str, now: u64) -> String {
+        self.k1(username, now / CHALLENGE_WINDOW_SECS)
+    }
+
+    pub fn issue(&self, username: &str) -> String {
+        self.issue_at(username, now())
+    }
+
+    /// Whether we issued `k1` for `username` recently enough
+    pub fn is_issued_at(&self, k1: &str, username: &str, now: u64) -> bool {
+        let window = now / CHALLENGE_WINDOW_SECS;
+        k1 == self.k1(username, window)
+            || window
+                .checked_sub(1)
+                .map_or(false, |previous| k1 == self.k1(username, previous))
+    }
+
+    pub fn is_issued(&self, k1: &str, username: &str) -> bool {
+        self.is_issued_at(k1, username, now())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// Auth field of the advertised schema, with a challenge for the payer to sign
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PayerDataAuthChallenge {
+    pub mandatory: bool,
+    pub k1: String,
+}
+
+/// `payerData` object advertised in the pay response
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PayerDataResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<PayerDataField>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<PayerDataField>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identifier: Option<PayerDataField>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<PayerDataField>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<PayerDataAuthChallenge>,
+}
+
+impl PayerDataResponse {
+    /// Builds the advertised schema, issuing an auth challenge if auth is requested
+    pub fn new(schema: &PayerDataSchema, username: &str, challenges: &AuthChallenges) -> Self {
+        let auth = schema.auth.map(|auth| PayerDataAuthChallenge {
+            mandatory: auth.mandatory,
+            k1: challenges.issue(username),
+        });
+
+        Self {
+            name: schema.name,
+            pubkey: schema.pubkey,
+            identifier: schema.identifier,
+            email: schema.email,
+            auth,
+        }
+    }
+}
+
+fn check_field<T>(
+    name: &'static str,
+    field: Option<PayerDataField>,
+    value: Option<&T>,
+) -> Result<(), PayerDataError> {
+    match (field, value) {
+        (None, Some(_)) => Err(PayerDataError::UnexpectedField(name)),
+        (Some(field), None) if field.mandatory => Err(PayerDataError::MissingField(name)),
+        _ => Ok(()),
+    }
+}
+
+fn verify_auth(auth: &PayerAuth) -> Result<(), PayerDataError> {
+    let key = PublicKey::from_str(&auth.key).map_err(|_| PayerDataError::InvalidAuth)?;
+    let k1 = Vec::from_hex(&auth.k1).map_err(|_| PayerDataError::InvalidAuth)?;
+    let message = Message::from_slice(&k1).map_err(|_| PayerDataError::InvalidAuth)?;
+    let sig = Vec::from_hex(&auth.sig).map_err(|_| PayerDataError::InvalidAuth)?;
+    let sig = Signature::from_der(&sig).map_err(|_| PayerDataError::InvalidAuth)?;
+
+    SECP256K1
+        .verify_ecdsa(&message, &sig, &key)
+        .map_err(|_| PayerDataError::InvalidAuth)
+}
+
+/// Parses the payer data sent by the payer and checks it against the user's schema,
+/// the auth must sign a challenge we issued for payments to `username`
+pub fn validate_payer_data(
+    schema: Option<&PayerDataSchema>,
+    payer_data: Option<&str>,
+    username: &str,
+    challenges: &AuthChallenges,
+) -> Result<Option<PayerData>, PayerDataError> {
+    let payer_data = payer_data
+        .map(serde_json::from_str::<PayerData>)
+        .transpose()
+        .map_err(|_| PayerDataError::InvalidJson)?;
+
+    let schema = schema.cloned().unwrap_or_default();
+    let data = payer_data.clone().unwrap_or_default();
+
+    check_field("name", schema.name, data.name.as_ref())?;
+    check_field("pubkey", schema.pubkey, data.pubkey.as_ref())?;
+    check_field("identifier", schema.identifier, data.identifier.as_ref())?;
+    check_field("email", schema.email, data.email.as_ref())?;
+    check_field("auth", schema.auth, data.auth.as_ref())?;
+
+    if let Some(pubkey) = data.pubkey.as_ref() {
+        PublicKey::from_str(pubkey).map_err(|_| PayerDataError::InvalidPubkey)?;
+    }
+
+    if let Some(auth) = data.auth.as_ref() {
+        if !challenges.is_issued(&auth.k1, username) {
+            return Err(PayerDataError::InvalidAuth);
+        }
+        verify_auth(auth)?;
+    }
+
+    Ok(payer_data)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::secp256k1::SecretKey;
+
+    use super::*;
+
+    fn challenges() -> AuthChallenges {
+        AuthChallenges::new([9u8; 32])
+    }
+
+    fn validate(
+        schema: Option<&PayerDataSchema>,
+        payer_data: Option<&str>,
+    ) -> Result<Option<PayerData>, PayerDataError> {
+        validate_payer_data(schema, payer_data, "alice", &challenges())
+    }
+
+    fn schema() -> PayerDataSchema {
+        PayerDataSchema {
+            name: Some(PayerDataField { mandatory: true }),
+            pubkey: Some(PayerDataField { mandatory: false }),
+            auth: Some(PayerDataField { mandatory: false }),
+            ..Default::default()
+        }
+    }
+
+    fn signed_auth(k1: &str) -> PayerAuth {
+        let secret_key = SecretKey::from_slice(&[1u8; 32]).unwrap();
+        let key = PublicKey::from_secret_key(SECP256K1, &secret_key);
+        let message = Message::from_slice(&Vec::from_hex(k1).unwrap()).unwrap();
+        let sig = SECP256K1.sign_ecdsa(&message, &secret_key);
+
+        PayerAuth {
+            key: key.to_string(),
+            k1: k1.to_string(),
+            sig: sig.serialize_der().to_hex(),
+        }
+    }
+
+    #[test]
+    fn test_valid_payer_data() {
+        let schema = schema();
+
+        let data = validate(Some(&schema), Some("{\"name\":\"satoshi\"}")).unwrap();
+        assert_eq!(data.unwrap().name, Some(String::from("satoshi")));
+
+        let auth = signed_auth(&challenges().issue("alice"));
+        let data = PayerData {
+            name: Some(String::from("satoshi")),
+            pubkey: Some(auth.key.clone()),
+            auth: Some(auth),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&data).unwrap();
+        assert_eq!(validate(Some(&schema), Some(&json)), Ok(Some(data)));
+
+        // no schema and no payer data
+        assert_eq!(validate(None, None), Ok(None));
+    }
+
+    #[test]
+    fn test_invalid_payer_data() {
+        let schema = schema();
+
+        assert_eq!(
+            validate(Some(&schema), Some("not json")),
+            Err(PayerDataError::InvalidJson)
+        );
+        assert_eq!(
+            validate(Some(&schema), None),
+            Err(PayerDataError::MissingField("name"))
+        );
+        assert_eq!(
+            validate(Some(&schema), Some("{\"name\":\"a\",\"email\":\"a@b.c\"}")),
+            Err(PayerDataError::UnexpectedField("email"))
+        );
+        assert_eq!(
+            validate(None, Some("{\"name\":\"a\"}")),
+            Err(PayerDataError::UnexpectedField("name"))
+        );
+        assert_eq!(
+            validate(Some(&schema), Some("{\"name\":\"a\",\"pubkey\":\"00\"}")),
+            Err(PayerDataError::InvalidPubkey)
+        );
+
+        let mut auth = signed_auth(&challenges().issue("alice"));
+        auth.sig = String::from("3006020101020101");
+        // bad signature, a k1 we didn't issue and one issued for another user
+        for auth in [
+            auth,
+            signed_auth(&[2u8; 32].to_hex()),
+            signed_auth(&challenges().issue("bob")),
+        ] {
+            let data = PayerData {
+                name: Some(String::from("satoshi")),
+                auth: Some(auth),
+                ..Default::default()
+            };
+            let json = serde_json::to_string(&data).unwrap();
+            assert_eq!(
+                validate(Some(&schema), Some(&json)),
+                Err(PayerDataError::InvalidAuth)
+            );
+        }
+    }
+
+    #[test]
+    fn test_auth_challenges() {
+        let challenges = challenges();
+        let now = 1_700_000_000;
+        let k1 = challenges.issue_at("alice", now);
+        assert_eq!(k1.len(), 64);
+
+        assert!(challenges.is_issued_at(&k1, "alice", now));
+        assert!(challenges.is_issued_at(&k1, "alice", now + CHALLENGE_WINDOW_SECS));
+        assert!(!challenges.is_issued_at(&k1, "alice", now + 2 * CHALLENGE_WINDOW_SECS));
+        assert!(!challenges.is_issued_at(&k1, "bob", now));
+        assert!(!AuthChallenges::new([8u8; 32]).is_issued_at(&k1, "alice", now));
+    }
+}
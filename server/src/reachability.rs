@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bitcoin::hashes::hex::ToHex;
+use diesel::SqliteConnection;
+
+use crate::config::Config;
+use crate::lightning::LightningBackend;
+use crate::models::invoice::Invoice;
+use crate::models::user::User;
+
+/// What probing a user's node told us, valid until it goes stale
+#[derive(Debug, Clone, Copy)]
+struct Reachability {
+    /// Largest amount a route was found for
+    reachable_msat: Option<u64>,
+    /// Smallest amount no route was found for
+    unreachable_msat: Option<u64>,
+    checked_at: Instant,
+}
+
+impl Reachability {
+    fn new() -> Self {
+        Self {
+            reachable_msat: None,
+            unreachable_msat: None,
+            checked_at: Instant::now(),
+        }
+    }
+}
+
+/// Results of probing users' nodes, so payers asking
+/// for invoices in a row don't each wait for a probe
+#[derive(Default)]
+pub struct ReachabilityCache {
+    users: Mutex<HashMap<String, Reachability>>,
+}
+
+impl ReachabilityCache {
+    /// Whether the user was reachable for `amount_msat` when last probed,
+    /// None if no probe answers it or the results are older than `ttl`
+    pub fn get(&self, username: &str, amount_msat: u64, ttl: Duration) -> Option<bool> {
+        let users = self.users.lock().unwrap();
+        let entry = users
+            .get(username)
+            .filter(|entry| entry.checked_at.elapsed() < ttl)?;
+
+        if entry.reachable_msat.map_or(false, |max| amount_msat <= max) {
+            Some(true)
+        } else if entry
+            .unreachable_msat
+            .map_or(false, |min| amount_msat >= min)
+        {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Records a probe, results that contradict it are dropped
+    pub fn record(&self, username: &str, amount_msat: u64, reachable: bool, ttl: Duration) {
+        let mut users = self.users.lock().unwrap();
+        let entry = users
+            .entry(username.to_string())
+            .or_insert_with(Reachability::new);
+        if entry.checked_at.elapsed() >= ttl {
+            *entry = Reachability::new();
+        }
+
+        if reachable {
+            entry.reachable_msat = entry.reachable_msat.max(Some(amount_msat));
+            entry.unreachable_msat = entry.unreachable_msat.filter(|min| *min > amount_msat);
+        } else {
+            entry.unreachable_msat = Some(
+                entry
+                    .unreachable_msat
+                    .map_or(amount_msat, |min| min.min(amount_msat)),
+            );
+            entry.reachable_msat = entry.reachable_msat.filter(|max| *max < amount_msat);
+        }
+    }
+}
+
+/// Checks a route to the user's node exists for what we would forward of
+/// `amount_msats`, by querying routes to the next invoice of their pool.
+/// Users without invoices are reported reachable and left for the invoice request to fail.
+pub async fn is_reachable(
+    user: &User,
+    amount_msats: u64,
+    lightning: &dyn LightningBackend,
+    config: &Config,
+    cache: &ReachabilityCache,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<bool> {
+    let fee = config.least_fee(&user.fee_policy(config), amount_msats);
+    let forward_msats = amount_msats.saturating_sub(fee);
+    let ttl = Duration::from_secs(config.probe_cache_seconds);
+
+    if let Some(reachable) = cache.get(&user.username, forward_msats, ttl) {
+        return Ok(reachable);
+    }
+
+    let invoice = match Invoice::peek_next_invoice(&user.username, conn)? {
+        None => return Ok(true),
+        Some(invoice) => invoice.invoice(),
+    };
+
+    let reachable = lightning
+        .estimate_route_fee(&invoice, forward_msats)
+        .await?
+        .is_some();
+    if !reachable {
+        println!(
+            "No route to {} for {forward_msats} msats, probed with {}",
+            user.username,
+            invoice.payment_hash().to_hex()
+        );
+    }
+    cache.record(&user.username, forward_msats, reachable, ttl);
+
+    Ok(reachable)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reachability_cache() {
+        let cache = ReachabilityCache::default();
+        let ttl = Duration::from_secs(60);
+        assert_eq!(cache.get("alice", 1_000, ttl), None);
+
+        cache.record("alice", 10_000, true, ttl);
+        cache.record("alice", 100_000, false, ttl);
+        assert_eq!(cache.get("alice", 1_000, ttl), Some(true));
+        assert_eq!(cache.get("alice", 10_000, ttl), Some(true));
+        assert_eq!(cache.get("alice", 50_000, ttl), None);
+        assert_eq!(cache.get("alice", 200_000, ttl), Some(false));
+        assert_eq!(cache.get("bob", 1_000, ttl), None);
+
+        // newer probes replace the results they contradict
+        cache.record("alice", 5_000, false, ttl);
+        assert_eq!(cache.get("alice", 1_000, ttl), None);
+        assert_eq!(cache.get("alice", 10_000, ttl), Some(false));
+
+        // stale results are ignored
+        assert_eq!(cache.get("alice", 10_000, Duration::ZERO), None);
+        cache.record("alice", 1_000, true, Duration::ZERO);
+        assert_eq!(cache.get("alice", 10_000, ttl), None);
+        assert_eq!(cache.get("alice", 1_000, ttl), Some(true));
+    }
+}
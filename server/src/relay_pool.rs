@@ -0,0 +1,180 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use nostr::{Event, Keys};
+use nostr_sdk::{Client, Options};
+use serde::Serialize;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+/// How long to wait for a relay to connect or accept an event
+const RELAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Publishing statistics for a single relay
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct RelayStats {
+    pub published: u64,
+    pub failed: u64,
+    pub last_latency_ms: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+/// Long-lived connections to nostr relays used to publish zap receipts.
+///
+/// The default relays are kept connected, the underlying client reconnects
+/// to them on its own when a connection drops. Relays from zap requests are
+/// only connected to while publishing the receipt.
+pub struct RelayPool {
+    client: Client,
+    keys: Keys,
+    default_relays: Vec<String>,
+    known_relays: Mutex<HashSet<String>>,
+    stats: Mutex<HashMap<String, RelayStats>>,
+}
+
+impl RelayPool {
+    pub fn new(keys: &Keys, default_relays: Vec<String>) -> Self {
+        let default_relays = default_relays
+            .iter()
+            .map(|relay| relay.trim_end_matches('/').to_string())
+            .collect();
+        Self {
+            client: Client::with_opts(keys, relay_options()),
+            keys: keys.clone(),
+            default_relays,
+            known_relays: Mutex::new(HashSet::new()),
+            stats: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Connects to the default relays
+    pub async fn connect(&self) {
+        let relays = self.default_relays.clone();
+        self.add_relays(&relays).await;
+    }
+
+    /// Adds any relays that are not in the pool yet and waits for them to connect
+    async fn add_relays(&self, relays: &[String]) {
+        // claimed under the lock so they are only added once,
+        // connecting happens outside of it
+        let added: Vec<String> = {
+            let mut known = self.known_relays.lock().unwrap();
+            relays
+                .iter()
+                .filter(|url| known.insert(url.to_string()))
+                .cloned()
+                .collect()
+        };
+
+        connect_relays(&self.client, &added).await;
+    }
+
+    /// Publishes an event to the given relays,
+    /// returns the relays that accepted it.
+    pub async fn publish(&self, event: &Event, relays: &[String]) -> Vec<String> {
+        let (pooled, requested): (Vec<String>, Vec<String>) = relays
+            .iter()
+            .cloned()
+            .partition(|relay| self.default_relays.contains(relay));
+        self.add_relays(&pooled).await;
+
+        // other relays get a client that is shut down after publishing,
+        // so zap requests can't grow the pool or keep connections open
+        let request_client = if requested.is_empty() {
+            None
+        } else {
+            let client = Client::with_opts(&self.keys, relay_options());
+            connect_relays(&client, &requested).await;
+            Some(client)
+        };
+
+        let mut sends = JoinSet::new();
+        for relay in relays.iter().cloned() {
+            let client = match &request_client {
+                Some(client) if !pooled.contains(&relay) => client.clone(),
+                _ => self.client.clone(),
+            };
+            let event = event.clone();
+            sends.spawn(async move {
+                let start = Instant::now();
+                let send = client.send_event_to(relay.as_str(), event);
+                let result = match timeout(RELAY_TIMEOUT, send).await {
+                    Ok(Ok(_)) => Ok(()),
+                    Ok(Err(e)) => Err(e.to_string()),
+                    Err(_) => Err(String::from("Timed out")),
+                };
+                (relay, start.elapsed(), result)
+            });
+        }
+
+        let mut accepted = Vec::new();
+        while let Some(joined) = sends.join_next().await {
+            let Ok((relay, latency, result)) = joined else {
+                continue;
+            };
+
+            self.record(&relay, latency, &result);
+            match result {
+                Ok(()) => accepted.push(relay),
+                Err(e) => println!("Failed to send event to {relay}: {e}"),
+            }
+        }
+
+        if let Some(client) = request_client {
+            if let Err(e) = client.shutdown().await {
+                println!("Failed to disconnect from zap request relays: {e}");
+            }
+        }
+
+        accepted
+    }
+
+    fn record(&self, relay: &str, latency: Duration, result: &Result<(), String>) {
+        let mut stats = self.stats.lock().unwrap();
+        let entry = stats.entry(relay.to_string()).or_default();
+
+        entry.last_latency_ms = Some(latency.as_millis() as u64);
+        match result {
+            Ok(()) => entry.published += 1,
+            Err(e) => {
+                entry.failed += 1;
+                entry.last_error = Some(e.clone());
+            }
+        }
+    }
+
+    /// Publishing statistics per relay
+    pub fn stats(&self) -> HashMap<String, RelayStats> {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+fn relay_options() -> Options {
+    // wait for the relay's OK so rejected events don't count as published
+    Options::new()
+        .wait_for_ok(true)
+        .send_timeout(Some(RELAY_TIMEOUT))
+}
+
+/// Adds relays to a client and waits for them to connect, all at once
+async fn connect_relays(client: &Client, relays: &[String]) {
+    let mut connects = JoinSet::new();
+    for url in relays.iter().cloned() {
+        if let Err(e) = client.add_relay(url.as_str(), None).await {
+            println!("Failed to add relay {url}: {e}");
+            continue;
+        }
+
+        let client = client.clone();
+        connects.spawn(async move {
+            if let Ok(relay) = client.relay(url.as_str()).await {
+                if timeout(RELAY_TIMEOUT, relay.connect(true)).await.is_err() {
+                    println!("Timed out connecting to relay {url}");
+                }
+            }
+        });
+    }
+
+    while connects.join_next().await.is_some() {}
+}
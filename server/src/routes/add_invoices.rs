@@ -6,7 +6,10 @@ use bitcoin::Network;
 use diesel::{Connection, RunQueryDsl, SqliteConnection};
 use lightning_invoice::Bolt11Invoice;
 
-pub use zap_tunnel_client::AddInvoices;
+pub use zap_tunnel_client::{AddInvoices, AesPayload};
+
+/// Maximum length of an encrypted success action, see LUD-10
+const MAX_AES_CIPHERTEXT: usize = 4096;
 
 use crate::models::invoice::Invoice;
 use crate::models::schema::*;
@@ -25,6 +28,18 @@ fn check_invoices(invoices: &[Bolt11Invoice], network: Network) -> bool {
     })
 }
 
+/// Returns true if the encrypted success actions match the invoices, see LUD-10
+fn check_aes_payloads(aes_payloads: &[AesPayload], num_invoices: usize) -> bool {
+    aes_payloads.is_empty()
+        || (aes_payloads.len() == num_invoices
+            && aes_payloads.iter().all(|payload| {
+                // base64 of a 16 byte iv
+                payload.iv.len() == 24
+                    && !payload.ciphertext.is_empty()
+                    && payload.ciphertext.len() <= MAX_AES_CIPHERTEXT
+            }))
+}
+
 pub(crate) fn add_invoices_impl(
     payload: AddInvoices,
     connection: &mut SqliteConnection,
@@ -38,12 +53,16 @@ pub(crate) fn add_invoices_impl(
             User::get_by_pubkey(connection, &payload.pubkey()?).ok_or(anyhow!("Invalid pubkey"))?;
         let username = user.username;
 
-        let invoices: Vec<Invoice> = payload
+        let mut invoices: Vec<Invoice> = payload
             .invoices
             .iter()
             .map(|x| Invoice::new(x, Some(&username)))
             .collect();
 
+        for (invoice, aes_payload) in invoices.iter_mut().zip(payload.aes_payloads.iter()) {
+            invoice.set_aes_payload(aes_payload)?;
+        }
+
         // insert invoices
         let num_inserted = diesel::insert_into(invoices::dsl::invoices)
             .values(&invoices)
@@ -69,6 +88,13 @@ pub async fn add_invoices(
         ));
     }
 
+    if !check_aes_payloads(&payload.aes_payloads, payload.invoices.len()) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            String::from("Invalid aes payloads, must have one for each invoice"),
+        ));
+    }
+
     let mut connection = state.db_pool.get().map_err(|_| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -0,0 +1,18 @@
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+
+use crate::health::HealthReport;
+use crate::State;
+
+/// Health of the lnd subscriptions, 503 while the invoice subscription is down
+pub async fn health(Extension(state): Extension<State>) -> (StatusCode, Json<HealthReport>) {
+    let report = state.subscription_health.report();
+
+    let status = if report.invoice_subscription.connected {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(report))
+}
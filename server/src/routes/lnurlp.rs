@@ -1,44 +1,153 @@
 use anyhow::anyhow;
 use std::collections::HashMap;
+use std::fmt;
 use std::str::FromStr;
 
 use crate::config::Config;
+use crate::lightning::{HoldInvoiceRequest, LightningBackend};
+use crate::limits::{check_in_flight, InFlightLimitError, PaymentLimits};
 use crate::models::invoice::{Invoice, DEFAULT_INVOICE_EXPIRY};
+use crate::models::payment::{ArchivedPayment, Payment, PaymentState};
+use crate::models::user::User;
 use crate::models::zap::Zap;
+use crate::nostr::{validate_zap_request, ZapRequestError};
+use crate::payer_data::{validate_payer_data, PayerDataError, PayerDataResponse};
+use crate::reachability::is_reachable;
+use crate::routes::update_settings::SuccessAction;
 use axum::extract::{Path, Query};
 use axum::http::StatusCode;
 use axum::{Extension, Json};
+use bitcoin::hashes::hex::ToHex;
 use bitcoin::hashes::{sha256, Hash};
 use diesel::SqliteConnection;
 use lightning_invoice::Bolt11Invoice;
-use lnurl::pay::{LnURLPayInvoice, PayResponse};
+use lnurl::pay::PayResponse;
 use lnurl::Tag;
 use nostr::Event;
+use serde::Serialize;
 use serde_json::json;
-use tonic_openssl_lnd::invoicesrpc::AddHoldInvoiceRequest;
-use tonic_openssl_lnd::LndInvoicesClient;
 
 use crate::State;
 
-fn calculate_metadata(username: &str, public_url: &str) -> String {
+/// Errors caused by the payer's request, returned to them as an LNURL error reason
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum InvoiceRequestError {
+    AmountTooSmall(u64),
+    AmountTooLarge(u64),
+    CommentTooLong(u32),
+    InvalidZapRequest(ZapRequestError),
+    InvalidPayerData(PayerDataError),
+}
+
+impl fmt::Display for InvoiceRequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvoiceRequestError::AmountTooSmall(min) => {
+                write!(f, "Amount too small, minimum is {min} msats")
+            }
+            InvoiceRequestError::AmountTooLarge(max) => {
+                write!(f, "Amount too large, maximum is {max} msats")
+            }
+            InvoiceRequestError::CommentTooLong(max) => {
+                write!(f, "Comment too long, maximum is {max} characters")
+            }
+            InvoiceRequestError::InvalidZapRequest(e) => write!(f, "Invalid zap request: {e}"),
+            InvoiceRequestError::InvalidPayerData(e) => write!(f, "Invalid payer data: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for InvoiceRequestError {}
+
+/// LUD-06 pay response with the extensions we support
+#[derive(Debug, Clone, Serialize)]
+pub struct LnurlPayResponse {
+    #[serde(flatten)]
+    pub pay: PayResponse,
+    /// LUD-12 max comment length
+    #[serde(rename = "commentAllowed", skip_serializing_if = "Option::is_none")]
+    pub comment_allowed: Option<u32>,
+    /// LUD-18 payer data the user asks for
+    #[serde(rename = "payerData", skip_serializing_if = "Option::is_none")]
+    pub payer_data: Option<PayerDataResponse>,
+}
+
+/// LUD-09 success action returned to the payer
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(tag = "tag", rename_all = "lowercase")]
+pub enum SuccessActionResponse {
+    Message {
+        message: String,
+    },
+    Url {
+        description: String,
+        url: String,
+    },
+    Aes {
+        description: String,
+        ciphertext: String,
+        iv: String,
+    },
+}
+
+/// LUD-06 callback response with the extensions we support
+#[derive(Debug, Clone, Serialize)]
+pub struct LnurlInvoiceResponse {
+    pub pr: String,
+    pub routes: Vec<String>,
+    /// LUD-21 url to check if the invoice was paid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify: Option<String>,
+    #[serde(rename = "successAction", skip_serializing_if = "Option::is_none")]
+    pub success_action: Option<SuccessActionResponse>,
+}
+
+/// LUD-21 verify response
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct VerifyResponse {
+    pub status: String,
+    pub settled: bool,
+    pub preimage: Option<String>,
+    pub pr: String,
+    /// Whether the invoice expired without being paid
+    pub expired: bool,
+}
+
+/// Parameters of a request to the lnurlp callback
+#[derive(Debug, Clone, Default)]
+pub(crate) struct InvoiceParams {
+    pub amount_msats: u64,
+    pub zap_request: Option<Event>,
+    /// LUD-12 payer comment
+    pub comment: Option<String>,
+    /// LUD-18 payer data json, hashed as sent
+    pub payer_data: Option<String>,
+}
+
+fn calculate_metadata(username: &str, public_url: &str, fee: &str) -> String {
     format!(
-        "[[\"text/plain\", \"Pay to {}\"], [\"text/identifier\", \"{}@{}\"]]",
-        username, username, public_url
+        "[[\"text/plain\", \"Pay to {}, fee: {}\"], [\"text/identifier\", \"{}@{}\"]]",
+        username, fee, username, public_url
     )
 }
 
+fn verify_url(username: &str, request_id: &str, public_url: &str) -> String {
+    format!("https://{public_url}/lnurlp/{username}/verify/{request_id}")
+}
+
 pub(crate) fn get_lnurlp_impl(
     username: String,
     config: &Config,
     connection: &mut SqliteConnection,
-) -> Option<PayResponse> {
-    let metadata = calculate_metadata(&username, &config.public_url);
-    let _ = crate::models::user::User::get_by_username(connection, &username)?;
+) -> Option<LnurlPayResponse> {
+    let user = User::get_by_username(connection, &username)?;
+    let fee = config.describe_fee(&user.fee_policy(config));
+    let metadata = calculate_metadata(&username, &config.public_url, &fee);
     let callback = format!("https://{}/lnurlp/{}", config.public_url, username);
-    let max_sendable = 100_000_000;
-    let min_sendable = config.min_sendable();
+    let max_sendable = user.max_sendable(config);
+    let min_sendable = user.min_sendable(config);
 
-    Some(PayResponse {
+    let pay = PayResponse {
         callback,
         max_sendable,
         min_sendable,
@@ -46,13 +155,22 @@ pub(crate) fn get_lnurlp_impl(
         metadata,
         allows_nostr: Some(true),
         nostr_pubkey: Some(config.public_key()),
+    };
+
+    Some(LnurlPayResponse {
+        pay,
+        comment_allowed: Some(config.comment_allowed).filter(|max| *max > 0),
+        payer_data: user
+            .payer_data()
+            .as_ref()
+            .map(|schema| PayerDataResponse::new(schema, &username, &config.auth_challenges())),
     })
 }
 
 pub async fn get_lnurlp(
     Path(username): Path<String>,
     Extension(state): Extension<State>,
-) -> Result<Json<PayResponse>, (StatusCode, String)> {
+) -> Result<Json<LnurlPayResponse>, (StatusCode, String)> {
     let mut connection = state.db_pool.get().map_err(|_| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -61,37 +179,134 @@ pub async fn get_lnurlp(
     })?;
 
     match get_lnurlp_impl(username, &state.config, &mut connection) {
-        Some(res) => Ok(Json(res)),
+        Some(mut res) => {
+            res.pay.max_sendable = state.liquidity.max_sendable(res.pay.max_sendable);
+            if res.pay.max_sendable < res.pay.min_sendable {
+                return Err((StatusCode::SERVICE_UNAVAILABLE, String::from("{\"status\":\"ERROR\",\"reason\":\"Payments can't be forwarded right now, try again later.\"}")));
+            }
+            Ok(Json(res))
+        }
         None => Err((StatusCode::NOT_FOUND, String::from("{\"status\":\"ERROR\",\"reason\":\"The user you're searching for could not be found.\"}"))),
     }
 }
 
-pub(crate) async fn get_lnurl_invoice_impl(
+/// An invoice request that passed validation, ready to be served
+struct ValidInvoiceRequest {
     username: String,
     amount_msats: u64,
+    desc_hash: sha256::Hash,
     zap_request: Option<Event>,
-    invoice_client: &LndInvoicesClient,
+    comment: Option<String>,
+    payer_data: Option<String>,
+}
+
+pub(crate) async fn get_lnurl_invoice_impl(
+    username: String,
+    params: InvoiceParams,
+    lightning: &dyn LightningBackend,
     config: &Config,
     connection: &mut SqliteConnection,
 ) -> anyhow::Result<Option<Bolt11Invoice>> {
-    if amount_msats <= config.min_sendable() {
-        return Err(anyhow!("Amount too small"));
+    match validate_invoice_request(username, params, config, connection)? {
+        None => Ok(None),
+        Some(request) => create_hold_invoice(request, lightning, connection).await,
+    }
+}
+
+/// Checks an invoice request without touching lnd, None if the user doesn't exist
+fn validate_invoice_request(
+    username: String,
+    params: InvoiceParams,
+    config: &Config,
+    connection: &mut SqliteConnection,
+) -> anyhow::Result<Option<ValidInvoiceRequest>> {
+    let user = match User::get_by_username(connection, &username) {
+        None => return Ok(None),
+        Some(user) => user,
+    };
+
+    let amount_msats = params.amount_msats;
+    let min_sendable = user.min_sendable(config);
+    if amount_msats < min_sendable {
+        return Err(InvoiceRequestError::AmountTooSmall(min_sendable).into());
+    }
+    let max_sendable = user.max_sendable(config);
+    if amount_msats > max_sendable {
+        return Err(InvoiceRequestError::AmountTooLarge(max_sendable).into());
     }
+    // the amount forwarded to the user must be above zero
+    let fee_policy = user.fee_policy(config);
+    if config.least_fee(&fee_policy, amount_msats) >= amount_msats {
+        return Err(InvoiceRequestError::AmountTooSmall(min_sendable).into());
+    }
+
+    let comment = params.comment.filter(|c| !c.is_empty());
+    if let Some(comment) = comment.as_ref() {
+        if comment.chars().count() > config.comment_allowed as usize {
+            return Err(InvoiceRequestError::CommentTooLong(config.comment_allowed).into());
+        }
+    }
+
+    let payer_data = params.payer_data;
+    validate_payer_data(
+        user.payer_data().as_ref(),
+        payer_data.as_deref(),
+        &username,
+        &config.auth_challenges(),
+    )
+    .map_err(InvoiceRequestError::InvalidPayerData)?;
 
+    let zap_request = params.zap_request;
     let desc_hash = match zap_request.as_ref() {
         None => {
-            let metadata = calculate_metadata(&username, &config.public_url);
-            sha256::Hash::hash(metadata.as_bytes())
+            // LUD-18: payer data is appended to the metadata before hashing
+            let fee = config.describe_fee(&fee_policy);
+            let metadata = calculate_metadata(&username, &config.public_url, &fee);
+            let preimage = metadata + payer_data.as_deref().unwrap_or_default();
+            sha256::Hash::hash(preimage.as_bytes())
         }
         Some(event) => {
-            // todo validate as valid zap request
-            if event.kind != nostr::Kind::ZapRequest {
-                return Err(anyhow!("Invalid zap request"));
-            }
+            validate_zap_request(event, amount_msats)
+                .map_err(InvoiceRequestError::InvalidZapRequest)?;
             sha256::Hash::hash(event.as_json().as_bytes())
         }
     };
 
+    // payers would only wait for their payment to be cancelled, unpaid
+    // invoices don't count as anyone could fetch them to block the user
+    check_in_flight(
+        Some(&username),
+        amount_msats,
+        &[PaymentState::HtlcAccepted, PaymentState::Forwarding],
+        &PaymentLimits::new(Some(&user), config),
+        connection,
+    )?;
+
+    Ok(Some(ValidInvoiceRequest {
+        username,
+        amount_msats,
+        desc_hash,
+        zap_request,
+        comment,
+        payer_data,
+    }))
+}
+
+/// Wraps the user's next invoice in a hold invoice for a validated request
+async fn create_hold_invoice(
+    request: ValidInvoiceRequest,
+    lightning: &dyn LightningBackend,
+    connection: &mut SqliteConnection,
+) -> anyhow::Result<Option<Bolt11Invoice>> {
+    let ValidInvoiceRequest {
+        username,
+        amount_msats,
+        desc_hash,
+        zap_request,
+        comment,
+        payer_data,
+    } = request;
+
     let invoice_db = match Invoice::get_next_invoice(&username, connection) {
         Err(e) => {
             println!("Error getting invoice: {}", e);
@@ -106,19 +321,24 @@ pub(crate) async fn get_lnurl_invoice_impl(
         return Err(anyhow!("CLTV expiry too long"));
     }
 
-    let request = AddHoldInvoiceRequest {
-        hash: invoice_db.payment_hash().to_vec(),
-        value_msat: amount_msats as i64,
-        description_hash: desc_hash.to_vec(),
+    let request = HoldInvoiceRequest {
+        payment_hash: invoice_db.payment_hash(),
+        value_msat: amount_msats,
+        description_hash: desc_hash,
         expiry: DEFAULT_INVOICE_EXPIRY,
         cltv_expiry,
-        ..Default::default()
     };
 
-    let client = &mut invoice_client.clone();
+    let inv = lightning.add_hold_invoice(request).await?;
 
-    let resp = client.add_hold_invoice(request).await?.into_inner();
-    let inv = Bolt11Invoice::from_str(&resp.payment_request)?;
+    Payment::create_reserved(
+        &inv.payment_hash().to_hex(),
+        &username,
+        amount_msats,
+        connection,
+    )?;
+
+    Invoice::set_request_details(&inv, comment.as_deref(), payer_data.as_deref(), connection)?;
 
     if let Some(zap_request) = zap_request {
         let zap = Zap::new(&inv, zap_request, None);
@@ -128,11 +348,76 @@ pub(crate) async fn get_lnurl_invoice_impl(
     Ok(Some(inv))
 }
 
+/// Whether the user's node can be reached to forward `amount_msats`, always true
+/// when probing is disabled. Failed probes are only logged, they shouldn't stop payments.
+pub(crate) async fn check_reachable(
+    username: &str,
+    amount_msats: u64,
+    state: &State,
+    connection: &mut SqliteConnection,
+) -> bool {
+    if !state.config.probe_recipients {
+        return true;
+    }
+
+    let user = match User::get_by_username(connection, username) {
+        None => return true,
+        Some(user) => user,
+    };
+
+    let reachable = is_reachable(
+        &user,
+        amount_msats,
+        state.lightning.as_ref(),
+        &state.config,
+        &state.reachability,
+        connection,
+    )
+    .await;
+
+    reachable.unwrap_or_else(|e| {
+        println!("Error probing {username}: {e}");
+        true
+    })
+}
+
+/// Success action for a served invoice, aes actions are only
+/// returned for invoices the user uploaded encrypted data for.
+pub(crate) fn get_success_action(
+    username: &str,
+    payment_hash: &str,
+    connection: &mut SqliteConnection,
+) -> anyhow::Result<Option<SuccessActionResponse>> {
+    let action =
+        match User::get_by_username(connection, username).and_then(|user| user.success_action()) {
+            None => return Ok(None),
+            Some(action) => action,
+        };
+
+    let res = match action {
+        SuccessAction::Message { message } => Some(SuccessActionResponse::Message { message }),
+        SuccessAction::Url { description, url } => {
+            Some(SuccessActionResponse::Url { description, url })
+        }
+        SuccessAction::Aes { description } => {
+            Invoice::get_by_payment_hash(payment_hash, connection)?
+                .and_then(|inv| inv.aes_payload())
+                .map(|payload| SuccessActionResponse::Aes {
+                    description,
+                    ciphertext: payload.ciphertext,
+                    iv: payload.iv,
+                })
+        }
+    };
+
+    Ok(res)
+}
+
 pub async fn get_lnurl_invoice(
     Path(username): Path<String>,
     Query(params): Query<HashMap<String, String>>,
     Extension(state): Extension<State>,
-) -> Result<Json<LnURLPayInvoice>, (StatusCode, Json<serde_json::Value>)> {
+) -> Result<Json<LnurlInvoiceResponse>, (StatusCode, Json<serde_json::Value>)> {
     match params.get("amount").and_then(|a| a.parse::<u64>().ok()) {
         None => Err((
             StatusCode::BAD_REQUEST,
@@ -169,37 +454,275 @@ pub async fn get_lnurl_invoice(
                 )
             })?;
 
-            let res = get_lnurl_invoice_impl(
-                username,
+            let params = InvoiceParams {
                 amount_msats,
                 zap_request,
-                &state.invoice_client,
+                comment: params.get("comment").cloned(),
+                payer_data: params.get("payerdata").cloned(),
+            };
+
+            let request = match validate_invoice_request(
+                username.clone(),
+                params,
                 &state.config,
                 &mut connection,
-            )
-            .await;
+            ) {
+                Ok(Some(request)) => request,
+                Ok(None) => return Err(user_not_found()),
+                Err(e) => return Err(invoice_error(e)),
+            };
 
-            match res {
-                Ok(Some(inv)) => {
-                    println!("Generated invoice: {}", inv);
-                    let res = LnURLPayInvoice::new(inv);
-                    Ok(Json(res))
-                }
-                Ok(None) => Err((
-                    StatusCode::NOT_FOUND,
+            // reject up front what we can't send instead of cancelling the payment
+            if let Some(max) = state
+                .liquidity
+                .outbound_msat()
+                .filter(|max| amount_msats > *max)
+            {
+                return Err((
+                    StatusCode::BAD_REQUEST,
                     Json(json!({
                         "status": "ERROR",
-                        "reason": "The user you're searching for could not be found."
+                        "reason": InvoiceRequestError::AmountTooLarge(max).to_string(),
                     })),
-                )),
-                Err(e) => Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
+                ));
+            }
+
+            // don't have the payer lock funds in a payment that can't be forwarded,
+            // only probed for valid requests as probing queries routes over the network
+            if !check_reachable(&username, amount_msats, &state, &mut connection).await {
+                return Err((
+                    StatusCode::SERVICE_UNAVAILABLE,
                     Json(json!({
                         "status": "ERROR",
-                        "reason": format!("Failed to generate invoice: {}", e)
+                        "reason": "The recipient can't be reached right now, try again later",
                     })),
-                )),
+                ));
+            }
+
+            let res = create_hold_invoice(request, state.lightning.as_ref(), &mut connection).await;
+
+            match res {
+                Ok(Some(inv)) => {
+                    println!("Generated invoice: {}", inv);
+                    let payment_hash = inv.payment_hash().to_hex();
+                    // payment hashes are reused, so payers verify the request they made
+                    let verify = Payment::get_by_payment_hash(&payment_hash, &mut connection)
+                        .unwrap_or_else(|e| {
+                            println!("Error getting payment: {e}");
+                            None
+                        })
+                        .map(|payment| {
+                            verify_url(&username, &payment.request_id, &state.config.public_url)
+                        });
+                    // the invoice is already created, so serve it without the action on errors
+                    let success_action =
+                        get_success_action(&username, &payment_hash, &mut connection)
+                            .unwrap_or_else(|e| {
+                                println!("Error getting success action: {e}");
+                                None
+                            });
+                    let res = LnurlInvoiceResponse {
+                        pr: inv.to_string(),
+                        routes: vec![],
+                        verify,
+                        success_action,
+                    };
+                    Ok(Json(res))
+                }
+                Ok(None) => Err(user_not_found()),
+                Err(e) => Err(invoice_error(e)),
             }
         }
     }
 }
+
+fn user_not_found() -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({
+            "status": "ERROR",
+            "reason": "The user you're searching for could not be found."
+        })),
+    )
+}
+
+fn invoice_error(e: anyhow::Error) -> (StatusCode, Json<serde_json::Value>) {
+    if e.is::<InFlightLimitError>() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({
+                "status": "ERROR",
+                "reason": format!("{e}, try again later"),
+            })),
+        );
+    }
+    if e.is::<InvoiceRequestError>() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "status": "ERROR",
+                "reason": e.to_string(),
+            })),
+        );
+    }
+
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({
+            "status": "ERROR",
+            "reason": format!("Failed to generate invoice: {}", e)
+        })),
+    )
+}
+
+pub(crate) fn verify_payment_impl(
+    username: &str,
+    request_id: &str,
+    connection: &mut SqliteConnection,
+) -> anyhow::Result<Option<VerifyResponse>> {
+    let payment_hash = match Payment::get_by_request_id(request_id, connection)? {
+        Some(payment) => payment.payment_hash().to_hex(),
+        None => return verify_archived_payment(username, request_id, connection),
+    };
+
+    let invoice = match Invoice::get_by_payment_hash(&payment_hash, connection)? {
+        Some(invoice) if invoice.username().as_deref() == Some(username) => invoice,
+        _ => return Ok(None),
+    };
+
+    // only invoices that were served to a payer can be verified
+    let wrapped = match invoice.wrapped_invoice() {
+        Some(wrapped) => wrapped,
+        None => return Ok(None),
+    };
+
+    let settled = invoice.is_settled();
+    let preimage = invoice.preimage().filter(|_| settled);
+
+    Ok(Some(VerifyResponse {
+        status: String::from("OK"),
+        settled,
+        preimage: preimage.map(|p| p.to_hex()),
+        pr: wrapped.to_string(),
+        expired: !settled && wrapped.is_expired(),
+    }))
+}
+
+/// Requests whose invoice was released or removed were never paid
+fn verify_archived_payment(
+    username: &str,
+    request_id: &str,
+    connection: &mut SqliteConnection,
+) -> anyhow::Result<Option<VerifyResponse>> {
+    let payment = match ArchivedPayment::get_by_request_id(request_id, connection)? {
+        Some(payment) if payment.username.as_deref() == Some(username) => payment,
+        _ => return Ok(None),
+    };
+
+    let expired = Bolt11Invoice::from_str(&payment.wrapped_invoice)
+        .map_or(true, |wrapped| wrapped.is_expired());
+
+    Ok(Some(VerifyResponse {
+        status: String::from("OK"),
+        settled: false,
+        preimage: None,
+        pr: payment.wrapped_invoice,
+        expired,
+    }))
+}
+
+pub async fn verify_payment(
+    Path((username, request_id)): Path<(String, String)>,
+    Extension(state): Extension<State>,
+) -> Result<Json<VerifyResponse>, (StatusCode, Json<serde_json::Value>)> {
+    let mut connection = state.db_pool.get().map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({
+                "status": "ERROR",
+                "reason": "Failed to get database connection",
+            })),
+        )
+    })?;
+
+    match verify_payment_impl(&username, &request_id, &mut connection) {
+        Ok(Some(res)) => Ok(Json(res)),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "status": "ERROR",
+                "reason": "Not found",
+            })),
+        )),
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({
+                "status": "ERROR",
+                "reason": format!("Failed to verify payment: {}", e)
+            })),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lightning::fake::ROUTING_FEE_MSAT;
+    use crate::test_utils::TestContext;
+
+    #[tokio::test]
+    async fn test_probe_recipient() {
+        let mut config = Config::dummy();
+        config.probe_recipients = true;
+        let mut ctx = TestContext::new(config);
+
+        let username = String::from("test_user");
+        ctx.create_user(&username);
+
+        // users without invoices are left for the invoice request to reject
+        assert!(check_reachable(&username, 10_000, &ctx.state, &mut ctx.conn).await);
+
+        let user_invoice = ctx.add_user_invoice(&username, [11u8; 32]);
+
+        ctx.node.set_route_fee(None);
+        assert!(!check_reachable(&username, 10_000, &ctx.state, &mut ctx.conn).await);
+
+        // probing doesn't reserve the invoice
+        let invoice = Invoice::peek_next_invoice(&username, &mut ctx.conn).unwrap();
+        assert_eq!(invoice.map(|inv| inv.invoice()), Some(user_invoice));
+
+        // the result is cached for the user
+        ctx.node.set_route_fee(Some(ROUTING_FEE_MSAT));
+        assert!(!check_reachable(&username, 10_000, &ctx.state, &mut ctx.conn).await);
+        assert!(check_reachable(&username, 5_000, &ctx.state, &mut ctx.conn).await);
+
+        // invalid requests are rejected before probing
+        let params = HashMap::from([
+            (String::from("amount"), String::from("10000")),
+            (String::from("comment"), "a".repeat(256)),
+        ]);
+        let (status, _) = get_lnurl_invoice(
+            Path(username.clone()),
+            Query(params),
+            Extension(ctx.state.clone()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let params = HashMap::from([(String::from("amount"), String::from("10000"))]);
+        let (status, _) = get_lnurl_invoice(
+            Path(username.clone()),
+            Query(params),
+            Extension(ctx.state.clone()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        // nothing is probed when probing is disabled
+        ctx.state.config.probe_recipients = false;
+        ctx.node.set_route_fee(None);
+        assert!(check_reachable(&username, 10_000, &ctx.state, &mut ctx.conn).await);
+    }
+}
@@ -6,14 +6,32 @@ use dioxus::prelude::*;
 pub use add_invoices::add_invoices;
 pub use check_user::check_user;
 pub use create_user::create_user;
-pub use lnurlp::{get_lnurl_invoice, get_lnurlp};
+pub use health::health;
+pub use lnurlp::{get_lnurl_invoice, get_lnurlp, verify_payment};
+pub use payments::get_payments;
+pub use relay_stats::relay_stats;
+pub use update_settings::update_settings;
+
+#[cfg(test)]
+pub(crate) use create_user::create_user_impl;
+#[cfg(test)]
+pub(crate) use lnurlp::{
+    get_lnurl_invoice_impl, get_lnurlp_impl, verify_payment_impl, InvoiceParams,
+    InvoiceRequestError,
+};
+#[cfg(test)]
+pub(crate) use update_settings::update_settings_impl;
 
 use crate::State;
 
 mod add_invoices;
 mod check_user;
 mod create_user;
+mod health;
 mod lnurlp;
+mod payments;
+mod relay_stats;
+mod update_settings;
 
 pub(crate) fn handle_anyhow_error(err: anyhow::Error) -> (StatusCode, String) {
     println!("Error: {err}");
@@ -25,6 +43,10 @@ pub async fn index(Extension(state): Extension<State>) -> Html<String> {
         "This Zap Tunnel is currently running on the following node: {}",
         state.connection_string
     );
+    let fee = format!(
+        "Payments routed through this Zap Tunnel are charged a fee of {}",
+        state.config.describe_fee(&state.config.fee_policy())
+    );
 
     Html(dioxus_ssr::render_lazy(rsx! {
         style { include_str!("../style.css") }
@@ -36,6 +58,7 @@ pub async fn index(Extension(state): Extension<State>) -> Html<String> {
         Because of this you are trusting that the Zap Tunnel will not just give out its own invoices, and that it won't siphon portions of the funds routed through it." }
         p { "Because this is meant to be a replacement for a custodial lightning address, it should be an okay trust assumption" }
         br {}
+        p {"{fee}"}
         p {"{connect}"}
     }))
 }
@@ -43,44 +66,39 @@ pub async fn index(Extension(state): Extension<State>) -> Html<String> {
 #[cfg(test)]
 mod test {
     use std::str::FromStr;
+    use std::sync::Arc;
+    use std::time::SystemTime;
 
     use bitcoin::hashes::hex::ToHex;
-    use bitcoin::secp256k1::rand::Rng;
     use bitcoin::secp256k1::{rand, PublicKey, SecretKey, SECP256K1};
-    use diesel::{Connection, SqliteConnection};
-    use diesel_migrations::MigrationHarness;
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
     use lightning_invoice::Bolt11Invoice;
     use lnurl::Tag;
 
-    use crate::routes::add_invoices::AddInvoices;
+    use crate::fees::FeePolicy;
+    use crate::lightning::fake::{FakeNode, ROUTING_FEE_MSAT};
+    use crate::lightning::InvoiceState;
+    use crate::limits::InFlightLimits;
+    use crate::maintenance::MaintenanceReport;
+    use crate::models::invoice::Invoice;
+    use crate::models::ledger::LedgerEntry;
+    use crate::models::payment::{ArchivedPayment, Payment, PaymentState};
+    use crate::models::schema::{invoices, ledger, payment_history, payments};
+    use crate::models::user::User;
+    use crate::models::zap::Zap;
+    use crate::payer_data::{PayerDataField, PayerDataSchema};
+    use crate::routes::add_invoices::{AddInvoices, AesPayload};
     use crate::routes::create_user::CreateUser;
+    use crate::routes::lnurlp::{InvoiceParams, InvoiceRequestError, SuccessActionResponse};
+    use crate::routes::payments::GetPayments;
+    use crate::routes::update_settings::{SuccessAction, UserSettings};
+    use crate::test_utils::{
+        add_user_invoice, create_database, create_pool, create_state, create_user, gen_tmp_db_name,
+        serve_invoice, signed_settings, teardown_database,
+    };
 
     const INVOICE_STR: &str = "lnbc30110n1psnhkd0pp5pa3778sup4c5h6adqjxcygwejqhrczfuverex9meta4amp7jpfdqdz8fag975j92324yn3qgfhhgw3qwa58jgryd9jzq7t0w5sxgetrdajx2grd0ysxjmnkda5kxegcqzpgxqzfvsp5uejqpus5df8tyf5kmfxpkq6r80up4r9ahewtl8qz6a9enn7e0ums9qyyssqyf8m5yy8y4s4shnr9psx0lm27h94dg2j9wqd6nanrymhnztdwaujk854vw98500vmleeymsywysltdaymlmxp2fr6t49f69a6xfd9tspy50l7d";
 
-    fn gen_tmp_db_name() -> String {
-        let rng = rand::thread_rng();
-        let rand_string: String = rng
-            .sample_iter(&rand::distributions::Alphanumeric)
-            .take(30)
-            .collect::<Vec<u8>>()
-            .to_hex();
-        format!("/tmp/zap_tunnel_{}.sqlite", rand_string)
-    }
-
-    fn create_database(db_name: &str) -> SqliteConnection {
-        let mut connection = SqliteConnection::establish(db_name).unwrap();
-
-        connection
-            .run_pending_migrations(crate::models::MIGRATIONS)
-            .expect("migrations could not run");
-
-        connection
-    }
-
-    fn teardown_database(db_name: &str) {
-        std::fs::remove_file(db_name).unwrap();
-    }
-
     #[test]
     fn test_create_user() {
         let db_name = gen_tmp_db_name();
@@ -134,9 +152,10 @@ mod test {
 
         let lnurlp = super::lnurlp::get_lnurlp_impl(user.username, &config, conn).unwrap();
 
-        assert_eq!(lnurlp.allows_nostr, Some(true));
-        assert!(lnurlp.callback.len() > 1);
-        assert_eq!(lnurlp.tag, Tag::PayRequest);
+        assert_eq!(lnurlp.pay.allows_nostr, Some(true));
+        assert!(lnurlp.pay.callback.len() > 1);
+        assert_eq!(lnurlp.pay.tag, Tag::PayRequest);
+        assert_eq!(lnurlp.comment_allowed, Some(config.comment_allowed));
 
         teardown_database(&db_name);
     }
@@ -167,7 +186,7 @@ mod test {
         let ln_invoice = Bolt11Invoice::from_str(INVOICE_STR).unwrap();
 
         let signature = SECP256K1.sign_ecdsa_low_r(
-            &AddInvoices::message_hash(&[ln_invoice.clone()]).unwrap(),
+            &AddInvoices::message_hash(&[ln_invoice.clone()], &[]).unwrap(),
             &private_key,
         );
 
@@ -175,6 +194,7 @@ mod test {
             pubkey: pubkey.to_string(),
             signature: signature.to_string(),
             invoices: vec![ln_invoice],
+            aes_payloads: vec![],
         };
 
         let num_added = super::add_invoices::add_invoices_impl(payload, conn).unwrap();
@@ -183,4 +203,682 @@ mod test {
 
         teardown_database(&db_name);
     }
+
+    #[tokio::test]
+    async fn test_accept_pay_settle() {
+        let db_name = gen_tmp_db_name();
+        let conn = &mut create_database(&db_name);
+        let db_pool = create_pool(&db_name);
+
+        let config = crate::config::Config::dummy();
+        let node = Arc::new(FakeNode::new(config.network));
+
+        let username = String::from("test_user");
+        let private_key = create_user(conn, &username);
+
+        // upload an invoice the node is able to pay
+        let preimage = [7u8; 32];
+        let user_invoice = add_user_invoice(conn, &node, &username, preimage);
+
+        let amount_msats = 10_000;
+        let params = InvoiceParams {
+            amount_msats,
+            comment: Some(String::from("thanks!")),
+            ..Default::default()
+        };
+        let hold_invoice = super::lnurlp::get_lnurl_invoice_impl(
+            username.clone(),
+            params,
+            node.as_ref(),
+            &config,
+            conn,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        let hash = *hold_invoice.payment_hash();
+        assert_eq!(hash, *user_invoice.payment_hash());
+        assert_eq!(hold_invoice.amount_milli_satoshis(), Some(amount_msats));
+        assert_eq!(node.invoice_state(&hash), Some(InvoiceState::Open));
+
+        let request_id = Payment::get_by_payment_hash(&hash.to_hex(), conn)
+            .unwrap()
+            .unwrap()
+            .request_id;
+        let verify = super::lnurlp::verify_payment_impl(&username, &request_id, conn)
+            .unwrap()
+            .unwrap();
+        assert!(!verify.settled);
+        assert!(!verify.expired);
+        assert_eq!(verify.preimage, None);
+        assert_eq!(verify.pr, hold_invoice.to_string());
+        assert!(
+            super::lnurlp::verify_payment_impl("other_user", &request_id, conn)
+                .unwrap()
+                .is_none()
+        );
+
+        let state = create_state(config, node.clone(), db_pool);
+
+        // payer pays the hold invoice
+        let update = node.accept_htlc(hash).unwrap();
+        crate::subscriber::handle_accepted_invoice(update, state).await;
+
+        assert_eq!(node.invoice_state(&hash), Some(InvoiceState::Settled));
+
+        let payments = node.payments();
+        assert_eq!(payments.len(), 1);
+        assert_eq!(payments[0].invoice, user_invoice);
+        assert_eq!(payments[0].amount_msat, 8_900);
+
+        let invoice_db = invoices::table
+            .find(hash.to_hex())
+            .first::<Invoice>(conn)
+            .unwrap();
+        assert!(invoice_db.is_paid());
+        assert!(invoice_db.is_settled());
+        assert_eq!(invoice_db.preimage(), Some(preimage));
+
+        let payment = Payment::get_by_payment_hash(&hash.to_hex(), conn)
+            .unwrap()
+            .unwrap();
+        assert_eq!(payment.state(), PaymentState::Settled);
+        assert_eq!(payment.amount_msats, amount_msats as i64);
+        assert_eq!(payment.forward_amount_msats, Some(8_900));
+        assert_eq!(payment.routing_fee_msats, Some(ROUTING_FEE_MSAT as i64));
+        assert_eq!(
+            payment.fees_earned_msats,
+            Some(1_100 - ROUTING_FEE_MSAT as i64)
+        );
+        assert!(payment.settled_at.is_some());
+
+        let verify = super::lnurlp::verify_payment_impl(&username, &request_id, conn)
+            .unwrap()
+            .unwrap();
+        assert!(verify.settled);
+        assert_eq!(verify.preimage, Some(preimage.to_hex()));
+
+        // the comment is delivered to the recipient
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let pubkey = PublicKey::from_secret_key(SECP256K1, &private_key);
+        let signature =
+            SECP256K1.sign_ecdsa_low_r(&GetPayments::message_hash(time).unwrap(), &private_key);
+        let payments = super::payments::get_payments_impl(time, &pubkey, &signature, conn).unwrap();
+        assert_eq!(payments.len(), 1);
+        assert_eq!(payments[0].payment_hash, hash.to_hex());
+        assert_eq!(payments[0].amount_msats, Some(amount_msats));
+        assert_eq!(payments[0].comment, Some(String::from("thanks!")));
+
+        teardown_database(&db_name);
+    }
+
+    #[tokio::test]
+    async fn test_lnurl_invoice_rejects_invalid_zap_request() {
+        let db_name = gen_tmp_db_name();
+        let conn = &mut create_database(&db_name);
+
+        let config = crate::config::Config::dummy();
+        let node = FakeNode::new(config.network);
+
+        let username = String::from("test_user");
+        create_user(conn, &username);
+
+        let user_invoice = add_user_invoice(conn, &node, &username, [7u8; 32]);
+
+        // zap request without p or relays tags
+        let keys = nostr::Keys::generate();
+        let zap_request = nostr::EventBuilder::new(nostr::Kind::ZapRequest, "", &[])
+            .to_event(&keys)
+            .unwrap();
+
+        let params = InvoiceParams {
+            amount_msats: 10_000,
+            zap_request: Some(zap_request),
+            ..Default::default()
+        };
+        let err = super::lnurlp::get_lnurl_invoice_impl(username, params, &node, &config, conn)
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<InvoiceRequestError>(),
+            Some(&InvoiceRequestError::InvalidZapRequest(
+                crate::nostr::ZapRequestError::MissingPTag
+            ))
+        );
+        assert_eq!(node.invoice_state(user_invoice.payment_hash()), None);
+
+        teardown_database(&db_name);
+    }
+
+    #[tokio::test]
+    async fn test_lnurl_invoice_rejects_long_comment() {
+        let db_name = gen_tmp_db_name();
+        let conn = &mut create_database(&db_name);
+
+        let config = crate::config::Config::dummy();
+        let node = FakeNode::new(config.network);
+
+        let username = String::from("test_user");
+        create_user(conn, &username);
+
+        let user_invoice = add_user_invoice(conn, &node, &username, [7u8; 32]);
+
+        let params = InvoiceParams {
+            amount_msats: 10_000,
+            comment: Some("a".repeat(config.comment_allowed as usize + 1)),
+            ..Default::default()
+        };
+        let err = super::lnurlp::get_lnurl_invoice_impl(username, params, &node, &config, conn)
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<InvoiceRequestError>(),
+            Some(&InvoiceRequestError::CommentTooLong(config.comment_allowed))
+        );
+        assert_eq!(node.invoice_state(user_invoice.payment_hash()), None);
+
+        teardown_database(&db_name);
+    }
+
+    #[tokio::test]
+    async fn test_lnurl_invoice_payer_data() {
+        let db_name = gen_tmp_db_name();
+        let conn = &mut create_database(&db_name);
+
+        let config = crate::config::Config::dummy();
+        let node = FakeNode::new(config.network);
+
+        let username = String::from("test_user");
+        let private_key = create_user(conn, &username);
+
+        let lnurlp = super::lnurlp::get_lnurlp_impl(username.clone(), &config, conn).unwrap();
+        assert_eq!(lnurlp.payer_data, None);
+
+        let settings = UserSettings {
+            payer_data: Some(PayerDataSchema {
+                name: Some(PayerDataField { mandatory: true }),
+                email: Some(PayerDataField { mandatory: false }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let payload = signed_settings(&private_key, settings.clone());
+        let updated = super::update_settings::update_settings_impl(payload, &config, conn).unwrap();
+        assert_eq!(updated, settings);
+
+        let lnurlp = super::lnurlp::get_lnurlp_impl(username.clone(), &config, conn).unwrap();
+        let payer_data = lnurlp.payer_data.unwrap();
+        assert_eq!(payer_data.name, Some(PayerDataField { mandatory: true }));
+        assert_eq!(payer_data.email, Some(PayerDataField { mandatory: false }));
+        assert_eq!(payer_data.auth, None);
+
+        let user_invoice = add_user_invoice(conn, &node, &username, [7u8; 32]);
+
+        // missing mandatory name
+        let params = InvoiceParams {
+            amount_msats: 10_000,
+            payer_data: Some(String::from("{\"email\":\"satoshi@example.com\"}")),
+            ..Default::default()
+        };
+        let err =
+            super::lnurlp::get_lnurl_invoice_impl(username.clone(), params, &node, &config, conn)
+                .await
+                .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<InvoiceRequestError>(),
+            Some(&InvoiceRequestError::InvalidPayerData(
+                crate::payer_data::PayerDataError::MissingField("name")
+            ))
+        );
+        assert_eq!(node.invoice_state(user_invoice.payment_hash()), None);
+
+        let payer_data = String::from("{\"name\":\"satoshi\"}");
+        let params = InvoiceParams {
+            amount_msats: 10_000,
+            payer_data: Some(payer_data.clone()),
+            ..Default::default()
+        };
+        let hold_invoice =
+            super::lnurlp::get_lnurl_invoice_impl(username, params, &node, &config, conn)
+                .await
+                .unwrap()
+                .unwrap();
+
+        let invoice_db = invoices::table
+            .find(hold_invoice.payment_hash().to_hex())
+            .first::<Invoice>(conn)
+            .unwrap();
+        assert_eq!(
+            invoice_db.payer_data().and_then(|data| data.name),
+            Some(String::from("satoshi"))
+        );
+
+        teardown_database(&db_name);
+    }
+
+    #[tokio::test]
+    async fn test_success_action() {
+        let db_name = gen_tmp_db_name();
+        let conn = &mut create_database(&db_name);
+
+        let config = crate::config::Config::dummy();
+        let node = FakeNode::new(config.network);
+
+        let username = String::from("test_user");
+        let private_key = create_user(conn, &username);
+
+        assert_eq!(
+            super::lnurlp::get_success_action(&username, "", conn).unwrap(),
+            None
+        );
+
+        // url actions must be https
+        let settings = UserSettings {
+            success_action: Some(SuccessAction::Url {
+                description: String::from("download"),
+                url: String::from("http://example.com"),
+            }),
+            ..Default::default()
+        };
+        let payload = signed_settings(&private_key, settings);
+        assert!(super::update_settings::update_settings_impl(payload, &config, conn).is_err());
+
+        let settings = UserSettings {
+            success_action: Some(SuccessAction::Message {
+                message: String::from("thanks!"),
+            }),
+            ..Default::default()
+        };
+        let payload = signed_settings(&private_key, settings);
+        super::update_settings::update_settings_impl(payload, &config, conn).unwrap();
+        assert_eq!(
+            super::lnurlp::get_success_action(&username, "", conn).unwrap(),
+            Some(SuccessActionResponse::Message {
+                message: String::from("thanks!")
+            })
+        );
+
+        let settings = UserSettings {
+            success_action: Some(SuccessAction::Aes {
+                description: String::from("your code"),
+            }),
+            ..Default::default()
+        };
+        let payload = signed_settings(&private_key, settings);
+        super::update_settings::update_settings_impl(payload, &config, conn).unwrap();
+
+        // upload an invoice with its encrypted success action
+        let user_invoice = node.create_payable_invoice([7u8; 32]);
+        let aes_payload = AesPayload {
+            ciphertext: String::from("Y2lwaGVydGV4dA=="),
+            iv: String::from("AAAAAAAAAAAAAAAAAAAAAA=="),
+        };
+        let invoices = vec![user_invoice.clone()];
+        let aes_payloads = vec![aes_payload.clone()];
+        let pubkey = PublicKey::from_secret_key(SECP256K1, &private_key);
+        let signature = SECP256K1.sign_ecdsa_low_r(
+            &AddInvoices::message_hash(&invoices, &aes_payloads).unwrap(),
+            &private_key,
+        );
+        let payload = AddInvoices {
+            pubkey: pubkey.to_string(),
+            signature: signature.to_string(),
+            invoices,
+            aes_payloads,
+        };
+        super::add_invoices::add_invoices_impl(payload, conn).unwrap();
+
+        let params = InvoiceParams {
+            amount_msats: 10_000,
+            ..Default::default()
+        };
+        let hold_invoice =
+            super::lnurlp::get_lnurl_invoice_impl(username.clone(), params, &node, &config, conn)
+                .await
+                .unwrap()
+                .unwrap();
+
+        let action = super::lnurlp::get_success_action(
+            &username,
+            &hold_invoice.payment_hash().to_hex(),
+            conn,
+        )
+        .unwrap();
+        assert_eq!(
+            action,
+            Some(SuccessActionResponse::Aes {
+                description: String::from("your code"),
+                ciphertext: aes_payload.ciphertext,
+                iv: aes_payload.iv,
+            })
+        );
+
+        teardown_database(&db_name);
+    }
+
+    #[tokio::test]
+    async fn test_settle_after_failed_settle() {
+        let db_name = gen_tmp_db_name();
+        let conn = &mut create_database(&db_name);
+        let db_pool = create_pool(&db_name);
+
+        let config = crate::config::Config::dummy();
+        let node = Arc::new(FakeNode::new(config.network));
+
+        let username = String::from("test_user");
+        create_user(conn, &username);
+
+        let preimage = [7u8; 32];
+        let hash = serve_invoice(conn, &node, &config, &username, preimage, 10_000).await;
+
+        let state = create_state(config, node.clone(), db_pool);
+
+        // the user gets paid but settling the hold invoice fails
+        node.set_fail_settle(true);
+        let update = node.accept_htlc(hash).unwrap();
+        crate::subscriber::handle_accepted_invoice(update, state.clone()).await;
+
+        assert_eq!(node.invoice_state(&hash), Some(InvoiceState::Accepted));
+        let invoice_db = invoices::table
+            .find(hash.to_hex())
+            .first::<Invoice>(conn)
+            .unwrap();
+        assert!(invoice_db.is_paid());
+        assert!(!invoice_db.is_settled());
+        assert_eq!(invoice_db.preimage(), Some(preimage));
+        let payment = Payment::get_by_payment_hash(&hash.to_hex(), conn)
+            .unwrap()
+            .unwrap();
+        assert_eq!(payment.state(), PaymentState::Forwarded);
+
+        // still failing, nothing changes
+        crate::subscriber::reconcile_payments(&state).await.unwrap();
+        assert_eq!(node.invoice_state(&hash), Some(InvoiceState::Accepted));
+
+        // the persisted preimage is used to settle
+        node.set_fail_settle(false);
+        crate::subscriber::reconcile_payments(&state).await.unwrap();
+        assert_eq!(node.invoice_state(&hash), Some(InvoiceState::Settled));
+
+        let invoice_db = invoices::table
+            .find(hash.to_hex())
+            .first::<Invoice>(conn)
+            .unwrap();
+        assert!(invoice_db.is_settled());
+        let payment = Payment::get_by_payment_hash(&hash.to_hex(), conn)
+            .unwrap()
+            .unwrap();
+        assert_eq!(payment.state(), PaymentState::Settled);
+
+        // the user was only paid once
+        assert_eq!(node.payments().len(), 1);
+
+        teardown_database(&db_name);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_after_failed_cancel() {
+        let db_name = gen_tmp_db_name();
+        let conn = &mut create_database(&db_name);
+        let db_pool = create_pool(&db_name);
+
+        let mut config = crate::config::Config::dummy();
+        config.in_flight_queue_seconds = 0;
+        let node = Arc::new(FakeNode::new(config.network));
+
+        let username = String::from("test_user");
+        create_user(conn, &username);
+
+        let hash = serve_invoice(conn, &node, &config, &username, [7u8; 32], 10_000).await;
+
+        // the payment can't be forwarded and cancelling its hold invoice fails
+        let limits = InFlightLimits {
+            max_payments: Some(0),
+            max_msats: None,
+        };
+        User::set_in_flight_limits(&username, Some(&limits), conn).unwrap();
+        let state = create_state(config, node.clone(), db_pool);
+        node.set_fail_cancel(true);
+        let update = node.accept_htlc(hash).unwrap();
+        crate::subscriber::handle_accepted_invoice(update, state.clone()).await;
+
+        assert_eq!(node.invoice_state(&hash), Some(InvoiceState::Accepted));
+        let payment = Payment::get_by_payment_hash(&hash.to_hex(), conn)
+            .unwrap()
+            .unwrap();
+        assert_eq!(payment.state(), PaymentState::Cancelled);
+        assert!(payment.cancel_failed);
+
+        // still failing, nothing changes
+        crate::subscriber::reconcile_payments(&state).await.unwrap();
+        assert_eq!(node.invoice_state(&hash), Some(InvoiceState::Accepted));
+
+        // the payer's HTLC is released once lnd is reachable again
+        node.set_fail_cancel(false);
+        crate::subscriber::reconcile_payments(&state).await.unwrap();
+        assert_eq!(node.invoice_state(&hash), Some(InvoiceState::Canceled));
+        assert!(node.payments().is_empty());
+        let payment = Payment::get_by_payment_hash(&hash.to_hex(), conn)
+            .unwrap()
+            .unwrap();
+        assert!(!payment.cancel_failed);
+
+        teardown_database(&db_name);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_accepted_events() {
+        let db_name = gen_tmp_db_name();
+        let conn = &mut create_database(&db_name);
+        let db_pool = create_pool(&db_name);
+
+        let config = crate::config::Config::dummy();
+        let node = Arc::new(FakeNode::new(config.network));
+
+        let username = String::from("test_user");
+        create_user(conn, &username);
+
+        let hash = serve_invoice(conn, &node, &config, &username, [7u8; 32], 10_000).await;
+
+        let state = create_state(config, node.clone(), db_pool);
+
+        // both subscriptions deliver the accepted invoice
+        let update = node.accept_htlc(hash).unwrap();
+        tokio::join!(
+            crate::subscriber::handle_accepted_invoice(update.clone(), state.clone()),
+            crate::subscriber::handle_accepted_invoice(update.clone(), state.clone()),
+        );
+        // and a late duplicate
+        crate::subscriber::handle_accepted_invoice(update, state).await;
+
+        assert_eq!(node.payments().len(), 1);
+        assert_eq!(node.invoice_state(&hash), Some(InvoiceState::Settled));
+        let payment = Payment::get_by_payment_hash(&hash.to_hex(), conn)
+            .unwrap()
+            .unwrap();
+        assert_eq!(payment.state(), PaymentState::Settled);
+
+        teardown_database(&db_name);
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let db_name = gen_tmp_db_name();
+        let conn = &mut create_database(&db_name);
+
+        let config = crate::config::Config::dummy();
+        let node = FakeNode::new(config.network);
+
+        let username = String::from("test_user");
+        create_user(conn, &username);
+
+        let mut add_invoice = |preimage: [u8; 32]| {
+            let invoice = add_user_invoice(conn, &node, &username, preimage);
+            invoice.payment_hash().to_hex()
+        };
+        let expired = add_invoice([1u8; 32]);
+        let available = add_invoice([2u8; 32]);
+        let settled = add_invoice([3u8; 32]);
+        let abandoned = add_invoice([4u8; 32]);
+        let zapped = add_invoice([5u8; 32]);
+
+        // expired long before the retention period
+        diesel::update(invoices::table.find(&expired))
+            .set(invoices::expires_at.eq(0))
+            .execute(conn)
+            .unwrap();
+
+        // settled long before the retention period, the zap receipt
+        // of one of them is still waiting to be published
+        for (hash, preimage) in [(&settled, [3u8; 32]), (&zapped, [5u8; 32])] {
+            Payment::create_reserved(hash, &username, 10_000, conn).unwrap();
+            Invoice::mark_invoice_paid(hash, 1090, &preimage, conn).unwrap();
+            diesel::update(payments::table.find(hash))
+                .set((
+                    payments::state.eq(PaymentState::Settled.as_str()),
+                    payments::settled_at.eq(Some(1_000_000)),
+                    payments::fees_earned_msats.eq(Some(1090)),
+                    payments::routing_fee_msats.eq(Some(ROUTING_FEE_MSAT as i64)),
+                ))
+                .execute(conn)
+                .unwrap();
+        }
+        let zap_invoice = Invoice::get_by_payment_hash(&zapped, conn)
+            .unwrap()
+            .unwrap()
+            .invoice();
+        let zap_request = nostr::EventBuilder::new(nostr::Kind::ZapRequest, "", &[])
+            .to_event(&nostr::Keys::generate())
+            .unwrap();
+        Zap::create(Zap::new(&zap_invoice, zap_request, None), conn).unwrap();
+
+        // served long before the retention period and cancelled without being released
+        diesel::update(invoices::table.find(&abandoned))
+            .set(invoices::wrapped_expiry.eq(Some(1_000_000)))
+            .execute(conn)
+            .unwrap();
+        Payment::create_reserved(&abandoned, &username, 10_000, conn).unwrap();
+        Payment::mark_cancelled(&abandoned, "payer left", conn).unwrap();
+
+        let report = crate::maintenance::run_maintenance(&config, conn).unwrap();
+        assert_eq!(
+            report,
+            MaintenanceReport {
+                expired_invoices: 1,
+                abandoned_invoices: 1,
+                compacted_payments: 1,
+                pruned_history: 0,
+            }
+        );
+
+        let mut remaining: Vec<String> = invoices::table
+            .select(invoices::payment_hash)
+            .load(conn)
+            .unwrap();
+        remaining.sort();
+        let mut expected = vec![available, zapped.clone()];
+        expected.sort();
+        assert_eq!(remaining, expected);
+        assert!(Payment::get_by_payment_hash(&settled, conn)
+            .unwrap()
+            .is_none());
+        assert!(Payment::get_by_payment_hash(&zapped, conn)
+            .unwrap()
+            .is_some());
+
+        // the abandoned request is kept in the history
+        let history = ArchivedPayment::get_by_payment_hash(&abandoned, conn).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].failure_reason.as_deref(), Some("payer left"));
+
+        let entries = ledger::table.load::<LedgerEntry>(conn).unwrap();
+        let entry = LedgerEntry {
+            username: username.clone(),
+            day: 950_400,
+            payments: 1,
+            amount_msats: 10_000,
+            fees_earned_msats: 1090,
+            routing_fee_msats: ROUTING_FEE_MSAT as i64,
+        };
+        assert_eq!(entries, vec![entry.clone()]);
+        assert_eq!(
+            LedgerEntry::get(Some(&username), conn).unwrap(),
+            vec![entry]
+        );
+        assert!(LedgerEntry::get(Some("other_user"), conn)
+            .unwrap()
+            .is_empty());
+
+        // the history is pruned after the retention period
+        diesel::update(payment_history::table)
+            .set(payment_history::updated_at.eq(1_000_000))
+            .execute(conn)
+            .unwrap();
+        let report = crate::maintenance::run_maintenance(&config, conn).unwrap();
+        assert_eq!(report.pruned_history, 1);
+
+        // nothing left to collect
+        let report = crate::maintenance::run_maintenance(&config, conn).unwrap();
+        assert_eq!(report, Default::default());
+
+        teardown_database(&db_name);
+    }
+
+    #[tokio::test]
+    async fn test_user_fee_policy() {
+        let db_name = gen_tmp_db_name();
+        let conn = &mut create_database(&db_name);
+        let db_pool = create_pool(&db_name);
+
+        let config = crate::config::Config::dummy();
+        let node = Arc::new(FakeNode::new(config.network));
+
+        let username = String::from("test_user");
+        create_user(conn, &username);
+
+        let fee_policy = FeePolicy::new(100, 1_000, 0, Some(5_000)).unwrap();
+        User::set_fee_policy(&username, Some(&fee_policy), conn).unwrap();
+        assert!(User::set_fee_policy("unknown", Some(&fee_policy), conn).is_err());
+
+        // the user's fee is published to payers
+        let res = super::lnurlp::get_lnurlp_impl(username.clone(), &config, conn).unwrap();
+        assert!(res
+            .pay
+            .metadata
+            .contains("Pay to test_user, fee: 100 msats + 1000 ppm, max 5000 msats"));
+
+        let hash = serve_invoice(conn, &node, &config, &username, [8u8; 32], 10_000).await;
+
+        let state = create_state(config.clone(), node.clone(), db_pool);
+        let update = node.accept_htlc(hash).unwrap();
+        crate::subscriber::handle_accepted_invoice(update, state).await;
+
+        // 100 msats base fee and 10 msats proportional fee
+        let payments = node.payments();
+        assert_eq!(payments.len(), 1);
+        assert_eq!(payments[0].amount_msat, 9_890);
+        assert_eq!(payments[0].fee_limit_msat, 110);
+
+        let payment = Payment::get_by_payment_hash(&hash.to_hex(), conn)
+            .unwrap()
+            .unwrap();
+        assert_eq!(payment.state(), PaymentState::Settled);
+        assert_eq!(
+            payment.fees_earned_msats,
+            Some(110 - ROUTING_FEE_MSAT as i64)
+        );
+
+        // back to the server's fee
+        User::set_fee_policy(&username, None, conn).unwrap();
+        let user = User::get_by_username(conn, &username).unwrap();
+        assert_eq!(user.fee_policy(&config), config.fee_policy());
+
+        teardown_database(&db_name);
+    }
 }
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use axum::extract::Query;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::secp256k1::ecdsa::Signature;
+use bitcoin::secp256k1::{PublicKey, SECP256K1};
+use diesel::SqliteConnection;
+
+pub use zap_tunnel_client::{GetPayments, ReceivedPayment};
+
+use crate::models::invoice::Invoice;
+use crate::models::user::User;
+use crate::routes::handle_anyhow_error;
+use crate::State;
+
+pub(crate) fn get_payments_impl(
+    time: u64,
+    pubkey: &PublicKey,
+    signature: &Signature,
+    connection: &mut SqliteConnection,
+) -> anyhow::Result<Vec<ReceivedPayment>> {
+    GetPayments::validate(SECP256K1, time, pubkey, signature)?;
+
+    let user = User::get_by_pubkey(connection, pubkey)
+        .ok_or(anyhow!("No user found with pubkey {}", pubkey))?;
+
+    let payments = Invoice::get_paid_invoices(&user.username, connection)?
+        .into_iter()
+        .map(|inv| ReceivedPayment {
+            payment_hash: inv.payment_hash().to_hex(),
+            amount_msats: inv.amount_msats.map(|a| a as u64),
+            fees_earned_msats: inv.fees_earned().unwrap_or_default() as u64,
+            payer_data: inv.payer_data(),
+            comment: inv.comment,
+        })
+        .collect();
+
+    Ok(payments)
+}
+
+pub async fn get_payments(
+    Extension(state): Extension<State>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Vec<ReceivedPayment>>, (StatusCode, String)> {
+    let mut connection = state.db_pool.get().map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            String::from("Failed to get database connection"),
+        )
+    })?;
+
+    let time = params.get("time").and_then(|p| p.parse::<u64>().ok());
+    let pubkey = params
+        .get("pubkey")
+        .and_then(|p| PublicKey::from_str(p).ok());
+    let signature = params
+        .get("signature")
+        .and_then(|p| Signature::from_str(p).ok());
+
+    let (Some(time), Some(pubkey), Some(signature)) = (time, pubkey, signature) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            String::from("Missing required parameters"),
+        ));
+    };
+
+    match get_payments_impl(time, &pubkey, &signature, &mut connection) {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
@@ -0,0 +1,10 @@
+use std::collections::HashMap;
+
+use axum::{Extension, Json};
+
+use crate::relay_pool::RelayStats;
+use crate::State;
+
+pub async fn relay_stats(Extension(state): Extension<State>) -> Json<HashMap<String, RelayStats>> {
+    Json(state.zap_publisher.relay_stats())
+}
@@ -0,0 +1,231 @@
+use anyhow::anyhow;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use bitcoin::secp256k1::SECP256K1;
+use diesel::{Connection, SqliteConnection};
+
+pub use zap_tunnel_client::{SuccessAction, UpdateSettings, UserSettings};
+
+/// Maximum length of the text fields of a success action, see LUD-09
+const MAX_SUCCESS_ACTION_TEXT: usize = 144;
+
+use crate::config::Config;
+use crate::models::user::User;
+use crate::routes::handle_anyhow_error;
+use crate::routing::{RoutingPolicy, MAX_PARTS_LIMIT, MIN_PAYMENT_TIMEOUT};
+use crate::State;
+
+/// Checks a success action is within the LUD-09 limits
+fn check_success_action(action: &SuccessAction) -> anyhow::Result<()> {
+    match action {
+        SuccessAction::Message { message } => {
+            if message.chars().count() > MAX_SUCCESS_ACTION_TEXT {
+                return Err(anyhow!(
+                    "message must be at most {MAX_SUCCESS_ACTION_TEXT} characters"
+                ));
+            }
+        }
+        SuccessAction::Url { description, url } => {
+            if description.chars().count() > MAX_SUCCESS_ACTION_TEXT {
+                return Err(anyhow!(
+                    "description must be at most {MAX_SUCCESS_ACTION_TEXT} characters"
+                ));
+            }
+            if !url.starts_with("https://") {
+                return Err(anyhow!("url must be https"));
+            }
+        }
+        SuccessAction::Aes { description } => {
+            if description.chars().count() > MAX_SUCCESS_ACTION_TEXT {
+                return Err(anyhow!(
+                    "description must be at most {MAX_SUCCESS_ACTION_TEXT} characters"
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the routing policy overrides are usable
+fn check_routing_policy(policy: &RoutingPolicy) -> anyhow::Result<()> {
+    if let Some(max_parts) = policy.max_parts {
+        if max_parts == 0 || max_parts > MAX_PARTS_LIMIT {
+            return Err(anyhow!("max_parts must be between 1 and {MAX_PARTS_LIMIT}"));
+        }
+    }
+
+    if let Some(timeout) = policy.timeout_seconds {
+        if timeout < MIN_PAYMENT_TIMEOUT {
+            return Err(anyhow!(
+                "timeout_seconds must be at least {MIN_PAYMENT_TIMEOUT}"
+            ));
+        }
+    }
+
+    if let Some(percent) = policy.fee_limit_percent {
+        if percent > 100 {
+            return Err(anyhow!("fee_limit_percent must be at most 100"));
+        }
+    }
+
+    if policy
+        .outgoing_chan_ids
+        .as_ref()
+        .map_or(false, |ids| ids.is_empty())
+    {
+        return Err(anyhow!("outgoing_chan_ids must not be empty"));
+    }
+
+    Ok(())
+}
+
+/// Checks the settings are within the limits of the server
+fn check_settings(settings: &UserSettings, config: &Config) -> anyhow::Result<()> {
+    if let Some(min) = settings.min_sendable {
+        if min < config.min_sendable() {
+            return Err(anyhow!(
+                "min_sendable must be at least {} msats",
+                config.min_sendable()
+            ));
+        }
+    }
+
+    if let Some(max) = settings.max_sendable {
+        if max > config.max_sendable {
+            return Err(anyhow!(
+                "max_sendable must be at most {} msats",
+                config.max_sendable
+            ));
+        }
+    }
+
+    if let Some(action) = settings.success_action.as_ref() {
+        check_success_action(action)?;
+    }
+
+    if let Some(policy) = settings.routing_policy.as_ref() {
+        check_routing_policy(policy)?;
+    }
+
+    let min = settings.min_sendable.unwrap_or(config.min_sendable());
+    let max = settings.max_sendable.unwrap_or(config.max_sendable);
+    if min > max {
+        return Err(anyhow!("min_sendable must not be above max_sendable"));
+    }
+
+    Ok(())
+}
+
+pub(crate) fn update_settings_impl(
+    payload: UpdateSettings,
+    config: &Config,
+    connection: &mut SqliteConnection,
+) -> anyhow::Result<UserSettings> {
+    // validate signature
+    payload.validate(SECP256K1)?;
+
+    check_settings(&payload.settings, config)?;
+
+    connection.transaction(|connection| {
+        let mut user =
+            User::get_by_pubkey(connection, &payload.pubkey()?).ok_or(anyhow!("Invalid pubkey"))?;
+
+        user.update_settings(&payload.settings, connection)?;
+
+        println!("Updated settings for user {}", user.username);
+
+        Ok(user.settings())
+    })
+}
+
+pub async fn update_settings(
+    Extension(state): Extension<State>,
+    Json(payload): Json<UpdateSettings>,
+) -> Result<Json<UserSettings>, (StatusCode, String)> {
+    let mut connection = state.db_pool.get().map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            String::from("Failed to get database connection"),
+        )
+    })?;
+
+    match update_settings_impl(payload, &state.config, &mut connection) {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::invoice::Invoice;
+    use crate::routes::lnurlp::{
+        get_lnurl_invoice_impl, get_lnurlp_impl, InvoiceParams, InvoiceRequestError,
+    };
+    use crate::test_utils::{signed_settings, TestContext};
+
+    #[tokio::test]
+    async fn test_user_limits() {
+        let config = Config::dummy();
+        let mut ctx = TestContext::new(config.clone());
+
+        let username = String::from("test_user");
+        let private_key = ctx.create_user(&username);
+
+        // defaults to the server's limits
+        let lnurlp = get_lnurlp_impl(username.clone(), &config, &mut ctx.conn).unwrap();
+        assert_eq!(lnurlp.pay.min_sendable, config.min_sendable());
+        assert_eq!(lnurlp.pay.max_sendable, config.max_sendable);
+
+        // limits outside of the server's limits are rejected
+        let settings = UserSettings {
+            min_sendable: Some(1),
+            ..Default::default()
+        };
+        let payload = signed_settings(&private_key, settings);
+        assert!(update_settings_impl(payload, &config, &mut ctx.conn).is_err());
+
+        let settings = UserSettings {
+            min_sendable: Some(10_000),
+            max_sendable: Some(50_000),
+            ..Default::default()
+        };
+        let payload = signed_settings(&private_key, settings.clone());
+        let updated = update_settings_impl(payload, &config, &mut ctx.conn).unwrap();
+        assert_eq!(updated, settings);
+
+        let lnurlp = get_lnurlp_impl(username.clone(), &config, &mut ctx.conn).unwrap();
+        assert_eq!(lnurlp.pay.min_sendable, 10_000);
+        assert_eq!(lnurlp.pay.max_sendable, 50_000);
+
+        let user_invoice = ctx.add_user_invoice(&username, [7u8; 32]);
+
+        for (amount, expected) in [
+            (9_999, InvoiceRequestError::AmountTooSmall(10_000)),
+            (50_001, InvoiceRequestError::AmountTooLarge(50_000)),
+        ] {
+            let params = InvoiceParams {
+                amount_msats: amount,
+                ..Default::default()
+            };
+            let err = get_lnurl_invoice_impl(
+                username.clone(),
+                params,
+                ctx.node.as_ref(),
+                &config,
+                &mut ctx.conn,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.downcast_ref::<InvoiceRequestError>(), Some(&expected));
+        }
+
+        // no hold invoice was created and the pooled invoice is still available
+        assert_eq!(ctx.node.invoice_state(user_invoice.payment_hash()), None);
+        assert_eq!(
+            Invoice::get_num_invoices_available(&username, &mut ctx.conn).unwrap(),
+            1
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use lightning_invoice::Bolt11Invoice;
+
+pub use zap_tunnel_client::RoutingPolicy;
+
+use crate::config::Config;
+use crate::lightning::PaymentRequest;
+
+/// Most parts a user can allow their payments to be split into
+pub const MAX_PARTS_LIMIT: u32 = 64;
+/// Shortest timeout a user can ask for, payments are not attempted with less time left
+pub const MIN_PAYMENT_TIMEOUT: u64 = 10;
+
+/// How a payment to a user is routed
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForwardPolicy {
+    pub max_parts: u32,
+    /// Channels the payment must leave through, any channel if empty
+    pub outgoing_chan_ids: Vec<u64>,
+    pub timeout_seconds: u64,
+    /// Percentage of our fee that can be spent on routing
+    pub fee_limit_percent: u8,
+    pub time_pref: f64,
+}
+
+impl ForwardPolicy {
+    /// The server's policy with the user's overrides applied,
+    /// the parts, channels, timeout and fee limit stay within the server's.
+    pub fn new(config: &Config, overrides: Option<&RoutingPolicy>) -> Self {
+        let overrides = overrides.cloned().unwrap_or_default();
+
+        Self {
+            max_parts: overrides
+                .max_parts
+                .map_or(config.max_parts, |p| p.min(config.max_parts)),
+            outgoing_chan_ids: overrides.outgoing_chan_ids.map_or_else(
+                || config.outgoing_chan_ids.clone(),
+                |ids| allowed_chan_ids(ids, &config.outgoing_chan_ids),
+            ),
+            timeout_seconds: overrides
+                .timeout_seconds
+                .map_or(config.payment_timeout, |t| t.min(config.payment_timeout)),
+            fee_limit_percent: overrides
+                .fee_limit_percent
+                .map_or(config.fee_limit_percent, |p| {
+                    p.min(config.fee_limit_percent)
+                }),
+            time_pref: config.time_pref,
+        }
+    }
+
+    /// Policy for the retry of a failed payment: the server's parts and
+    /// channels, the whole routing budget and a preference for reliable routes
+    pub fn relaxed(&self, config: &Config) -> Self {
+        Self {
+            max_parts: config.max_parts,
+            outgoing_chan_ids: config.outgoing_chan_ids.clone(),
+            timeout_seconds: self.timeout_seconds,
+            fee_limit_percent: 100,
+            time_pref: 1.0,
+        }
+    }
+
+    /// Seconds to try paying the invoice for,
+    /// None if it expires too soon to be paid
+    pub fn timeout_for(&self, invoice: &Bolt11Invoice) -> Option<u64> {
+        let remaining_secs = invoice.duration_until_expiry().as_secs();
+
+        if remaining_secs > self.timeout_seconds {
+            Some(self.timeout_seconds)
+        } else if remaining_secs > MIN_PAYMENT_TIMEOUT {
+            Some(remaining_secs)
+        } else {
+            None
+        }
+    }
+
+    /// Request to pay the user's invoice, spending up to the policy's share
+    /// of `fee_budget_msat` on routing, None if it expires too soon to be paid
+    pub fn payment_request(
+        &self,
+        invoice: Bolt11Invoice,
+        amount_msat: u64,
+        fee_budget_msat: u64,
+    ) -> Option<PaymentRequest> {
+        let timeout_seconds = self.timeout_for(&invoice)?;
+
+        Some(PaymentRequest {
+            invoice,
+            amount_msat,
+            fee_limit_msat: fee_budget_msat * self.fee_limit_percent as u64 / 100,
+            timeout_seconds,
+            max_parts: self.max_parts,
+            outgoing_chan_ids: self.outgoing_chan_ids.clone(),
+            time_pref: self.time_pref,
+        })
+    }
+}
+
+/// The user's channels the server allows payments through,
+/// the server's when it allows none of them
+fn allowed_chan_ids(chan_ids: Vec<u64>, server_chan_ids: &[u64]) -> Vec<u64> {
+    if server_chan_ids.is_empty() {
+        return chan_ids;
+    }
+
+    let allowed: Vec<u64> = chan_ids
+        .into_iter()
+        .filter(|id| server_chan_ids.contains(id))
+        .collect();
+    if allowed.is_empty() {
+        server_chan_ids.to_vec()
+    } else {
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use zap_tunnel_client::UserSettings;
+
+    use super::*;
+    use crate::lightning::InvoiceState;
+    use crate::models::payment::PaymentState;
+    use crate::routes::update_settings_impl;
+    use crate::subscriber::handle_accepted_invoice;
+    use crate::test_utils::{signed_settings, TestContext};
+
+    #[test]
+    fn test_forward_policy() {
+        let mut config = Config::dummy();
+        config.fee_limit_percent = 50;
+        config.outgoing_chan_ids = vec![1, 2];
+
+        let policy = ForwardPolicy::new(&config, None);
+        assert_eq!(policy.max_parts, 16);
+        assert_eq!(policy.outgoing_chan_ids, vec![1, 2]);
+        assert_eq!(policy.timeout_seconds, 60);
+        assert_eq!(policy.fee_limit_percent, 50);
+
+        // parts, channels, timeout and fee limit are capped by the server's
+        let overrides = RoutingPolicy {
+            max_parts: Some(32),
+            outgoing_chan_ids: Some(vec![2, 3]),
+            timeout_seconds: Some(120),
+            fee_limit_percent: Some(80),
+        };
+        let policy = ForwardPolicy::new(&config, Some(&overrides));
+        assert_eq!(policy.max_parts, 16);
+        assert_eq!(policy.outgoing_chan_ids, vec![2]);
+        assert_eq!(policy.timeout_seconds, 60);
+        assert_eq!(policy.fee_limit_percent, 50);
+
+        let overrides = RoutingPolicy {
+            max_parts: Some(4),
+            outgoing_chan_ids: Some(vec![3]),
+            ..Default::default()
+        };
+        let policy = ForwardPolicy::new(&config, Some(&overrides));
+        assert_eq!(policy.max_parts, 4);
+        assert_eq!(policy.outgoing_chan_ids, vec![1, 2]);
+
+        // the retry stays on the server's channels
+        let relaxed = policy.relaxed(&config);
+        assert_eq!(relaxed.max_parts, 16);
+        assert_eq!(relaxed.outgoing_chan_ids, vec![1, 2]);
+        assert_eq!(relaxed.fee_limit_percent, 100);
+
+        // any of the user's channels when the server allows all
+        config.outgoing_chan_ids = vec![];
+        let policy = ForwardPolicy::new(&config, Some(&overrides));
+        assert_eq!(policy.outgoing_chan_ids, vec![3]);
+        assert!(policy.relaxed(&config).outgoing_chan_ids.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_routing_policy() {
+        // no routing fee can be paid on the first attempt
+        let mut config = Config::dummy();
+        config.fee_limit_percent = 0;
+        config.outgoing_chan_ids = vec![7, 8];
+        let mut ctx = TestContext::new(config);
+
+        let username = String::from("test_user");
+        let private_key = ctx.create_user(&username);
+
+        // invalid overrides are rejected
+        let settings = UserSettings {
+            routing_policy: Some(RoutingPolicy {
+                max_parts: Some(0),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let payload = signed_settings(&private_key, settings);
+        assert!(update_settings_impl(payload, &ctx.state.config, &mut ctx.conn).is_err());
+
+        let settings = UserSettings {
+            routing_policy: Some(RoutingPolicy {
+                max_parts: Some(4),
+                outgoing_chan_ids: Some(vec![7]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let payload = signed_settings(&private_key, settings.clone());
+        let updated = update_settings_impl(payload, &ctx.state.config, &mut ctx.conn).unwrap();
+        assert_eq!(updated, settings);
+
+        let hash = ctx.serve_invoice(&username, [7u8; 32], 10_000).await;
+
+        let update = ctx.node.accept_htlc(hash).unwrap();
+        handle_accepted_invoice(update, ctx.state.clone()).await;
+
+        // the first attempt follows the user's policy and fails,
+        // the retry is relaxed within the server's policy and succeeds
+        let payments = ctx.node.payments();
+        assert_eq!(payments.len(), 2);
+        assert_eq!(payments[0].max_parts, 4);
+        assert_eq!(payments[0].outgoing_chan_ids, vec![7]);
+        assert_eq!(payments[0].fee_limit_msat, 0);
+        assert_eq!(payments[1].max_parts, 16);
+        assert_eq!(payments[1].outgoing_chan_ids, vec![7, 8]);
+        assert_eq!(payments[1].fee_limit_msat, 1_100);
+
+        assert_eq!(ctx.node.invoice_state(&hash), Some(InvoiceState::Settled));
+        let payment = ctx.payment(&hash).unwrap();
+        assert_eq!(payment.state(), PaymentState::Settled);
+        // the payment shows what the retry was allowed to spend
+        assert_eq!(payment.fee_limit_msats, Some(1_100));
+        assert_eq!(payment.fees_earned_msats, Some(1090));
+    }
+}
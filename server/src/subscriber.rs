@@ -1,26 +1,28 @@
+use std::time::{Duration, Instant, SystemTime};
+
 use anyhow::anyhow;
-use bitcoin::hashes::hex::{FromHex, ToHex};
-use diesel::r2d2::{ConnectionManager, Pool};
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::hashes::sha256::Hash as Sha256;
 use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl, SqliteConnection};
-use lnrpc::payment::PaymentStatus;
-use tonic_openssl_lnd::invoicesrpc::SubscribeSingleInvoiceRequest;
-use tonic_openssl_lnd::lnrpc::invoice::InvoiceState;
-use tonic_openssl_lnd::{
-    invoicesrpc, lnrpc, LndInvoicesClient, LndLightningClient, LndRouterClient,
-};
+use lightning_invoice::Bolt11Invoice;
 
-use crate::config::Config;
+use crate::fees::{FeeMode, FeePolicy, PaymentFee};
+use crate::forwards::ForwardGuard;
+use crate::lightning::{
+    InvoiceState, InvoiceStream, InvoiceUpdate, LightningBackend, PaymentRequest, PaymentResult,
+    PaymentStatus,
+};
+use crate::limits::{claim_forwarding, Claim, PaymentLimits, IN_FLIGHT_POLL_INTERVAL};
+use crate::liquidity::refresh_liquidity;
 use crate::models::invoice::Invoice;
+use crate::models::payment::{Payment, PaymentState};
 use crate::models::schema::invoices::*;
-use crate::nostr::handle_zap;
+use crate::models::user::User;
+use crate::routing::ForwardPolicy;
+use crate::State;
 
-pub async fn start_active_invoice_subscriptions(
-    router: LndRouterClient,
-    invoice_client: LndInvoicesClient,
-    config: Config,
-    db_pool: Pool<ConnectionManager<SqliteConnection>>,
-) -> anyhow::Result<()> {
-    let db = &mut db_pool.get()?;
+pub async fn start_active_invoice_subscriptions(state: State) -> anyhow::Result<()> {
+    let db = &mut state.db_pool.get()?;
 
     let active_invoices = Invoice::get_active_invoices(db)?;
 
@@ -30,251 +32,1112 @@ pub async fn start_active_invoice_subscriptions(
     );
 
     for inv in active_invoices.iter() {
-        let r_hash = inv.payment_hash().to_vec();
-        let router_clone = router.clone();
-        let invoice_client_clone = invoice_client.clone();
-        let config_clone = config.clone();
-        let db_pool_clone = db_pool.clone();
+        let r_hash = inv.payment_hash();
+        let state_clone = state.clone();
 
         // Use tokio::spawn instead of tokio::task::spawn
         // to avoid borrowing the variables beyond their lifetime.
         tokio::spawn(async move {
-            handle_open_hodl_invoice(
-                r_hash,
-                router_clone,
-                invoice_client_clone,
-                &config_clone,
-                db_pool_clone,
-            )
-            .await;
+            handle_open_hodl_invoice(r_hash, state_clone).await;
         });
     }
 
     Ok(())
 }
 
-pub async fn start_invoice_subscription(
-    mut lnd: LndLightningClient,
-    router: LndRouterClient,
-    invoice_client: LndInvoicesClient,
-    config: Config,
-    db_pool: Pool<ConnectionManager<SqliteConnection>>,
+/// Logs payment state transitions that failed or were not allowed from the current state
+fn record_transition(
+    result: anyhow::Result<bool>,
+    invoice_hash: &Sha256,
+    to: PaymentState,
+    db: &mut SqliteConnection,
 ) {
-    println!("Starting invoice subscription, network: {}", config.network);
+    let hash = invoice_hash.to_hex();
+    match result {
+        Ok(true) => {}
+        Ok(false) => {
+            let current = Payment::get_by_payment_hash(&hash, db)
+                .ok()
+                .flatten()
+                .map(|p| p.state());
+            println!("Payment {hash} cannot move to {to} from {current:?}");
+        }
+        Err(e) => println!("Failed to move payment {hash} to {to}: {e}"),
+    }
+}
 
-    let sub = lnrpc::InvoiceSubscription::default();
-    let mut invoice_stream = lnd
-        .subscribe_invoices(sub)
-        .await
-        .expect("Failed to start invoice subscription")
-        .into_inner();
+/// Brings payments that were in progress while we were down or disconnected
+/// in line with their hold invoice and outgoing payment in lnd.
+pub async fn reconcile_payments(state: &State) -> anyhow::Result<()> {
+    // the connection is only held for reading, reconciling waits on lnd
+    let (unfinished, cancel_failed) = {
+        let db = &mut state.db_pool.get()?;
+        (
+            Payment::get_unfinished(db)?,
+            Payment::get_cancel_failed(db)?,
+        )
+    };
+
+    println!("Reconciling unfinished payments: {}", unfinished.len());
+
+    for payment in unfinished {
+        if let Err(e) = reconcile_payment(&payment, state).await {
+            println!(
+                "Failed to reconcile payment {}: {e}",
+                payment.payment_hash().to_hex()
+            );
+        }
+    }
+
+    // payments that ended while cancelling their hold invoice failed
+    for payment in cancel_failed {
+        if let Err(e) = cancel_accepted_invoice(&payment, state).await {
+            println!(
+                "Failed to cancel invoice {}: {e}",
+                payment.payment_hash().to_hex()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Cancels the hold invoice of an ended payment if lnd still holds its HTLCs
+async fn cancel_accepted_invoice(payment: &Payment, state: &State) -> anyhow::Result<()> {
+    let invoice_hash = payment.payment_hash();
+    let hold_invoice = state.lightning.lookup_invoice(invoice_hash).await?;
+
+    if hold_invoice.map_or(false, |update| update.state == InvoiceState::Accepted) {
+        state.lightning.cancel_invoice(invoice_hash).await?;
+        println!(
+            "cancelled invoice of {} payment: {}",
+            payment.state(),
+            invoice_hash.to_hex()
+        );
+    }
+
+    Payment::set_cancel_failed(&invoice_hash.to_hex(), false, &mut state.db_pool.get()?)?;
+
+    Ok(())
+}
+
+/// Cancels the hold invoice of a payment that ended,
+/// failures are recorded for [`reconcile_payments`] to retry.
+async fn cancel_ended_invoice(invoice_hash: Sha256, state: &State) -> anyhow::Result<()> {
+    let hash = invoice_hash.to_hex();
+
+    if let Err(e) = state.lightning.cancel_invoice(invoice_hash).await {
+        let recorded = state
+            .db_pool
+            .get()
+            .map_err(anyhow::Error::from)
+            .and_then(|mut db| Payment::set_cancel_failed(&hash, true, &mut db));
+        if let Err(e) = recorded {
+            println!("Failed to record cancelling invoice {hash} failed: {e}");
+        }
+        return Err(e);
+    }
+
+    println!("cancelled invoice: {hash}");
+    Ok(())
+}
+
+async fn reconcile_payment(payment: &Payment, state: &State) -> anyhow::Result<()> {
+    let invoice_hash = payment.payment_hash();
+    let hash = invoice_hash.to_hex();
 
-    while let Some(ln_invoice) = invoice_stream
-        .message()
+    let hold_invoice = state.lightning.lookup_invoice(invoice_hash).await?;
+    let hold_state = hold_invoice.as_ref().map(|update| update.state);
+
+    match (payment.state(), hold_invoice) {
+        // still waiting for the payer, the subscriptions handle these
+        (PaymentState::Reserved, Some(update)) if update.state == InvoiceState::Open => {}
+        // HTLCs accepted while we were not listening, forwarding them can take
+        // as long as the payment is in flight
+        (PaymentState::Reserved | PaymentState::HtlcAccepted, Some(update))
+            if update.state == InvoiceState::Accepted =>
+        {
+            tokio::spawn(handle_accepted_invoice(update, state.clone()));
+        }
+        (PaymentState::Reserved | PaymentState::HtlcAccepted, update)
+            if update
+                .as_ref()
+                .map_or(true, |u| u.state == InvoiceState::Canceled) =>
+        {
+            let db = &mut state.db_pool.get()?;
+            let moved = Payment::mark_cancelled(&hash, "hold invoice cancelled", db);
+            record_transition(moved, &invoice_hash, PaymentState::Cancelled, db);
+        }
+        // payments a handler is forwarding may not have reached lnd yet
+        (PaymentState::Forwarding | PaymentState::Forwarded, _) => {
+            let forward = match state.forwards.start(invoice_hash) {
+                Some(forward) => forward,
+                None => {
+                    println!("Payment {hash} is being forwarded, leaving it to its handler");
+                    return Ok(());
+                }
+            };
+
+            if payment.state() == PaymentState::Forwarding {
+                println!("Payment {hash} was being forwarded, tracking it");
+                track_forwarding(payment.clone(), forward, state.clone());
+            } else {
+                let preimage = Invoice::get_by_payment_hash(&hash, &mut state.db_pool.get()?)?
+                    .and_then(|inv| inv.preimage())
+                    .ok_or(anyhow!("Forwarded payment has no preimage"))?;
+                finish_paid_invoice(invoice_hash, preimage, hold_state, state).await;
+            }
+        }
+        (payment_state, _) => println!(
+            "Cannot reconcile payment {hash} ({payment_state}) with hold invoice in state {hold_state:?}"
+        ),
+    }
+
+    Ok(())
+}
+
+/// Finds out in the background how a payment to the user that was interrupted
+/// ended, lnd can take as long as the payment is in flight to tell.
+fn track_forwarding(payment: Payment, forward: ForwardGuard, state: State) {
+    tokio::spawn(async move {
+        let _forward = forward;
+        let invoice_hash = payment.payment_hash();
+        let result = wait_for_payment(invoice_hash, state.lightning.as_ref()).await;
+        if let Err(e) = finish_forwarding(&payment, result, &state).await {
+            println!("Failed to finish payment {}: {e}", invoice_hash.to_hex());
+        }
+    });
+}
+
+/// Applies the final result of forwarding a payment once we learn about it
+async fn finish_forwarding(
+    payment: &Payment,
+    result: PaymentResult,
+    state: &State,
+) -> anyhow::Result<()> {
+    let invoice_hash = payment.payment_hash();
+    let hash = invoice_hash.to_hex();
+
+    let hold_state = state
+        .lightning
+        .lookup_invoice(invoice_hash)
+        .await?
+        .map(|update| update.state);
+
+    match result {
+        PaymentResult {
+            status: PaymentStatus::Succeeded,
+            preimage: Some(preimage),
+            fee_msat,
+            ..
+        } => {
+            println!("paid invoice while down: {hash}");
+            let total_fee =
+                payment.amount_msats - payment.forward_amount_msats.unwrap_or(payment.amount_msats);
+            record_forwarded(
+                invoice_hash,
+                total_fee,
+                preimage,
+                fee_msat,
+                &mut state.db_pool.get()?,
+            );
+            finish_paid_invoice(invoice_hash, preimage, hold_state, state).await;
+            state.zap_publisher.notify();
+        }
+        PaymentResult {
+            status: PaymentStatus::Failed,
+            failure_reason,
+            ..
+        } => {
+            let reason = format!("{:?}: {failure_reason}", PaymentStatus::Failed);
+            fail_payment(invoice_hash, &reason, state)?;
+
+            if hold_state == Some(InvoiceState::Accepted) {
+                cancel_ended_invoice(invoice_hash, state).await?;
+            }
+        }
+        result => {
+            println!(
+                "Payment {hash} is still {:?}, leaving its hold invoice accepted",
+                result.status
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Records forwarding a payment failed
+fn fail_payment(invoice_hash: Sha256, reason: &str, state: &State) -> anyhow::Result<()> {
+    let db = &mut state.db_pool.get()?;
+    let moved = Payment::mark_failed(&invoice_hash.to_hex(), reason, db);
+    record_transition(moved, &invoice_hash, PaymentState::Failed, db);
+
+    Ok(())
+}
+
+/// Claims an accepted payment for forwarding, waiting up to the configured
+/// queue time for it to fit within the in-flight limits. A database connection
+/// is only held for each claim attempt so waiting payments don't drain the pool.
+async fn queue_claim(
+    invoice_hash: Sha256,
+    username: Option<&str>,
+    amount_msats: u64,
+    req: &PaymentRequest,
+    limits: &PaymentLimits,
+    state: &State,
+) -> anyhow::Result<Claim> {
+    let queue_deadline = Instant::now() + Duration::from_secs(state.config.in_flight_queue_seconds);
+
+    loop {
+        let claim = claim_forwarding(
+            &invoice_hash.to_hex(),
+            username,
+            amount_msats,
+            req.amount_msat,
+            req.fee_limit_msat,
+            limits,
+            &mut state.db_pool.get()?,
+        )?;
+        match claim {
+            Claim::Limited(limit) if Instant::now() >= queue_deadline => return Err(limit.into()),
+            Claim::Limited(_) => tokio::time::sleep(IN_FLIGHT_POLL_INTERVAL).await,
+            claim => return Ok(claim),
+        }
+    }
+}
+
+/// Fee for forwarding `amount_msat` to the user's invoice,
+/// the fee policy's when the routing cost can't be estimated
+async fn payment_fee(
+    fee_policy: &FeePolicy,
+    invoice: &Bolt11Invoice,
+    amount_msat: u64,
+    state: &State,
+) -> PaymentFee {
+    if state.config.fee_mode == FeeMode::Fixed {
+        return PaymentFee::fixed(fee_policy, amount_msat);
+    }
+
+    match state
+        .lightning
+        .estimate_route_fee(invoice, amount_msat)
         .await
-        .expect("Failed to receive invoices")
     {
-        match InvoiceState::from_i32(ln_invoice.state) {
-            Some(InvoiceState::Open) => {
-                if ln_invoice.r_preimage.is_empty() {
-                    let invoice_client = invoice_client.clone();
-                    let router = router.clone();
-                    let config = config.clone();
-                    let db_pool = db_pool.clone();
+        Ok(Some(route_fee)) => PaymentFee::route_cost(
+            fee_policy,
+            &state.config.route_fee_margin(),
+            amount_msat,
+            route_fee,
+        ),
+        Ok(None) => {
+            println!(
+                "No route found to {}, charging the fee policy",
+                invoice.payment_hash().to_hex()
+            );
+            PaymentFee::fixed(fee_policy, amount_msat)
+        }
+        Err(e) => {
+            println!("Error estimating route fee: {e}");
+            PaymentFee::fixed(fee_policy, amount_msat)
+        }
+    }
+}
+
+/// Pays the user's invoice, if lnd doesn't tell us how the payment ended
+/// we ask until it does, the incoming HTLC must not be cancelled
+/// while the outgoing payment can still succeed.
+async fn send_payment(
+    req: PaymentRequest,
+    invoice_hash: Sha256,
+    lightning: &dyn LightningBackend,
+) -> PaymentResult {
+    match lightning.send_payment(req).await {
+        Ok(payment) if payment.status.is_final() => payment,
+        Ok(payment) => {
+            println!(
+                "Payment {} is still {:?}, tracking it",
+                invoice_hash.to_hex(),
+                payment.status
+            );
+            wait_for_payment(invoice_hash, lightning).await
+        }
+        Err(e) => {
+            println!(
+                "Lost payment stream for {}: {e}, tracking it",
+                invoice_hash.to_hex()
+            );
+            wait_for_payment(invoice_hash, lightning).await
+        }
+    }
+}
+
+/// Asks lnd how an outgoing payment is doing until it reaches a final status,
+/// a payment lnd never started is reported as failed.
+async fn wait_for_payment(invoice_hash: Sha256, lightning: &dyn LightningBackend) -> PaymentResult {
+    let mut attempts = 0;
+
+    loop {
+        match lightning.track_payment(invoice_hash).await {
+            Ok(Some(result)) if result.status.is_final() => return result,
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                return PaymentResult {
+                    status: PaymentStatus::Failed,
+                    preimage: None,
+                    fee_msat: 0,
+                    failure_reason: String::from("payment was never sent"),
+                }
+            }
+            Err(e) => println!("Failed to track payment {}: {e}", invoice_hash.to_hex()),
+        }
+
+        tokio::time::sleep(reconnect_delay(attempts)).await;
+        attempts = attempts.saturating_add(1);
+    }
+}
+
+/// Settles the hold invoice of a payment we forwarded,
+/// or records it as settled if that happened while we were down.
+async fn finish_paid_invoice(
+    invoice_hash: Sha256,
+    preimage: [u8; 32],
+    hold_state: Option<InvoiceState>,
+    state: &State,
+) {
+    match hold_state {
+        Some(InvoiceState::Accepted) => settle_paid_invoice(invoice_hash, preimage, state).await,
+        Some(InvoiceState::Settled) => {
+            let db = &mut match state.db_pool.get() {
+                Ok(db) => db,
+                Err(e) => {
+                    println!("Failed to get database connection: {e}");
+                    return;
+                }
+            };
+            if let Err(e) = Invoice::mark_settled(&invoice_hash.to_hex(), db) {
+                println!(
+                    "Failed to mark invoice {} as settled: {e}",
+                    invoice_hash.to_hex()
+                );
+            }
+            let moved = Payment::mark_settled(&invoice_hash.to_hex(), db);
+            record_transition(moved, &invoice_hash, PaymentState::Settled, db);
+        }
+        hold_state => println!(
+            "Cannot settle paid invoice {} in state {hold_state:?}",
+            invoice_hash.to_hex()
+        ),
+    }
+}
+
+/// Persists the preimage of a forwarded payment, this must happen before
+/// settling and errors are only logged as the hold invoice can't be cancelled anymore.
+fn record_forwarded(
+    invoice_hash: Sha256,
+    total_fee: i64,
+    preimage: [u8; 32],
+    routing_fee_msat: u64,
+    db: &mut SqliteConnection,
+) {
+    let fees_earned_msats = total_fee - routing_fee_msat as i64;
+
+    if let Err(e) =
+        Invoice::mark_invoice_paid(&invoice_hash.to_hex(), fees_earned_msats, &preimage, db)
+    {
+        println!(
+            "Failed to mark invoice {} as paid: {e}",
+            invoice_hash.to_hex()
+        );
+    }
+    let moved = Payment::mark_forwarded(
+        &invoice_hash.to_hex(),
+        routing_fee_msat,
+        fees_earned_msats,
+        db,
+    );
+    record_transition(moved, &invoice_hash, PaymentState::Forwarded, db);
+}
+
+/// Settles a hold invoice whose preimage has already been persisted,
+/// failures are left for [`reconcile_payments`] to retry.
+async fn settle_paid_invoice(invoice_hash: Sha256, preimage: [u8; 32], state: &State) {
+    if let Err(e) = state.lightning.settle_invoice(preimage).await {
+        println!("Failed to settle invoice {}: {e}", invoice_hash.to_hex());
+        return;
+    }
+
+    let db = &mut match state.db_pool.get() {
+        Ok(db) => db,
+        Err(e) => {
+            println!("Failed to get database connection: {e}");
+            return;
+        }
+    };
+
+    if let Err(e) = Invoice::mark_settled(&invoice_hash.to_hex(), db) {
+        println!(
+            "Failed to mark invoice {} as settled: {e}",
+            invoice_hash.to_hex()
+        );
+    }
+    let moved = Payment::mark_settled(&invoice_hash.to_hex(), db);
+    record_transition(moved, &invoice_hash, PaymentState::Settled, db);
+}
+
+/// Delay before the first reconnect attempt, doubled on every failed attempt
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+fn reconnect_delay(attempts: u32) -> Duration {
+    RECONNECT_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempts))
+        .min(RECONNECT_MAX_DELAY)
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+pub async fn start_invoice_subscription(state: State) {
+    println!(
+        "Starting invoice subscription, network: {}",
+        state.config.network
+    );
+
+    let mut attempts = 0;
+    let mut connected_before = false;
+
+    loop {
+        let error = match state.lightning.subscribe_invoices().await {
+            Ok(mut invoice_stream) => {
+                state.subscription_health.connected();
+                attempts = 0;
+
+                // updates were missed while we were disconnected, settle what we
+                // already paid and re-subscribe to hold invoices that may have
+                // been accepted in the meantime
+                if connected_before {
+                    if let Err(e) = reconcile_payments(&state).await {
+                        println!("Failed to reconcile payments: {e}");
+                    }
+                    if let Err(e) = start_active_invoice_subscriptions(state.clone()).await {
+                        println!("Failed to restart active invoice subscriptions: {e}");
+                    }
+                }
+                connected_before = true;
+
+                handle_invoice_stream(&mut invoice_stream, &state).await
+            }
+            Err(e) => e,
+        };
+
+        state.subscription_health.disconnected(error.to_string());
+
+        let delay = reconnect_delay(attempts);
+        attempts = attempts.saturating_add(1);
+        println!(
+            "Invoice subscription lost: {error}, reconnecting in {}s",
+            delay.as_secs()
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Dispatches invoice updates until the stream fails or closes
+async fn handle_invoice_stream(invoice_stream: &mut InvoiceStream, state: &State) -> anyhow::Error {
+    while let Some(ln_invoice) = invoice_stream.recv().await {
+        let ln_invoice = match ln_invoice {
+            Ok(ln_invoice) => ln_invoice,
+            Err(e) => return e,
+        };
+        match ln_invoice.state {
+            InvoiceState::Open => {
+                if ln_invoice.is_hold_invoice() {
+                    let state = state.clone();
                     tokio::spawn(async move {
-                        handle_open_hodl_invoice(
-                            ln_invoice.r_hash,
-                            router,
-                            invoice_client,
-                            &config,
-                            db_pool,
-                        )
-                        .await
+                        handle_open_hodl_invoice(ln_invoice.payment_hash, state).await
                     });
                 }
             }
-            Some(InvoiceState::Accepted) => {
-                let invoice_client = invoice_client.clone();
-                let router = router.clone();
-                let config = config.clone();
-                let db_pool = db_pool.clone();
-                tokio::spawn(async move {
-                    handle_accepted_invoice(ln_invoice, router, invoice_client, &config, db_pool)
-                        .await
-                });
+            InvoiceState::Accepted => {
+                let state = state.clone();
+                tokio::spawn(async move { handle_accepted_invoice(ln_invoice, state).await });
             }
-            None | Some(InvoiceState::Canceled) | Some(InvoiceState::Settled) => {}
+            InvoiceState::Canceled | InvoiceState::Settled => {}
         }
     }
+
+    anyhow!("Invoice stream closed")
 }
 
-async fn handle_open_hodl_invoice(
-    r_hash: Vec<u8>,
-    router: LndRouterClient,
-    mut invoice_client: LndInvoicesClient,
-    config: &Config,
-    db_pool: Pool<ConnectionManager<SqliteConnection>>,
-) {
+async fn handle_open_hodl_invoice(r_hash: Sha256, state: State) {
+    // the invoice may already be watched, we re-subscribe after reconnecting
+    if !state.subscription_health.start_single(r_hash) {
+        return;
+    }
+
     println!("got open hodl invoice: {}", r_hash.to_hex());
 
-    let req = SubscribeSingleInvoiceRequest { r_hash };
-    let mut invoice_stream = invoice_client
-        .subscribe_single_invoice(req)
-        .await
-        .expect("Failed to subscribe to single invoice")
-        .into_inner();
+    let mut attempts = 0;
 
-    while let Some(ln_invoice) = invoice_stream
-        .message()
-        .await
-        .expect("Failed to receive single invoice stream")
-    {
-        if let Some(InvoiceState::Accepted) = InvoiceState::from_i32(ln_invoice.state) {
-            handle_accepted_invoice(
-                ln_invoice,
-                router.clone(),
-                invoice_client.clone(),
-                config,
-                db_pool.clone(),
-            )
-            .await
+    loop {
+        let error = match state.lightning.subscribe_single_invoice(r_hash).await {
+            Ok(mut invoice_stream) => {
+                attempts = 0;
+                match handle_single_invoice_stream(&mut invoice_stream, &state).await {
+                    Ok(()) => break,
+                    Err(e) => e,
+                }
+            }
+            Err(e) => e,
+        };
+
+        if !needs_subscription(&r_hash, &state) {
+            break;
         }
+
+        let delay = reconnect_delay(attempts);
+        attempts = attempts.saturating_add(1);
+        println!(
+            "Subscription to invoice {} lost: {error}, reconnecting in {}s",
+            r_hash.to_hex(),
+            delay.as_secs()
+        );
+        tokio::time::sleep(delay).await;
     }
+
+    state.subscription_health.end_single(&r_hash);
 }
 
-async fn handle_accepted_invoice(
-    ln_invoice: lnrpc::Invoice,
-    router: LndRouterClient,
-    mut invoice_client: LndInvoicesClient,
-    config: &Config,
-    db_pool: Pool<ConnectionManager<SqliteConnection>>,
-) {
-    let result = handle_accepted_invoice_impl(
-        ln_invoice.clone(),
-        router,
-        invoice_client.clone(),
-        config,
-        db_pool,
-    )
-    .await;
+/// Handles updates of a single hold invoice until it is settled or cancelled
+async fn handle_single_invoice_stream(
+    invoice_stream: &mut InvoiceStream,
+    state: &State,
+) -> anyhow::Result<()> {
+    while let Some(ln_invoice) = invoice_stream.recv().await {
+        let ln_invoice = ln_invoice?;
+        match ln_invoice.state {
+            InvoiceState::Accepted => handle_accepted_invoice(ln_invoice, state.clone()).await,
+            InvoiceState::Canceled | InvoiceState::Settled => return Ok(()),
+            InvoiceState::Open => {}
+        }
+    }
+
+    Err(anyhow!("Single invoice stream closed"))
+}
+
+/// Whether a lost single invoice subscription should be re-established,
+/// false once the payment is done or the wrapped invoice expired unpaid.
+fn needs_subscription(invoice_hash: &Sha256, state: &State) -> bool {
+    let hash = invoice_hash.to_hex();
+
+    // keep watching if we can't tell
+    let db = &mut match state.db_pool.get() {
+        Ok(db) => db,
+        Err(_) => return true,
+    };
+
+    let payment = match Payment::get_by_payment_hash(&hash, db) {
+        Ok(payment) => payment,
+        Err(_) => return true,
+    };
+
+    match payment.map(|p| p.state()) {
+        Some(PaymentState::Settled | PaymentState::Cancelled | PaymentState::Failed) => false,
+        Some(PaymentState::Reserved) | None => {
+            let expiry: Option<i64> = dsl::invoices
+                .filter(payment_hash.eq(&hash))
+                .select(wrapped_expiry)
+                .first::<Option<i64>>(db)
+                .optional()
+                .ok()
+                .flatten()
+                .flatten();
+            expiry.map_or(false, |expiry| expiry > now())
+        }
+        Some(_) => true,
+    }
+}
+
+pub(crate) async fn handle_accepted_invoice(ln_invoice: InvoiceUpdate, state: State) {
+    let result = handle_accepted_invoice_impl(ln_invoice.clone(), &state).await;
 
     // Cancel invoice if there was an error
     // otherwise the invoice will stay in the accepted state
     // and cause a stuck payment.
     if let Err(e) = result {
         println!("Error handling accepted invoice: {:?}", e);
-        let invoice_hash: Vec<u8> = ln_invoice.r_hash;
+        let invoice_hash = ln_invoice.payment_hash;
 
-        invoice_client
-            .cancel_invoice(invoicesrpc::CancelInvoiceMsg {
-                payment_hash: invoice_hash.to_vec(),
-            })
-            .await
-            .expect("Failed to cancel invoice");
+        // errors only happen before the payment is claimed for forwarding,
+        // so never cancel a payment another handler has claimed
+        if !release_unclaimed_payment(&invoice_hash, &e.to_string(), &state) {
+            return;
+        }
 
-        println!("cancelled invoice: {}", invoice_hash.to_hex());
+        // the payment has ended, reconcile_payments cancels its hold invoice later
+        if let Err(e) = cancel_ended_invoice(invoice_hash, &state).await {
+            println!("Failed to cancel invoice {}: {e}", invoice_hash.to_hex());
+        }
+    }
+}
+
+/// Marks a payment nobody claimed for forwarding as cancelled,
+/// returns true if its hold invoice can be cancelled.
+fn release_unclaimed_payment(invoice_hash: &Sha256, reason: &str, state: &State) -> bool {
+    let hash = invoice_hash.to_hex();
+
+    // without the database no one can have claimed the payment
+    let db = &mut match state.db_pool.get() {
+        Ok(db) => db,
+        Err(e) => {
+            println!("Failed to get database connection: {e}");
+            return true;
+        }
+    };
+
+    match Payment::mark_cancelled(&hash, reason, db) {
+        Ok(true) => true,
+        Ok(false) => match Payment::get_by_payment_hash(&hash, db) {
+            // payments that were never tracked
+            Ok(None) => true,
+            Ok(Some(payment)) => {
+                println!(
+                    "Not cancelling invoice {hash}, payment is {}",
+                    payment.state()
+                );
+                false
+            }
+            Err(e) => {
+                println!("Failed to get payment {hash}: {e}");
+                true
+            }
+        },
+        Err(e) => {
+            println!("Failed to mark payment {hash} as cancelled: {e}");
+            true
+        }
     }
 }
 
 async fn handle_accepted_invoice_impl(
-    ln_invoice: lnrpc::Invoice,
-    mut router: LndRouterClient,
-    mut invoice_client: LndInvoicesClient,
-    config: &Config,
-    db_pool: Pool<ConnectionManager<SqliteConnection>>,
+    ln_invoice: InvoiceUpdate,
+    state: &State,
 ) -> anyhow::Result<()> {
-    println!("got accepted invoice: {}", ln_invoice.r_hash.to_hex());
+    println!("got accepted invoice: {}", ln_invoice.payment_hash.to_hex());
+
+    let config = &state.config;
+    let lightning = state.lightning.as_ref();
 
-    let db = &mut db_pool.get()?;
+    let invoice_hash = ln_invoice.payment_hash;
 
-    let invoice_hash: Vec<u8> = ln_invoice.r_hash;
+    // connections are only taken for database work, never while waiting on lnd,
+    // payments can be in flight for as long as their HTLCs are held
+    let (invoice_opt, user) = {
+        let db = &mut state.db_pool.get()?;
+        let invoice_opt: Option<Invoice> = dsl::invoices
+            .filter(payment_hash.eq(invoice_hash.to_hex()))
+            .first::<Invoice>(db)
+            .optional()
+            .ok()
+            .flatten();
+        let user = invoice_opt
+            .as_ref()
+            .and_then(|inv| inv.username())
+            .and_then(|username| User::get_by_username(db, &username));
+        (invoice_opt, user)
+    };
 
-    let invoice_opt: Option<Invoice> = dsl::invoices
-        .filter(payment_hash.eq(invoice_hash.to_hex()))
-        .filter(fees_earned.is_null())
-        .first::<Invoice>(db)
-        .optional()
-        .ok()
-        .flatten();
+    // we already paid the user, only the settle is left
+    if let Some(preimage) = invoice_opt
+        .as_ref()
+        .filter(|inv| inv.is_paid())
+        .and_then(|inv| inv.preimage())
+    {
+        settle_paid_invoice(invoice_hash, preimage, state).await;
+        return Ok(());
+    }
+
+    {
+        let db = &mut state.db_pool.get()?;
+        let moved = Payment::mark_htlc_accepted(&invoice_hash.to_hex(), db);
+        record_transition(moved, &invoice_hash, PaymentState::HtlcAccepted, db);
+    }
 
     if let Some(user_invoice) = invoice_opt {
-        let remaining_time_secs = user_invoice.invoice().duration_until_expiry().as_secs();
-        // max 60 seconds timeout, min 10 seconds timeout
-        let timeout_seconds = if remaining_time_secs > 60 {
-            Some(60)
-        } else if remaining_time_secs > 10 {
-            Some(remaining_time_secs)
-        } else {
-            None
-        };
+        let overrides = user.as_ref().and_then(|user| user.routing_policy());
+        let policy = ForwardPolicy::new(config, overrides.as_ref());
+        let fee_policy = user
+            .as_ref()
+            .map_or(config.fee_policy(), |user| user.fee_policy(config));
+
+        let fee = payment_fee(
+            &fee_policy,
+            &user_invoice.invoice(),
+            ln_invoice.value_msat,
+            state,
+        )
+        .await;
+        let amt_msat = ln_invoice
+            .value_msat
+            .checked_sub(fee.total_msat)
+            .filter(|amt| *amt > 0)
+            .ok_or(anyhow!("Amount does not cover the fee"))?;
 
         // only pay invoice if we have enough time
-        if let Some(timeout_seconds) = timeout_seconds {
-            let total_fee =
-                config.base_fee as f64 + (config.fee_rate / 100.0) * ln_invoice.value_msat as f64;
-            let total_fee = total_fee as i64;
+        if let Some(req) =
+            policy.payment_request(user_invoice.invoice(), amt_msat, fee.routing_budget_msat)
+        {
+            // claim the payment, accepted events can be delivered by both the
+            // global and the single invoice subscription but only one may forward.
+            // Payments over the in-flight limits wait for earlier ones to finish.
+            // Reconciling leaves the payment alone while we hold it.
+            let _forward = match state.forwards.start(invoice_hash) {
+                Some(forward) => forward,
+                None => {
+                    println!(
+                        "Dropping duplicate accepted invoice {}, it is being forwarded",
+                        invoice_hash.to_hex()
+                    );
+                    return Ok(());
+                }
+            };
+            let limits = PaymentLimits::new(user.as_ref(), config);
+            let claim = queue_claim(
+                invoice_hash,
+                user.as_ref().map(|user| user.username.as_str()),
+                ln_invoice.value_msat,
+                &req,
+                &limits,
+                state,
+            )
+            .await?;
+
+            if claim == Claim::Taken {
+                let db = &mut state.db_pool.get()?;
+                return match Payment::get_by_payment_hash(&invoice_hash.to_hex(), db)? {
+                    Some(payment) => {
+                        println!(
+                            "Dropping duplicate accepted invoice {}, payment is {}",
+                            invoice_hash.to_hex(),
+                            payment.state()
+                        );
+                        Ok(())
+                    }
+                    None => Err(anyhow!("No payment to claim")),
+                };
+            }
 
-            let amt_msat: i64 = ln_invoice.value_msat - total_fee;
-            let req = tonic_openssl_lnd::routerrpc::SendPaymentRequest {
-                payment_request: user_invoice.invoice,
+            // the timeout shrinks while the payment is queued
+            let req = match policy.payment_request(
+                user_invoice.invoice(),
                 amt_msat,
-                fee_limit_msat: total_fee,
-                timeout_seconds: timeout_seconds as i32,
-                no_inflight_updates: true,
-                allow_self_payment: false,
-                amp: false,
-                time_pref: 0.9,
-                ..Default::default()
+                fee.routing_budget_msat,
+            ) {
+                Some(req) => req,
+                None => {
+                    let reason = "invoice expired while queued";
+                    fail_payment(invoice_hash, reason, state)?;
+                    cancel_ended_invoice(invoice_hash, state).await?;
+                    return Ok(());
+                }
             };
 
-            let mut stream = router.send_payment_v2(req).await?.into_inner();
-
-            if let Some(payment) = stream.message().await.ok().flatten() {
-                if let Some(PaymentStatus::Succeeded) = PaymentStatus::from_i32(payment.status) {
-                    // success
-                    println!("paid invoice: {}", invoice_hash.to_hex());
+            let mut payment = send_payment(req, invoice_hash, lightning).await;
 
-                    let preimage: Vec<u8> = Vec::from_hex(payment.payment_preimage.as_str())?;
+            // retry once with a relaxed policy before giving up on the payment
+            if payment.status == PaymentStatus::Failed {
+                let retry = policy.relaxed(config).payment_request(
+                    user_invoice.invoice(),
+                    amt_msat,
+                    fee.routing_budget_msat,
+                );
+                if let Some(req) = retry {
+                    println!(
+                        "retrying invoice {} with a relaxed policy: {}",
+                        invoice_hash.to_hex(),
+                        payment.failure_reason
+                    );
+                    let hash = invoice_hash.to_hex();
+                    let recorded = Payment::set_fee_limit(
+                        &hash,
+                        req.fee_limit_msat,
+                        &mut state.db_pool.get()?,
+                    );
+                    if let Err(e) = recorded {
+                        println!("Failed to record the fee limit of {hash}: {e}");
+                    }
+                    payment = send_payment(req, invoice_hash, lightning).await;
+                }
+            }
 
-                    // settle invoice
-                    invoice_client
-                        .settle_invoice(invoicesrpc::SettleInvoiceMsg { preimage })
-                        .await?;
+            // the payment moved our balances
+            refresh_liquidity(state).await;
 
-                    let fees_earned_msats = total_fee - payment.fee_msat;
+            if let (PaymentStatus::Succeeded, Some(preimage)) = (payment.status, payment.preimage) {
+                // success
+                println!("paid invoice: {}", invoice_hash.to_hex());
 
-                    // mark invoice as paid
-                    Invoice::mark_invoice_paid(&invoice_hash.to_hex(), fees_earned_msats, db)
-                        .expect("Failed to mark invoice as paid");
+                record_forwarded(
+                    invoice_hash,
+                    fee.total_msat as i64,
+                    preimage,
+                    payment.fee_msat,
+                    &mut state.db_pool.get()?,
+                );
 
-                    // create and broadcast zap if applicable
-                    // todo handle errors
-                    handle_zap(&invoice_hash, &config.nostr_keys(), db)
-                        .await
-                        .expect("Failed to handle zap");
+                // settle invoice
+                settle_paid_invoice(invoice_hash, preimage, state).await;
 
-                    return Ok(());
-                } else {
-                    // failed or unknown
-                    println!(
-                        "failed to pay invoice ({}) {}: {}",
-                        payment.status,
-                        invoice_hash.to_hex(),
-                        payment.failure_reason
-                    );
+                // publish zap receipt if applicable
+                state.zap_publisher.notify();
 
-                    invoice_client
-                        .cancel_invoice(invoicesrpc::CancelInvoiceMsg {
-                            payment_hash: invoice_hash.to_vec(),
-                        })
-                        .await?;
+                return Ok(());
+            } else {
+                // failed
+                println!(
+                    "failed to pay invoice ({:?}) {}: {}",
+                    payment.status,
+                    invoice_hash.to_hex(),
+                    payment.failure_reason
+                );
 
-                    println!("cancelled invoice: {}", invoice_hash.to_hex());
-                    return Ok(());
-                }
+                let reason = format!("{:?}: {}", payment.status, payment.failure_reason);
+                fail_payment(invoice_hash, &reason, state)?;
+                cancel_ended_invoice(invoice_hash, state).await?;
+                return Ok(());
             }
         }
     }
 
     Err(anyhow!("Failed to handle invoice"))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Config;
+    use crate::models::schema::invoices;
+    use crate::test_utils::{wait_until, TestContext};
+
+    #[test]
+    fn test_reconnect_delay() {
+        assert_eq!(reconnect_delay(0), Duration::from_secs(1));
+        assert_eq!(reconnect_delay(1), Duration::from_secs(2));
+        assert_eq!(reconnect_delay(5), Duration::from_secs(32));
+        assert_eq!(reconnect_delay(6), RECONNECT_MAX_DELAY);
+        assert_eq!(reconnect_delay(u32::MAX), RECONNECT_MAX_DELAY);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_payments() {
+        let mut ctx = TestContext::new(Config::dummy());
+
+        let username = String::from("test_user");
+        ctx.create_user(&username);
+
+        let mut hashes = vec![];
+        for preimage in [[1u8; 32], [2u8; 32]] {
+            hashes.push(ctx.serve_invoice(&username, preimage, 10_000).await);
+        }
+        let (accepted, forwarding) = (hashes[0], hashes[1]);
+
+        // an HTLC is accepted while we are down
+        ctx.node.accept_htlc(accepted).unwrap();
+
+        // we go down after paying the user, before recording it
+        ctx.node.accept_htlc(forwarding).unwrap();
+        Payment::mark_htlc_accepted(&forwarding.to_hex(), &mut ctx.conn).unwrap();
+        Payment::mark_forwarding(&forwarding.to_hex(), 8_900, 1_100, &mut ctx.conn).unwrap();
+        let user_invoice = invoices::table
+            .find(forwarding.to_hex())
+            .first::<Invoice>(&mut ctx.conn)
+            .unwrap()
+            .invoice();
+        ctx.node
+            .send_payment(PaymentRequest {
+                invoice: user_invoice,
+                amount_msat: 8_900,
+                fee_limit_msat: 1_100,
+                timeout_seconds: 60,
+                max_parts: 16,
+                outgoing_chan_ids: vec![],
+                time_pref: 0.9,
+            })
+            .await
+            .unwrap();
+
+        reconcile_payments(&ctx.state).await.unwrap();
+
+        // both are handled in the background
+        wait_until(|| {
+            [accepted, forwarding].iter().all(|hash| {
+                ctx.payment(hash)
+                    .map_or(false, |payment| payment.state() == PaymentState::Settled)
+            })
+        })
+        .await;
+
+        for hash in [accepted, forwarding] {
+            assert_eq!(ctx.node.invoice_state(&hash), Some(InvoiceState::Settled));
+
+            let invoice_db = invoices::table
+                .find(hash.to_hex())
+                .first::<Invoice>(&mut ctx.conn)
+                .unwrap();
+            assert!(invoice_db.is_settled());
+            assert_eq!(invoice_db.fees_earned(), Some(1090));
+
+            let payment = ctx.payment(&hash).unwrap();
+            assert_eq!(payment.state(), PaymentState::Settled);
+        }
+
+        // the user was only paid once for each
+        assert_eq!(ctx.node.payments().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_in_flight_payment() {
+        let mut ctx = TestContext::new(Config::dummy());
+
+        let username = String::from("test_user");
+        ctx.create_user(&username);
+
+        let hash = ctx.serve_invoice(&username, [7u8; 32], 10_000).await;
+
+        // we go down while paying the user and lnd only answers
+        // tracking requests once the payment is final
+        ctx.node.accept_htlc(hash).unwrap();
+        Payment::mark_htlc_accepted(&hash.to_hex(), &mut ctx.conn).unwrap();
+        Payment::mark_forwarding(&hash.to_hex(), 8_900, 1_100, &mut ctx.conn).unwrap();
+        let user_invoice = invoices::table
+            .find(hash.to_hex())
+            .first::<Invoice>(&mut ctx.conn)
+            .unwrap()
+            .invoice();
+        ctx.node.set_hold_payments(true);
+        ctx.node.set_block_tracking(true);
+        ctx.node
+            .send_payment(PaymentRequest {
+                invoice: user_invoice,
+                amount_msat: 8_900,
+                fee_limit_msat: 1_100,
+                timeout_seconds: 60,
+                max_parts: 16,
+                outgoing_chan_ids: vec![],
+                time_pref: 0.9,
+            })
+            .await
+            .unwrap();
+
+        // reconciling isn't held up by the payment in flight
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            reconcile_payments(&ctx.state),
+        )
+        .await
+        .expect("reconcile waited for the payment")
+        .unwrap();
+
+        assert_eq!(ctx.node.invoice_state(&hash), Some(InvoiceState::Accepted));
+        let payment = ctx.payment(&hash).unwrap();
+        assert_eq!(payment.state(), PaymentState::Forwarding);
+
+        // and finishes the payment once lnd does
+        ctx.node.complete_payments();
+        wait_until(|| {
+            ctx.payment(&hash)
+                .map_or(false, |payment| payment.state() == PaymentState::Settled)
+        })
+        .await;
+        assert_eq!(ctx.node.invoice_state(&hash), Some(InvoiceState::Settled));
+        assert_eq!(ctx.node.payments().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_live_forward() {
+        let mut ctx = TestContext::new(Config::dummy());
+
+        let username = String::from("test_user");
+        ctx.create_user(&username);
+
+        let hash = ctx.serve_invoice(&username, [7u8; 32], 10_000).await;
+
+        // a handler claimed the payment but lnd doesn't know it yet
+        let update = ctx.node.accept_htlc(hash).unwrap();
+        Payment::mark_htlc_accepted(&hash.to_hex(), &mut ctx.conn).unwrap();
+        Payment::mark_forwarding(&hash.to_hex(), 8_900, 1_100, &mut ctx.conn).unwrap();
+        let forward = ctx.state.forwards.start(hash).unwrap();
+
+        // reconciling after a reconnect leaves it to the handler
+        reconcile_payments(&ctx.state).await.unwrap();
+        handle_accepted_invoice(update, ctx.state.clone()).await;
+
+        assert_eq!(ctx.node.invoice_state(&hash), Some(InvoiceState::Accepted));
+        let payment = ctx.payment(&hash).unwrap();
+        assert_eq!(payment.state(), PaymentState::Forwarding);
+        assert!(ctx.node.payments().is_empty());
+
+        // without a handler the payment was never sent
+        drop(forward);
+        reconcile_payments(&ctx.state).await.unwrap();
+        wait_until(|| {
+            ctx.payment(&hash)
+                .map_or(false, |payment| payment.state() == PaymentState::Failed)
+        })
+        .await;
+        assert_eq!(ctx.node.invoice_state(&hash), Some(InvoiceState::Canceled));
+        assert!(ctx.node.payments().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_payment_in_flight() {
+        let mut ctx = TestContext::new(Config::dummy());
+
+        let username = String::from("test_user");
+        ctx.create_user(&username);
+
+        let hash = ctx.serve_invoice(&username, [7u8; 32], 10_000).await;
+
+        // lnd only reports the payment as in flight
+        ctx.node.set_hold_payments(true);
+        let update = ctx.node.accept_htlc(hash).unwrap();
+        let handler = tokio::spawn(handle_accepted_invoice(update, ctx.state.clone()));
+        wait_until(|| !ctx.node.payments().is_empty()).await;
+
+        // the incoming HTLC is held while the payment may still succeed
+        assert_eq!(ctx.node.invoice_state(&hash), Some(InvoiceState::Accepted));
+        let payment = ctx.payment(&hash).unwrap();
+        assert_eq!(payment.state(), PaymentState::Forwarding);
+
+        // no database connection is held while the payment is in flight
+        let pool_state = ctx.state.db_pool.state();
+        assert_eq!(pool_state.idle_connections, pool_state.connections);
+
+        // operators can find the payments that are stuck in flight
+        let forwarding =
+            Payment::find(None, Some(PaymentState::Forwarding), &mut ctx.conn).unwrap();
+        assert_eq!(forwarding.len(), 1);
+        assert_eq!(forwarding[0].payment_hash(), hash);
+        let by_hash = Payment::find(Some(&hash.to_hex()), None, &mut ctx.conn).unwrap();
+        assert_eq!(by_hash.len(), 1);
+        assert!(
+            Payment::find(None, Some(PaymentState::Failed), &mut ctx.conn)
+                .unwrap()
+                .is_empty()
+        );
+
+        ctx.node.complete_payments();
+        handler.await.unwrap();
+
+        assert_eq!(ctx.node.invoice_state(&hash), Some(InvoiceState::Settled));
+        let payment = ctx.payment(&hash).unwrap();
+        assert_eq!(payment.state(), PaymentState::Settled);
+        assert_eq!(payment.fees_earned_msats, Some(1090));
+        assert_eq!(ctx.node.payments().len(), 1);
+    }
+}
@@ -0,0 +1,220 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::secp256k1::rand::Rng;
+use bitcoin::secp256k1::{rand, PublicKey, SecretKey, SECP256K1};
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::{Connection, RunQueryDsl, SqliteConnection};
+use diesel_migrations::MigrationHarness;
+use lightning_invoice::Bolt11Invoice;
+use zap_tunnel_client::{CreateUser, UpdateSettings, UserSettings};
+
+use crate::config::Config;
+use crate::forwards::ActiveForwards;
+use crate::health::SubscriptionHealth;
+use crate::lightning::fake::FakeNode;
+use crate::liquidity::LiquidityCache;
+use crate::models::invoice::Invoice;
+use crate::models::payment::Payment;
+use crate::models::schema::invoices;
+use crate::nostr::ZapPublisher;
+use crate::reachability::ReachabilityCache;
+use crate::routes::{create_user_impl, get_lnurl_invoice_impl, InvoiceParams};
+use crate::State;
+
+/// A database, a fake lnd node and the server state using both,
+/// the database is removed when the context is dropped
+pub(crate) struct TestContext {
+    db_name: String,
+    pub conn: SqliteConnection,
+    pub node: Arc<FakeNode>,
+    pub state: State,
+}
+
+impl TestContext {
+    pub fn new(config: Config) -> Self {
+        let db_name = gen_tmp_db_name();
+        let conn = create_database(&db_name);
+        let node = Arc::new(FakeNode::new(config.network));
+        let state = create_state(config, node.clone(), create_pool(&db_name));
+
+        Self {
+            db_name,
+            conn,
+            node,
+            state,
+        }
+    }
+
+    pub fn create_user(&mut self, username: &str) -> SecretKey {
+        create_user(&mut self.conn, username)
+    }
+
+    pub fn add_user_invoice(&mut self, username: &str, preimage: [u8; 32]) -> Bolt11Invoice {
+        add_user_invoice(&mut self.conn, &self.node, username, preimage)
+    }
+
+    pub async fn serve_invoice(
+        &mut self,
+        username: &str,
+        preimage: [u8; 32],
+        amount_msats: u64,
+    ) -> Sha256 {
+        serve_invoice(
+            &mut self.conn,
+            &self.node,
+            &self.state.config,
+            username,
+            preimage,
+            amount_msats,
+        )
+        .await
+    }
+
+    pub fn payment(&mut self, payment_hash: &Sha256) -> Option<Payment> {
+        Payment::get_by_payment_hash(&payment_hash.to_hex(), &mut self.conn).unwrap()
+    }
+}
+
+impl Drop for TestContext {
+    fn drop(&mut self) {
+        // also runs when the test failed, which must not panic again
+        let _ = std::fs::remove_file(&self.db_name);
+    }
+}
+
+pub(crate) fn gen_tmp_db_name() -> String {
+    let rng = rand::thread_rng();
+    let rand_string: String = rng
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(30)
+        .collect::<Vec<u8>>()
+        .to_hex();
+    format!("/tmp/zap_tunnel_{}.sqlite", rand_string)
+}
+
+pub(crate) fn create_database(db_name: &str) -> SqliteConnection {
+    let mut connection = SqliteConnection::establish(db_name).unwrap();
+
+    connection
+        .run_pending_migrations(crate::models::MIGRATIONS)
+        .expect("migrations could not run");
+
+    connection
+}
+
+pub(crate) fn teardown_database(db_name: &str) {
+    std::fs::remove_file(db_name).unwrap();
+}
+
+pub(crate) fn create_pool(db_name: &str) -> Pool<ConnectionManager<SqliteConnection>> {
+    Pool::builder()
+        .build(ConnectionManager::<SqliteConnection>::new(db_name))
+        .unwrap()
+}
+
+pub(crate) fn create_state(
+    config: Config,
+    node: Arc<FakeNode>,
+    db_pool: Pool<ConnectionManager<SqliteConnection>>,
+) -> State {
+    State {
+        connection_string: String::from("test@127.0.0.1:9735"),
+        zap_publisher: Arc::new(ZapPublisher::new(&config)),
+        config,
+        lightning: node,
+        db_pool,
+        subscription_health: Arc::new(SubscriptionHealth::default()),
+        reachability: Arc::new(ReachabilityCache::default()),
+        liquidity: Arc::new(LiquidityCache::default()),
+        forwards: Arc::new(ActiveForwards::default()),
+    }
+}
+
+pub(crate) fn create_user(conn: &mut SqliteConnection, username: &str) -> SecretKey {
+    let private_key = SecretKey::new(&mut rand::thread_rng());
+    let pubkey = PublicKey::from_secret_key(SECP256K1, &private_key);
+
+    let signature =
+        SECP256K1.sign_ecdsa_low_r(&CreateUser::message_hash(username).unwrap(), &private_key);
+
+    let payload = CreateUser {
+        username: username.to_string(),
+        pubkey: pubkey.to_string(),
+        signature: signature.to_string(),
+    };
+
+    create_user_impl(payload, conn).unwrap();
+
+    private_key
+}
+
+/// Adds an invoice paying to `preimage` to the user's pool
+pub(crate) fn add_user_invoice(
+    conn: &mut SqliteConnection,
+    node: &FakeNode,
+    username: &str,
+    preimage: [u8; 32],
+) -> Bolt11Invoice {
+    let invoice = node.create_payable_invoice(preimage);
+    diesel::insert_into(invoices::table)
+        .values(&Invoice::new(&invoice, Some(username)))
+        .execute(conn)
+        .unwrap();
+    invoice
+}
+
+/// Adds an invoice to the user's pool and serves it wrapped to a payer
+/// of `amount_msats`, returns the payment hash of the hold invoice
+pub(crate) async fn serve_invoice(
+    conn: &mut SqliteConnection,
+    node: &FakeNode,
+    config: &Config,
+    username: &str,
+    preimage: [u8; 32],
+    amount_msats: u64,
+) -> Sha256 {
+    add_user_invoice(conn, node, username, preimage);
+
+    let params = InvoiceParams {
+        amount_msats,
+        ..Default::default()
+    };
+    let hold_invoice = get_lnurl_invoice_impl(username.to_string(), params, node, config, conn)
+        .await
+        .unwrap()
+        .unwrap();
+    *hold_invoice.payment_hash()
+}
+
+/// Polls `condition` until it holds, failing the test after 5 seconds
+pub(crate) async fn wait_until(mut condition: impl FnMut() -> bool) {
+    for _ in 0..100 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("Condition not met in time");
+}
+
+pub(crate) fn signed_settings(private_key: &SecretKey, settings: UserSettings) -> UpdateSettings {
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let signature = SECP256K1.sign_ecdsa_low_r(
+        &UpdateSettings::message_hash(time, &settings).unwrap(),
+        private_key,
+    );
+
+    UpdateSettings {
+        pubkey: PublicKey::from_secret_key(SECP256K1, private_key).to_string(),
+        time,
+        signature: signature.to_string(),
+        settings,
+    }
+}